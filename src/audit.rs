@@ -0,0 +1,150 @@
+//! snapshots of the relay states at a fixed interval, for audit trails
+//!
+//! the snapshots go to one CSV file per day (UTC), e.g. `relay8x-2018-10-14.csv`, with a
+//! line per card:
+//!
+//! ```text
+//! timestamp,card,relays
+//! 1539507162,1,00000101
+//! 1539507162,2,00000000
+//! ```
+//!
+//! timestamps are seconds since the unix epoch, relays are printed like `--show-state`
+//! does, relay 8 first
+
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rpc::CardState;
+
+/// first line of every file
+const HEADER: &str = "timestamp,card,relays";
+
+/// writes snapshots to the file of the day in a directory
+pub struct AuditLog {
+    dir: PathBuf,
+    interval: Duration,
+    // when the last snapshot was taken
+    last: Option<SystemTime>,
+    // day of the open file and the file
+    file: Option<(String, File)>,
+}
+
+impl AuditLog {
+    /// log to files in `dir`, taking a snapshot every `interval`
+    pub fn new<P: Into<PathBuf>>(dir: P, interval: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            interval,
+            last: None,
+            file: None,
+        }
+    }
+
+    /// whether the next snapshot is due at `now`, the first one always is
+    pub fn due(&self, now: SystemTime) -> bool {
+        self.last.is_none_or(|last| {
+            now.duration_since(last)
+                .map_or(true, |elapsed| elapsed >= self.interval)
+        })
+    }
+
+    /// appends the `states` taken at `now` to the file of the day
+    ///
+    /// a new day starts a new file with a header; after a failed write the file is
+    /// reopened for the next snapshot
+    pub fn record(&mut self, states: &[CardState], now: SystemTime) -> io::Result<()> {
+        self.last = Some(now);
+        let secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let day = date(secs / 86_400);
+        let lines: String = states
+            .iter()
+            .map(|s| format!("{},{},{:08b}\n", secs, s.card, s.state))
+            .collect();
+        let result = self
+            .file_of(&day)
+            .and_then(|file| file.write_all(lines.as_bytes()));
+        if result.is_err() {
+            self.file = None;
+        }
+        result
+    }
+
+    /// the open file if it is the one of `day`, otherwise the file of `day` opened
+    fn file_of(&mut self, day: &str) -> io::Result<&mut File> {
+        let open = match self.file.take() {
+            Some((open, file)) if open == day => (open, file),
+            _ => {
+                create_dir_all(&self.dir)?;
+                let path = self.dir.join(format!("relay8x-{}.csv", day));
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                if file.metadata()?.len() == 0 {
+                    writeln!(file, "{}", HEADER)?;
+                }
+                (day.to_string(), file)
+            }
+        };
+        Ok(&mut self.file.insert(open).1)
+    }
+}
+
+/// `YYYY-MM-DD` of `days` since the unix epoch, by Howard Hinnant's `civil_from_days`
+fn date(days: u64) -> String {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{read_to_string, remove_dir_all};
+    use std::process;
+
+    #[test]
+    fn dates_of_days() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(1_700_000_000 / 86_400), "2023-11-14");
+        assert_eq!(date(1_709_164_800 / 86_400), "2024-02-29");
+        assert_eq!(date(1_709_251_200 / 86_400), "2024-03-01");
+    }
+
+    #[test]
+    fn snapshots_rotate_by_day() {
+        let dir = temp_dir().join(format!("relay8x-audit-test-{}", process::id()));
+        let mut log = AuditLog::new(&dir, Duration::from_secs(60));
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let states = [
+            CardState { card: 1, state: 5 },
+            CardState { card: 2, state: 0 },
+        ];
+
+        // 2023-11-14 23:59 and 2023-11-15 00:00
+        assert!(log.due(at(1_700_006_340)));
+        log.record(&states, at(1_700_006_340)).unwrap();
+        assert!(!log.due(at(1_700_006_399)));
+        assert!(log.due(at(1_700_006_400)));
+        log.record(&states[..1], at(1_700_006_400)).unwrap();
+
+        assert_eq!(
+            read_to_string(dir.join("relay8x-2023-11-14.csv")).unwrap(),
+            "timestamp,card,relays\n1700006340,1,00000101\n1700006340,2,00000000\n"
+        );
+        assert_eq!(
+            read_to_string(dir.join("relay8x-2023-11-15.csv")).unwrap(),
+            "timestamp,card,relays\n1700006400,1,00000101\n"
+        );
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+/// how long-running modes retry a failed device, e.g. an unplugged USB adapter
+///
+/// the delay before each attempt doubles from `base` up to `max`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// delay before the first attempt
+    pub base: Duration,
+    /// longest delay between two attempts
+    pub max: Duration,
+    /// attempts before giving up, 0 doesn't retry at all
+    pub attempts: u32,
+}
+
+impl Default for Backoff {
+    /// a second at first, then at most once a minute, giving up after 5 attempts
+    fn default() -> Self {
+        Self {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            attempts: 5,
+        }
+    }
+}
+
+impl Backoff {
+    /// backoff with a non zero `base` of at most `max`
+    pub fn new(base: Duration, max: Duration, attempts: u32) -> io::Result<Self> {
+        if base == Duration::from_secs(0) || max < base {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Reconnect delays of {} ms up to {} ms, need 0 < base <= max",
+                    base.as_millis(),
+                    max.as_millis()
+                ),
+            ));
+        }
+        Ok(Self {
+            base,
+            max,
+            attempts,
+        })
+    }
+
+    /// delay before attempt `attempt`, counting from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        2u32.checked_pow(attempt)
+            .and_then(|factor| self.base.checked_mul(factor))
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_max() {
+        let backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_millis(500), 5).unwrap();
+        let delays: Vec<u128> = (0..5).map(|a| backoff.delay(a).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 500, 500]);
+        assert_eq!(backoff.delay(100), Duration::from_millis(500));
+
+        assert!(Backoff::new(Duration::from_millis(0), Duration::from_millis(5), 1).is_err());
+        assert!(Backoff::new(Duration::from_millis(10), Duration::from_millis(5), 1).is_err());
+        assert!(Backoff::new(Duration::from_millis(5), Duration::from_millis(5), 0).is_ok());
+    }
+}
@@ -1,3 +1,4 @@
+extern crate ctrlc;
 extern crate docopt;
 extern crate env_logger;
 extern crate relay8x;
@@ -5,34 +6,231 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate failure;
+extern crate serde_json;
+#[cfg(feature = "http")]
+extern crate tiny_http;
 
 use docopt::Docopt;
+#[cfg(feature = "ws")]
+use relay8x::audit::AuditLog;
+use std::collections::BTreeMap;
 use std::env::var;
+use std::fmt;
+use std::fs::File;
 use std::io;
+use std::io::BufReader;
+#[cfg(feature = "ws")]
+use std::net::TcpListener;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use relay8x::{CardIndex, Relay8x, RelayIndex};
+use relay8x::capture::{read_capture, tx_schedule};
+use relay8x::sim::SimulatedRelays;
+use relay8x::{
+    build_frame, check_baud, check_pulse, check_timeout, expand_relays, iter_relays,
+    parse_address_map, parse_card_relays, parse_duration, parse_mask, parse_port,
+    parse_relay_states, u8_as_relays, AliasFile, Backoff, Capture, CardIndex, ChecksumError,
+    ConfigFile, ConfigWatcher, Debouncer, DeviceLock, Frame, RateLimitPolicy, Relay8x,
+    Relay8xCmdSet, Relay8xConfig, Relay8xError, RelayIndex, RelayTest, PROTOCOL, TCP_SCHEME,
+};
+use relay8x::{levels, rpc};
 
-const USAGE: &'static str = "
+const USAGE: &str = "
 relay8x
 
 Usage:
-  relay8x set [--dev=<dev>] [--card=<card> ...] [--relay=<relay> ...] <state>
-  relay8x toggle [--dev=<dev>] [--card=<card> ...] [--relay=<relay> ...]
-  relay8x reset [--dev=<dev>] [--card=<card> ...] [--relay=<relay> ...]
+  relay8x set [options] [--card=<card> ...] [--relay=<relay> ... | --mask=<mask>] [<state>]
+  relay8x toggle [options] [--card=<card> ...] (--all | --relay=<relay> ... | --mask=<mask>)
+  relay8x reset [options] [--card=<card> ...] [--relay=<relay> ... | --mask=<mask>]
+  relay8x pulse [options] [--card=<card> ...] [--relay=<relay> ... | --mask=<mask>] --ms=<dur>
+  relay8x write [options] [--card=<card> ...] <mask>
+  relay8x all-off [options]
+  relay8x at [options] [--card=<card> ...] [--relay=<relay> ... | --mask=<mask>] [--in=<dur>] [--every=<dur>] <state>
+  relay8x batch [options] [--card=<card> ...] --ops=<json>
+  relay8x simulate [options] [--card=<card> ...] [--sim-cards=<n>] --ops=<json>
+  relay8x levels [options] [--card=<card> ...]
+  relay8x replay [options] --capture=<path> [--preserve-timing] [--speed=<factor>]
+  relay8x counters [options] [--card=<card> ...]
+  relay8x faults [options] [--card=<card> ...] [--clear]
+  relay8x locate [options] [--card=<card> ...]
+  relay8x selftest [options] [--card=<card> ...]
+  relay8x whoami [options]
+  relay8x ping [options] [--card=<card> ...]
+  relay8x option [options] [--card=<card>] [--set=<byte>]
+  relay8x status [options] [--card=<card> ...] [--relay=<relay> ... | --mask=<mask>]
+  relay8x latency [options] [--card=<card> ...] --relay=<relay> ...
+  relay8x encode [options] (init | query | set | toggle | reset) [--card=<card> ...] [--relay=<relay> ... | --mask=<mask>]
+  relay8x ws [options] [--card=<card> ...] --port=<port>
+  relay8x daemon [options] [--card=<card> ...] --socket=<path>
+  relay8x mqtt [options] [--card=<card>] --broker=<url> --topic=<prefix>
+  relay8x serve [options] [--card=<card> ...] --port=<port>
+  relay8x mirror [options] --source-card=<card> --target-card=<card> [--interval-ms=<dur>]
+  relay8x watch [options] [--card=<card> ...] [--debounce-ms=<dur>]
   relay8x (-h | --help)
+  relay8x version [--json]
   relay8x (-v | --version)
   
 Commands:
-  set       set specified relay 'on' or 'off', if no relay number is given all relays are set
-  toggle    toggle specified relay,  if no relay number is given all relays are toggeled
+  set       set specified relay 'on' or 'off', if no relay number is given all relays are set;
+            without <state> the relays are given with their states, e.g. --relay=1:on,3:off
+  toggle    toggle specified relays, toggling all relays needs the explicit flag --all
   reset     switch all or just one relay off to reach defined state again
+  pulse     switch the relays on and after --ms off again, e.g. for door openers;
+            all relays if none are given, ctrl-c doesn't cut the pulse short
+  write     switch the relays of <mask> on and all others off with a single frame, the
+            mask like 0b00000101, 0x05 or 5 with relay 1 in bit 0; 0 switches all off
+  all-off   switch every relay of every card found by the initialisation off, each card
+            has to acknowledge; with --no-init or --skip-init-ack by a broadcast instead
+  at        wait --in=<dur>, then switch the relays 'on', 'off' or 'toggle' them,
+            with --every=<dur> again and again until cancelled with ctrl-c
+  batch     run a JSON array of operations on one open port, see the README
+  simulate  run the operations of a batch on simulated cards and draw their relays after
+            each operation, no device needed
+  levels    read lines like '3=1' or '3=0' from stdin and switch the relay on or off,
+            until stdin is closed
+  replay    re-send the TX frames of a capture file and print the responses, as far
+            apart as they were recorded with --preserve-timing
+  counters  print the switch counts per relay, if the firmware keeps track of them
+  faults    print the faults reported by the cards, or reset them with --clear, if
+            the firmware keeps track of them
+  locate    strobe the relays of a card for a few seconds to identify it, restores the
+            relay states afterwards
+  selftest  switch every relay on and off on its own and check the states read back,
+            all relays are off during the test and restored afterwards
+  whoami    print the addresses the cards report without initialising them, to check
+            them against the software; cards ignoring broadcasts don't answer, scan for
+            them with --address-auto instead
+  ping      check that the cards answer a query of their relay states, for health
+            checks; neither initialises the cards nor switches any relay
+  option    print the option byte of a card, or write it with --set=<byte>; the card
+            keeps it across power cycles, see its manual for the bits
+  status    print whether the relays are on or off, one line like 'relay 3: on' per
+            relay, all relays if none are given; lines start with the card if
+            several cards are given
+  latency   switch relays on and measure how long until they read back on, relays
+            which were off are switched off again
+  encode    print the frame a command sends to each card as hex and binary, without
+            touching any device; set, toggle and reset take all relays if none are given
+  ws        serve JSON-RPC commands over a WebSocket and push state changes of the
+            cards to all clients, needs the 'ws' feature
+  daemon    keep the port open and initialised and run commands like 'set 1 3 on',
+            'toggle 2' or 'reset 1-4' read line by line from a Unix socket, each
+            answered with 'ok' or an error
+  mqtt      switch the relays of a card for messages 'on', 'off' or 'toggle' to
+            <prefix>/relay/<n>/set and publish their states to <prefix>/relay/<n>/state,
+            needs the 'mqtt' feature
+  serve     serve a REST API for dashboards: GET /relays lists the relay states,
+            POST /relays/<n> with {\"state\": \"on\"} switches a relay and
+            POST /relays/<n>/toggle toggles it; needs the 'http' feature
+  mirror    poll the source card and switch the relays of the target card to match it,
+            also after the target was switched by hand, until cancelled
+  watch     print the relay states of the cards and every change until cancelled
 
 Options:
   -h --help         Show this screen.
   -v --version      Show version.
-  --dev=<dev>       optional path to serial device, overrides the env var DEVICE
-  --relay=<relay>   number of relays (1..8), one flag per relay [default: 1 2 3 4 5 6 7 8]
-  --card=<card>     number of card (1..x), one flag per card [default: 0]
+  --json            print a JSON object instead of text: for version with the protocol
+                    spoken, for set, toggle, reset and status with the outcome
+  --dev=<dev>       optional path to serial device, overrides the env var DEVICE;
+                    tcp://<host>:<port> connects to a serial-to-Ethernet bridge
+  --no-lock         don't take the exclusive lock on the device, by default a second
+                    invocation on the same device fails with 'device busy'
+  --capture=<path>  record every frame sent and received to a file, see the README for
+                    the format, replay reads the frames to send from it
+  --address-map=<map>  map card numbers to addresses for chains with non-contiguous
+                    addresses, e.g. '1:1,2:3,3:7' sends --card=2 to address 3
+  --address-auto    use the first address a card answers on instead of 1, for boards
+                    with an unknown address
+  --card-relays=<counts>  relays per card for mixed chains, e.g. '2:4' for a 4 relay
+                    card as card 2, all other cards have 8
+  --max-cards=<n>   most cards in the chain, raise it for longer chains [default: 8]
+  --timeout-ms=<dur>  how long to wait for a card to answer a frame, raise it for busy
+                    machines or slow bridges; like all -ms flags it takes milliseconds
+                    or a duration like 1s500ms [default: 1000]
+  --init-timeout-ms=<dur>  how long to wait for each answer to the initialisation,
+                    raise it for long chains [default: 1000]
+  --baud=<baud>     baud rate the cards are jumpered to, one of 9600, 19200, 38400,
+                    57600 or 115200 [default: 19200]
+  --no-reconfigure  keep the serial settings of the port instead of setting the ones of
+                    the card, for TCP bridges and ports set up by another tool
+  --no-init         skip initialising the cards, for chains set up by a previous call;
+                    a card which lost its address after a power cycle doesn't respond
+  --skip-init-ack   don't wait for the cards to acknowledge the initialisation, for boards
+                    which never send it, a missing card then only shows on the next command
+  --min-interval-ms=<dur>  shortest time between two switches of a relay, a switch
+                    within it waits until it passed, 0 doesn't limit [default: 0]
+  --reject-too-frequent  fail instead of waiting if a relay is switched again
+                    within the --min-interval-ms
+  --normally-closed=<relays>  relays wired normally closed on every card, e.g. '2,4':
+                    'on' closes their circuit by releasing the coil, states are
+                    those of the circuits
+  --msb-first       relay 1 is the most significant bit of the data byte, for boards
+                    numbering their relays the other way round
+  --no-drain        don't discard stale bytes waiting on the port before every frame
+  --pipeline        write the frames for all cards before reading the first answer
+  --skip-redundant  don't send frames for relays already known to be in the wanted state
+  --echo-frames     print every frame sent (TX) and received (RX) as hex to stderr
+  --retries=<n>     resend a frame whose answer is garbled up to n times [default: 0]
+  --clear           reset the faults instead of printing them
+  --reset-on-error  switch all relays of the chain off if switching fails, instead of
+                    leaving them as they are
+  --broadcast       switch the relays of all cards with a single frame instead of one
+                    per card, the answers are not checked; ignores --card
+  --ordered         switch relays on one frame per relay in ascending order, instead of
+                    all at once
+  --clear-first     switch all relays off before switching the given ones on, all relays
+                    are briefly off in between
+  --show-state      query and print the resulting relay states of every affected card
+  --quiet-success   set, toggle and reset end with a summary line like 'OK: 3 cards,
+                    12 relays switched, 0 failures', print it only if something failed
+  -0 --null         end every printed entry with a NUL byte instead of a newline, for
+                    xargs -0
+  --reconnect-base-ms=<dur>  watch and at --every reopen a failed device, mqtt a lost
+                    broker, waiting this long before the first attempt [default: 1000]
+  --reconnect-max-ms=<dur>  the wait doubles with each attempt up to this [default: 60000]
+  --reconnect-attempts=<n>  attempts before giving up, 0 to fail at once [default: 5]
+  --debounce-ms=<dur>  only report changes which persist that long, against contact
+                    bounce of noisy boards [default: 0]
+  --source-card=<card>  card mirror copies the relay states from
+  --target-card=<card>  card mirror switches to match the source
+  --interval-ms=<dur>  how often mirror polls the cards [default: 200]
+  --port=<port>     port the WebSocket or the REST API listens on
+  --socket=<path>   path of the Unix socket daemon listens on
+  --set=<byte>      option byte option writes, like 0b11, 0x03 or 3
+  --broker=<url>    MQTT broker of mqtt, e.g. mqtt://192.168.1.10:1883
+  --topic=<prefix>  prefix of the topics of mqtt, e.g. home/relays
+  --bind=<addr>     address the WebSocket or the REST API listens on
+                    [default: 127.0.0.1]
+  --audit-csv=<dir>  ws also writes the relay states of the cards to a CSV file per day
+                    in this directory, for audit trails
+  --audit-interval=<dur>  how often ws writes the states to --audit-csv [default: 1m]
+  --ops=<json>      operations of a batch, all are validated before the first one runs
+  --sim-cards=<n>   cards in the chain of simulate [default: 1]
+  --preserve-timing  replay sends each frame as long after the first one as recorded
+  --speed=<factor>  replay the recording this many times faster, e.g. 2.0 or 0.5
+                    [default: 1.0]
+  --in=<dur>        how long to wait before switching, e.g. 500ms, 30s, 5m, 2h
+                    or 1m30s
+  --every=<dur>     repeat switching at this interval
+  --ms=<dur>         how long pulse keeps the relays on, at most 60000
+  --relay=<relay>   number of relays (1..8), one flag per relay or ranges like 1-4,6,
+                    all relays if omitted; set also takes relays with their states,
+                    e.g. 1:on,3:off
+  --config=<path>   TOML config with the address map, relay names, interlocks and
+                    momentary relays, long running modes reload it on change
+  --relay-alias-file=<path>  TOML file with [aliases] and [groups] naming relays, --relay
+                    then also takes these names
+  --mask=<mask>     relays as mask instead of --relay, bit 0 is relay 1, e.g. 0b00001010,
+                    0x0a or 10
+  --all             toggle all relays, prints the relay states before and after
+  --card=<card>     number of card (1..x), one flag per card [default: 1]
+
+Exit status:
+  0 success, 1 other failure, 2 no card answered, 3 device couldn't be opened,
+  4 timeout, 5 bad checksum, 64 invalid arguments
 ";
 
 #[derive(Debug, Deserialize)]
@@ -40,86 +238,1518 @@ struct Args {
     cmd_set: bool,
     cmd_toggle: bool,
     cmd_reset: bool,
+    cmd_replay: bool,
+    cmd_at: bool,
+    cmd_pulse: bool,
+    cmd_write: bool,
+    cmd_all_off: bool,
+    cmd_batch: bool,
+    cmd_levels: bool,
+    cmd_simulate: bool,
+    cmd_counters: bool,
+    cmd_locate: bool,
+    cmd_faults: bool,
+    cmd_selftest: bool,
+    cmd_latency: bool,
+    cmd_whoami: bool,
+    cmd_ping: bool,
+    cmd_option: bool,
+    cmd_status: bool,
+    cmd_encode: bool,
+    cmd_init: bool,
+    cmd_query: bool,
+    cmd_ws: bool,
+    cmd_daemon: bool,
+    cmd_mqtt: bool,
+    cmd_serve: bool,
+    cmd_watch: bool,
+    cmd_mirror: bool,
     flag_dev: Option<String>,
+    flag_no_lock: bool,
+    flag_capture: Option<String>,
     flag_version: bool,
+    cmd_version: bool,
+    flag_json: bool,
     flag_help: bool,
+    #[serde(rename = "flag_relay")]
+    relay_specs: Vec<String>,
+    // from --relay or --mask, after parsing
+    #[serde(skip)]
     flag_relay: Option<RelayIndex>,
+    // affected relays and their states from --relay=1:on,3:off
+    #[serde(skip)]
+    relay_states: Option<(u8, u8)>,
+    flag_mask: Option<String>,
+    flag_all: bool,
+    flag_show_state: bool,
+    flag_quiet_success: bool,
+    flag_null: bool,
+    flag_clear_first: bool,
+    flag_broadcast: bool,
+    flag_ordered: bool,
+    flag_reset_on_error: bool,
+    flag_clear: bool,
+    flag_in: Option<String>,
+    flag_ops: Option<String>,
+    flag_sim_cards: u8,
+    flag_every: Option<String>,
+    flag_echo_frames: bool,
+    flag_retries: u8,
+    flag_skip_init_ack: bool,
+    flag_no_init: bool,
+    flag_ms: String,
+    flag_timeout_ms: String,
+    flag_init_timeout_ms: String,
+    flag_max_cards: u8,
+    flag_address_auto: bool,
+    flag_skip_redundant: bool,
+    flag_msb_first: bool,
+    flag_preserve_timing: bool,
+    flag_speed: f64,
+    flag_baud: u32,
+    flag_no_reconfigure: bool,
+    flag_no_drain: bool,
+    flag_pipeline: bool,
+    flag_min_interval_ms: String,
+    flag_reject_too_frequent: bool,
+    flag_debounce_ms: String,
+    flag_source_card: u8,
+    flag_target_card: u8,
+    flag_interval_ms: String,
+    flag_reconnect_base_ms: String,
+    flag_reconnect_max_ms: String,
+    flag_reconnect_attempts: u32,
+    #[cfg_attr(not(any(feature = "ws", feature = "http")), allow(dead_code))]
+    flag_port: Option<u16>,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    flag_socket: Option<String>,
+    flag_set: Option<String>,
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    flag_broker: Option<String>,
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    flag_topic: Option<String>,
+    #[cfg_attr(not(any(feature = "ws", feature = "http")), allow(dead_code))]
+    flag_bind: String,
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    flag_audit_csv: Option<String>,
+    #[cfg_attr(not(feature = "ws"), allow(dead_code))]
+    flag_audit_interval: String,
+    flag_address_map: Option<String>,
+    flag_card_relays: Option<String>,
+    flag_normally_closed: Option<String>,
+    flag_config: Option<String>,
+    flag_relay_alias_file: Option<String>,
     flag_card: Option<CardIndex>,
     arg_state: String,
+    arg_mask: String,
+}
+
+/// exit status of the tool, for scripts telling failures apart
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExitCode {
+    /// any other failure
+    Failure = 1,
+    /// no card answered, e.g. the device is no relay card or a card is missing
+    NoCard = 2,
+    /// the device couldn't be opened or is locked by another process
+    OpenFailed = 3,
+    /// a card didn't answer in time
+    Timeout = 4,
+    /// a response had a wrong checksum
+    ChecksumError = 5,
+    /// invalid command line arguments, like EX_USAGE of sysexits.h
+    InvalidArgs = 64,
+}
+
+impl ExitCode {
+    /// exit status for a failure of `run`
+    ///
+    /// errors of the arguments, a missing card or a device which can't be opened are
+    /// marked where they happen, the kind of an error alone doesn't tell them apart
+    fn of(e: &io::Error) -> Self {
+        if let Some(inner) = e.get_ref() {
+            if let Some(marked) = inner.downcast_ref::<Marked>() {
+                return marked.code;
+            }
+            if inner.is::<ChecksumError>() {
+                return ExitCode::ChecksumError;
+            }
+            if let Some(e) = inner.downcast_ref::<Relay8xError>() {
+                return ExitCode::of_relay(e);
+            }
+        }
+        match e.kind() {
+            io::ErrorKind::TimedOut => ExitCode::Timeout,
+            _ => ExitCode::Failure,
+        }
+    }
+
+    /// exit status for a failure of the cards, that of all failed cards if they agree
+    fn of_relay(e: &Relay8xError) -> Self {
+        match *e {
+            Relay8xError::Timeout { .. } => ExitCode::Timeout,
+            Relay8xError::BadChecksum(_) => ExitCode::ChecksumError,
+            Relay8xError::Io(ref e) => ExitCode::of(e),
+            Relay8xError::Partial { ref failed, .. } => {
+                let mut codes = failed.iter().map(|(_, e)| ExitCode::of_relay(e));
+                match codes.next() {
+                    Some(code) if codes.all(|other| other == code) => code,
+                    _ => ExitCode::Failure,
+                }
+            }
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+/// an error marked with the exit status it ends the tool with
+#[derive(Debug)]
+struct Marked {
+    code: ExitCode,
+    error: io::Error,
 }
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-const NAME: &'static str = env!("CARGO_PKG_NAME");
+impl fmt::Display for Marked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for Marked {}
+
+/// marks errors for the exit status `code`, e.g. `.map_err(mark(ExitCode::OpenFailed))`
+fn mark<E: Into<io::Error>>(code: ExitCode) -> impl Fn(E) -> io::Error + Copy {
+    move |error| {
+        let error = error.into();
+        io::Error::new(error.kind(), Marked { code, error })
+    }
+}
+
+/// parses the value of a `-ms` flag, milliseconds like `1500` or a duration like `1s500ms`
+fn parse_ms(spec: &str) -> io::Result<Duration> {
+    match spec.trim().parse() {
+        Ok(millis) => Ok(Duration::from_millis(millis)),
+        Err(_) => parse_duration(spec).map_err(mark(ExitCode::InvalidArgs)),
+    }
+}
+
+/// invalid command line arguments, exiting with `ExitCode::InvalidArgs`
+fn invalid_args<M: Into<String>>(msg: M) -> io::Error {
+    mark(ExitCode::InvalidArgs)(io::Error::new(io::ErrorKind::InvalidInput, msg.into()))
+}
+
+/// marks an error of the initialisation or a scan as `ExitCode::NoCard` if no card
+/// answered, these report it as `NotFound`
+fn no_card(e: Relay8xError) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::NotFound => mark(ExitCode::NoCard)(e),
+        _ => e.into(),
+    }
+}
+
+/// lock the device against concurrent use by other processes unless opted out
+///
+/// a TCP bridge has no device node to lock, it serialises its clients itself
+fn lock_device(device: &str, no_lock: bool) -> io::Result<Option<DeviceLock>> {
+    if no_lock || device.starts_with(TCP_SCHEME) {
+        Ok(None)
+    } else {
+        DeviceLock::acquire(device)
+            .map(Some)
+            .map_err(mark(ExitCode::OpenFailed))
+    }
+}
+
+/// settings of the card chain from the command line
+fn config(args: &Args) -> io::Result<Relay8xConfig> {
+    let invalid = mark(ExitCode::InvalidArgs);
+    let address_map = match args.flag_address_map {
+        Some(ref spec) => Some(parse_address_map(spec).map_err(invalid)?),
+        None => None,
+    };
+    let card_relays = match args.flag_card_relays {
+        Some(ref spec) => parse_card_relays(spec).map_err(invalid)?,
+        None => BTreeMap::new(),
+    };
+    let normally_closed = match args.flag_normally_closed {
+        Some(ref spec) => expand_relays(spec)
+            .map_err(io::Error::from)
+            .map_err(invalid)?,
+        None => Vec::new(),
+    };
+    check_baud(args.flag_baud).map_err(invalid)?;
+    let timeout = parse_ms(&args.flag_timeout_ms)?;
+    check_timeout(timeout).map_err(invalid)?;
+    // address of relay is always 1 as for now
+    let mut config = Relay8xConfig {
+        baud: args.flag_baud,
+        address: 1,
+        address_map,
+        card_relays,
+        normally_closed,
+        skip_init_ack: args.flag_skip_init_ack,
+        skip_redundant: args.flag_skip_redundant,
+        max_cards: args.flag_max_cards,
+        timeout,
+        init_timeout: parse_ms(&args.flag_init_timeout_ms)?,
+        ordered: args.flag_ordered,
+        drain: !args.flag_no_drain,
+        pipeline: args.flag_pipeline,
+        reconfigure: !args.flag_no_reconfigure,
+        msb_first: args.flag_msb_first,
+        min_interval: parse_ms(&args.flag_min_interval_ms)?,
+        rate_limit_policy: if args.flag_reject_too_frequent {
+            RateLimitPolicy::Reject
+        } else {
+            RateLimitPolicy::Delay
+        },
+        ..Default::default()
+    };
+    if let Some(ref path) = args.flag_config {
+        let file = ConfigFile::load(path)?;
+        if file.address_map.is_some() && args.flag_address_map.is_some() {
+            return Err(invalid_args(
+                "Give the address map either in the config or with --address-map",
+            ));
+        }
+        file.apply(&mut config);
+    }
+    Ok(config)
+}
+
+/// lock and open the device, recording to the capture file if requested
+fn open_device(device: &str, args: &Args) -> io::Result<(Option<DeviceLock>, Relay8x)> {
+    let config = config(args)?;
+    let lock = lock_device(device, args.flag_no_lock)?;
+    let mut relay = Relay8x::with_config(device, config).map_err(mark(ExitCode::OpenFailed))?;
+    relay.set_echo_frames(args.flag_echo_frames);
+    relay.set_retries(args.flag_retries);
+    if let Some(ref path) = args.flag_capture {
+        relay.set_capture(Capture::create(path)?);
+    }
+    Ok((lock, relay))
+}
+
+/// initialise the chain, looking for the first card's address first if requested
+///
+/// with --no-init only the serial port is set up and the cards keep their addresses,
+/// returns the number of cards answering the initialisation; 0 with --no-init or
+/// --skip-init-ack, which don't count the cards
+fn init_device(relay: &mut Relay8x, args: &Args) -> io::Result<u8> {
+    relay.configure_port()?;
+    if args.flag_address_auto {
+        let address = relay.auto_address().map_err(no_card)?;
+        println!("Using card at address {}", address);
+    }
+    if args.flag_no_init {
+        return Ok(0);
+    }
+    relay.configure_device().map_err(no_card)
+}
+
+/// print one entry of a list, terminated by a newline or with `--null` by a NUL byte
+fn print_entry(null: bool, entry: String) {
+    if null {
+        print!("{}\0", entry);
+    } else {
+        println!("{}", entry);
+    }
+}
+
+/// query and print the relay states of the cards, one entry per card
+fn print_states(relay: &mut Relay8x, cards: CardIndex, null: bool) -> io::Result<()> {
+    let mut unreachable = Vec::new();
+    for (&card, (_, state)) in cards.iter().zip(relay.sweep(cards.clone())?) {
+        match state {
+            Some(state) => print_entry(null, format!("card {}: {:08b}", card, state)),
+            None => {
+                unreachable.push(card);
+                print_entry(null, format!("card {}: unreachable", card));
+            }
+        }
+    }
+    if !unreachable.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("No answer from cards {:?}", unreachable),
+        ));
+    }
+    Ok(())
+}
+
+/// state of one relay in a --json report
+#[derive(Serialize)]
+struct RelayStatus {
+    card: u8,
+    relay: u8,
+    state: &'static str,
+}
+
+/// outcome of set, toggle, reset and status printed with --json instead of text
+#[derive(Serialize)]
+struct Report {
+    dev: String,
+    command: &'static str,
+    cards: CardIndex,
+    relays: RelayIndex,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    // relay states read by status or --show-state
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    states: Vec<RelayStatus>,
+}
 
-fn main() -> io::Result<()> {
+impl Report {
+    /// report on the command of `args`, none without --json or for other commands
+    fn of(args: &Args, device: &str) -> Option<Self> {
+        let command = match () {
+            _ if !args.flag_json => return None,
+            _ if args.cmd_set => "set",
+            _ if args.cmd_toggle => "toggle",
+            _ if args.cmd_reset => "reset",
+            _ if args.cmd_status => "status",
+            _ => return None,
+        };
+        let state = match command {
+            "set" if args.relay_states.is_none() => Some(args.arg_state.clone()),
+            "reset" => Some("off".to_string()),
+            _ => None,
+        };
+        let cards = args.flag_card.clone().unwrap_or_default();
+        let relays = match args.relay_states {
+            Some((affect, _)) => u8_as_relays(affect),
+            None => {
+                // all relays of the largest card, smaller cards switch theirs
+                let count = config(args)
+                    .ok()
+                    .and_then(|config| cards.iter().map(|&card| config.relay_count(card)).max());
+                relays_or_all(args.flag_relay.clone(), count.unwrap_or(8))
+            }
+        };
+        Some(Self {
+            dev: device.to_string(),
+            command,
+            cards,
+            relays,
+            state,
+            ok: false,
+            error: None,
+            states: Vec::new(),
+        })
+    }
+
+    /// print the report with the outcome of the command and pass it on
+    fn print(mut self, result: io::Result<()>) -> io::Result<()> {
+        self.ok = result.is_ok();
+        self.error = result.as_ref().err().map(|e| e.to_string());
+        println!(
+            "{}",
+            serde_json::to_string(&self).map_err(io::Error::other)?
+        );
+        result
+    }
+}
+
+/// states of the relays `numbers` of the cards, all relays of a card if none are given
+fn relay_status(
+    relay: &mut Relay8x,
+    cards: &CardIndex,
+    numbers: &Option<RelayIndex>,
+) -> io::Result<Vec<RelayStatus>> {
+    let mut status = Vec::new();
+    for (&card, state) in cards.iter().zip(relay.get_relays(cards.clone())?) {
+        let numbers = match *numbers {
+            Some(ref numbers) if !numbers.is_empty() => numbers.clone(),
+            _ => relay.all_relays(card),
+        };
+        for (number, on) in iter_relays(state).filter(|&(r, _)| numbers.contains(&r)) {
+            status.push(RelayStatus {
+                card,
+                relay: number,
+                state: if on { "on" } else { "off" },
+            });
+        }
+    }
+    Ok(status)
+}
+
+/// print the relay states for --show-state, with --json they go into the report
+fn show_states(
+    relay: &mut Relay8x,
+    cards: CardIndex,
+    null: bool,
+    report: &mut Option<Report>,
+) -> io::Result<()> {
+    match *report {
+        Some(ref mut report) => {
+            report.states = relay_status(relay, &cards, &None)?;
+            Ok(())
+        }
+        None => print_states(relay, cards, null),
+    }
+}
+
+/// the given relays or, if none are given, relays 1 to `count`
+fn relays_or_all(relays: Option<RelayIndex>, count: u8) -> RelayIndex {
+    match relays {
+        Some(ref numbers) if !numbers.is_empty() => numbers.clone(),
+        _ => (1..=count).collect(),
+    }
+}
+
+/// watcher of the --config file for long running modes, which reload it on change
+fn watcher(args: &Args) -> io::Result<Option<ConfigWatcher>> {
+    let mut watcher = match args.flag_config {
+        Some(ref path) => ConfigWatcher::new(path)?,
+        None => return Ok(None),
+    };
+    // reloads keep checking that the map is given either way, not both
+    if let Some(ref spec) = args.flag_address_map {
+        let map = parse_address_map(spec).map_err(mark(ExitCode::InvalidArgs))?;
+        watcher
+            .set_address_map(map)
+            .map_err(mark(ExitCode::InvalidArgs))?;
+    }
+    Ok(Some(watcher))
+}
+
+/// serve the WebSocket endpoint until the device fails
+#[cfg(feature = "ws")]
+fn serve_ws(device: &str, args: &Args) -> io::Result<()> {
+    let (_lock, mut relay) = open_device(device, args)?;
+    init_device(&mut relay, args)?;
+    let audit = match args.flag_audit_csv {
+        Some(ref dir) => Some(AuditLog::new(
+            dir,
+            parse_duration(&args.flag_audit_interval).map_err(mark(ExitCode::InvalidArgs))?,
+        )),
+        None => None,
+    };
+    let listener = TcpListener::bind((args.flag_bind.as_str(), args.flag_port.unwrap_or(0)))?;
+    println!("Listening on ws://{}", listener.local_addr()?);
+    relay8x::ws::serve(
+        relay,
+        listener,
+        args.flag_card.clone().unwrap_or_default(),
+        audit,
+        watcher(args)?,
+    )
+}
+
+#[cfg(not(feature = "ws"))]
+fn serve_ws(_device: &str, _args: &Args) -> io::Result<()> {
+    Err(io::Error::other(
+        "WebSocket support is not built in, rebuild with --features ws",
+    ))
+}
+
+/// run the commands sent to the Unix socket until the device fails
+#[cfg(unix)]
+fn serve_daemon(device: &str, args: &Args) -> io::Result<()> {
+    let (_lock, mut relay) = open_device(device, args)?;
+    init_device(&mut relay, args)?;
+    let path = args.flag_socket.clone().unwrap_or_default();
+    let listener = relay8x::daemon::bind(&path)?;
+    println!("Listening on {}", path);
+    let card_numbers = args.flag_card.clone().unwrap_or_default();
+    relay8x::daemon::serve(&mut relay, &card_numbers, listener, watcher(args)?)
+}
+
+#[cfg(not(unix))]
+fn serve_daemon(_device: &str, _args: &Args) -> io::Result<()> {
+    Err(io::Error::other("The daemon needs Unix sockets"))
+}
+
+/// bridge a card to the MQTT broker until the device fails or the broker is gone
+#[cfg(feature = "mqtt")]
+fn serve_mqtt(device: &str, args: &Args) -> io::Result<()> {
+    let cards = args.flag_card.clone().unwrap_or_default();
+    if cards.len() != 1 {
+        return Err(invalid_args(
+            "mqtt bridges a single card, pick it with --card",
+        ));
+    }
+    let broker = relay8x::mqtt::parse_broker(args.flag_broker.as_deref().unwrap_or_default())
+        .map_err(mark(ExitCode::InvalidArgs))?;
+    let prefix = args.flag_topic.clone().unwrap_or_default();
+    let backoff = backoff(args)?;
+    let (_lock, mut relay) = open_device(device, args)?;
+    init_device(&mut relay, args)?;
+    println!(
+        "Bridging card {} to {}:{} as {}",
+        cards[0], broker.host, broker.port, prefix
+    );
+    relay8x::mqtt::serve(
+        &mut relay,
+        cards[0],
+        &broker,
+        &prefix,
+        backoff,
+        watcher(args)?,
+    )
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn serve_mqtt(_device: &str, _args: &Args) -> io::Result<()> {
+    Err(io::Error::other(
+        "MQTT support is not built in, rebuild with --features mqtt",
+    ))
+}
+
+/// serve the REST API until the server fails
+#[cfg(feature = "http")]
+fn serve_http(device: &str, args: &Args) -> io::Result<()> {
+    let (_lock, mut relay) = open_device(device, args)?;
+    init_device(&mut relay, args)?;
+    let address = (args.flag_bind.as_str(), args.flag_port.unwrap_or(0));
+    let server = tiny_http::Server::http(address).map_err(io::Error::other)?;
+    println!("Listening on http://{}", server.server_addr());
+    relay8x::http::serve(
+        relay,
+        server,
+        args.flag_card.clone().unwrap_or_default(),
+        watcher(args)?,
+    )
+}
+
+#[cfg(not(feature = "http"))]
+fn serve_http(_device: &str, _args: &Args) -> io::Result<()> {
+    Err(io::Error::other(
+        "REST API support is not built in, rebuild with --features http",
+    ))
+}
+
+/// passes `result` on, with --reset-on-error a failure first switches off all relays of
+/// the chain; each card has to acknowledge that, if one doesn't the reset is broadcast as
+/// well, which reaches cards that stopped answering but is confirmed by none
+fn or_reset<T, E: Into<io::Error>>(
+    result: Result<T, E>,
+    relay: &mut Relay8x,
+    reset_on_error: bool,
+) -> io::Result<T> {
+    let err = match result {
+        Ok(value) => return Ok(value),
+        Err(e) => e.into(),
+    };
+    if reset_on_error {
+        match relay.reset_all() {
+            Ok(()) => eprintln!("Switched all relays off after error"),
+            Err(e) => {
+                eprintln!("Failed to switch all relays off after error: {}", e);
+                let all = (1..=relay.max_relays()).collect();
+                match relay.broadcast_relays(all, false) {
+                    Ok(()) => eprintln!("Reset broadcast sent, not confirmed by the cards"),
+                    Err(e) => eprintln!("Failed to broadcast the reset: {}", e),
+                }
+            }
+        }
+    }
+    Err(err)
+}
+
+/// counts cards and relays switched by set, toggle and reset for the summary line
+struct Summary {
+    null: bool,
+    quiet_success: bool,
+    // with --json the report tells how it went, nothing is printed
+    json: bool,
+    // whether any operation was counted, broadcasts aren't
+    counted: bool,
+    cards: usize,
+    relays: usize,
+    // 'card 3: <error>' per failure
+    failures: Vec<String>,
+}
+
+impl Summary {
+    fn new(null: bool, quiet_success: bool, json: bool) -> Self {
+        Self {
+            null,
+            quiet_success,
+            json,
+            counted: false,
+            cards: 0,
+            relays: 0,
+            failures: Vec::new(),
+        }
+    }
+
+    /// counts the outcome of switching `relays` relays on each of `cards` and passes it
+    /// on, a failure ends the command so the summary is printed right away
+    fn count<T>(
+        &mut self,
+        cards: &[u8],
+        relays: usize,
+        result: Result<T, Relay8xError>,
+    ) -> Result<T, Relay8xError> {
+        self.counted = true;
+        match result {
+            Ok(_) => {
+                self.cards += cards.len();
+                self.relays += cards.len() * relays;
+            }
+            Err(Relay8xError::Partial {
+                ref done,
+                ref failed,
+            }) => {
+                self.cards += done.len();
+                self.relays += done.len() * relays;
+                for &(card, ref e) in failed {
+                    self.failures.push(format!("card {}: {}", card, e));
+                }
+            }
+            Err(ref e) => {
+                let failed = match *e {
+                    Relay8xError::Timeout { card, .. } => format!("card {}", card),
+                    _ if cards.len() == 1 => format!("card {}", cards[0]),
+                    // failing fast, it is unknown which of the cards were switched
+                    _ => format!("cards {:?}", cards),
+                };
+                self.failures.push(format!("{}: {}", failed, e));
+            }
+        }
+        if result.is_err() {
+            self.print();
+        }
+        result
+    }
+
+    /// prints the summary line, unless all went well and --quiet-success is given or the
+    /// outcome goes into the --json report
+    fn print(&self) {
+        if self.json || !self.counted || (self.failures.is_empty() && self.quiet_success) {
+            return;
+        }
+        let mut line = format!(
+            "{}: {} card{}, {} relay{} switched, {} failure{}",
+            if self.failures.is_empty() {
+                "OK"
+            } else {
+                "FAILED"
+            },
+            self.cards,
+            if self.cards == 1 { "" } else { "s" },
+            self.relays,
+            if self.relays == 1 { "" } else { "s" },
+            self.failures.len(),
+            if self.failures.len() == 1 { "" } else { "s" },
+        );
+        if !self.failures.is_empty() {
+            line = format!("{} ({})", line, self.failures.join(", "));
+        }
+        print_entry(self.null, line);
+    }
+}
+
+/// reconnect delays given by the --reconnect flags
+fn backoff(args: &Args) -> io::Result<Backoff> {
+    Backoff::new(
+        parse_ms(&args.flag_reconnect_base_ms)?,
+        parse_ms(&args.flag_reconnect_max_ms)?,
+        args.flag_reconnect_attempts,
+    )
+    .map_err(mark(ExitCode::InvalidArgs))
+}
+
+/// reopen and initialise the device after it failed with `cause`, waiting between the
+/// attempts as given by `backoff`; fails with the last error once all attempts failed
+fn reconnect(
+    device: &str,
+    args: &Args,
+    backoff: &Backoff,
+    failed: (Option<DeviceLock>, Relay8x),
+    cause: io::Error,
+    cancelled: &AtomicBool,
+) -> io::Result<(Option<DeviceLock>, Relay8x)> {
+    // release the lock before taking it again
+    drop(failed);
+    let mut cause = cause;
+    for attempt in 0..backoff.attempts {
+        let delay = backoff.delay(attempt);
+        eprintln!(
+            "Device failed: {}, reconnecting in {} ms (attempt {} of {})",
+            cause,
+            delay.as_millis(),
+            attempt + 1,
+            backoff.attempts
+        );
+        if !wait(delay, cancelled) {
+            break;
+        }
+        let session = open_device(device, args).and_then(|mut session| {
+            init_device(&mut session.1, args)?;
+            Ok(session)
+        });
+        match session {
+            Ok(session) => {
+                eprintln!("Reconnected to {}", device);
+                return Ok(session);
+            }
+            Err(e) => cause = e,
+        }
+    }
+    Err(cause)
+}
+
+/// sleeps for `duration` unless cancelled in between, returns false if cancelled
+fn wait(duration: Duration, cancelled: &AtomicBool) -> bool {
+    let start = Instant::now();
+    while !cancelled.load(Ordering::SeqCst) {
+        let elapsed = start.elapsed();
+        if elapsed >= duration {
+            return true;
+        }
+        thread::sleep((duration - elapsed).min(Duration::from_millis(100)));
+    }
+    false
+}
+
+/// switch the relays after a delay and, with --every, periodically
+fn at(device: &str, args: &Args) -> io::Result<()> {
+    let delay = match args.flag_in {
+        Some(ref spec) => parse_duration(spec).map_err(mark(ExitCode::InvalidArgs))?,
+        None => Duration::from_secs(0),
+    };
+    let every = match args.flag_every {
+        Some(ref spec) => Some(parse_duration(spec).map_err(mark(ExitCode::InvalidArgs))?),
+        None => None,
+    };
+    if every == Some(Duration::from_secs(0)) {
+        return Err(invalid_args("--every needs a duration above 0"));
+    }
+    let state = args.arg_state.as_str();
+    if !["on", "off", "toggle"].contains(&state) {
+        return Err(invalid_args(
+            "Failed to determine state, use 'on', 'off' or 'toggle'.",
+        ));
+    }
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let handler = cancelled.clone();
+    ctrlc::set_handler(move || handler.store(true, Ordering::SeqCst))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    let backoff = backoff(args)?;
+    let mut session = open_device(device, args)?;
+    init_device(&mut session.1, args)?;
+    let card_numbers = args.flag_card.clone().unwrap_or_default();
+    let mut next = delay;
+    while wait(next, &cancelled) {
+        let relay = &mut session.1;
+        let groups = relay.relay_groups(
+            &card_numbers,
+            args.flag_relay.as_deref().unwrap_or_default(),
+        );
+        let result = groups.into_iter().try_for_each(|(cards, numbers)| {
+            match state {
+                "on" => relay.set_relays(cards, numbers),
+                "off" => relay.reset_relays(cards, numbers),
+                _ => relay.toggle_relays(cards, numbers),
+            }
+            .map(|_| ())
+        });
+        if let Err(e) = or_reset(result, relay, args.flag_reset_on_error) {
+            if every.is_none() {
+                return Err(e);
+            }
+            session = reconnect(device, args, &backoff, session, e, &cancelled)?;
+        }
+        match every {
+            Some(interval) => next = interval,
+            None => return Ok(()),
+        }
+    }
+    println!("Cancelled");
+    Ok(())
+}
+
+/// print the relay states and their changes until cancelled
+fn watch(device: &str, args: &Args) -> io::Result<()> {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let handler = cancelled.clone();
+    ctrlc::set_handler(move || handler.store(true, Ordering::SeqCst))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    let backoff = backoff(args)?;
+    let mut session = open_device(device, args)?;
+    init_device(&mut session.1, args)?;
+    let card_numbers = args.flag_card.clone().unwrap_or_default();
+    let mut debouncer = Debouncer::new(parse_ms(&args.flag_debounce_ms)?);
+    let states = session.1.get_relays(card_numbers.clone())?;
+    for (&card, &state) in card_numbers.iter().zip(&states) {
+        debouncer.update(card, state, Instant::now());
+        print_entry(args.flag_null, format!("card {}: {:08b}", card, state));
+    }
+    while wait(WATCH_INTERVAL, &cancelled) {
+        let states = match session.1.get_relays(card_numbers.clone()) {
+            Ok(states) => states,
+            Err(e) => {
+                session = reconnect(device, args, &backoff, session, e.into(), &cancelled)?;
+                continue;
+            }
+        };
+        for (&card, state) in card_numbers.iter().zip(states) {
+            for (number, on) in debouncer.update(card, state, Instant::now()) {
+                let state = if on { "on" } else { "off" };
+                print_entry(
+                    args.flag_null,
+                    format!("card {} relay {}: {}", card, number, state),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// switch the target card to the states of the source card until cancelled
+fn mirror(device: &str, args: &Args) -> io::Result<()> {
+    let (source, target) = (args.flag_source_card, args.flag_target_card);
+    if source == target {
+        return Err(invalid_args("Source and target card are the same"));
+    }
+    let interval = parse_ms(&args.flag_interval_ms)?;
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let handler = cancelled.clone();
+    ctrlc::set_handler(move || handler.store(true, Ordering::SeqCst))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    let backoff = backoff(args)?;
+    let mut session = open_device(device, args)?;
+    init_device(&mut session.1, args)?;
+    // the first pass syncs the target without waiting
+    let mut next = Duration::from_secs(0);
+    while wait(next, &cancelled) {
+        next = interval;
+        match session.1.mirror_card(source, target) {
+            Ok(Some(state)) => {
+                print_entry(args.flag_null, format!("card {}: {:08b}", target, state))
+            }
+            Ok(None) => {}
+            Err(e) => {
+                session = reconnect(device, args, &backoff, session, e.into(), &cancelled)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// how often `watch` polls the cards
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// how long `latency` waits for a relay to read back on
+const LATENCY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// how long `locate` strobes the relays
+const LOCATE_SECONDS: u32 = 5;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const NAME: &str = env!("CARGO_PKG_NAME");
+
+#[derive(Serialize)]
+struct VersionInfo {
+    name: &'static str,
+    version: &'static str,
+    protocol: &'static str,
+}
+
+/// print name and version, with --json also the protocol
+fn print_version(json: bool) -> io::Result<()> {
+    if !json {
+        println!("{}: {}", NAME, VERSION);
+        return Ok(());
+    }
+    let info = VersionInfo {
+        name: NAME,
+        version: VERSION,
+        protocol: PROTOCOL,
+    };
+    println!(
+        "{}",
+        serde_json::to_string(&info).map_err(io::Error::other)?
+    );
+    Ok(())
+}
+
+/// run a batch on simulated cards, drawing the relays before and after every operation
+fn simulate(args: &Args) -> io::Result<()> {
+    let ops = rpc::parse_batch(args.flag_ops.as_deref().unwrap_or_default())
+        .map_err(mark(ExitCode::InvalidArgs))?;
+    let sim = SimulatedRelays::new(args.flag_sim_cards);
+    let mut relay = Relay8x::from_transport_with_config(sim.clone(), config(args)?);
+    relay.configure_device()?;
+    let card_numbers = args.flag_card.clone().unwrap_or_default();
+    print!("{}", sim.panel());
+    for (i, op) in ops.iter().enumerate() {
+        match (op, op.request()) {
+            (&rpc::Operation::Sleep { ms }, _) => thread::sleep(Duration::from_millis(ms)),
+            (_, Some(mut request)) => {
+                if request.params.cards.is_empty() {
+                    request.params.cards = card_numbers.clone();
+                }
+                let failed = |e: io::Error| {
+                    let code = ExitCode::of(&e);
+                    mark(code)(io::Error::new(
+                        e.kind(),
+                        format!("Operation {} failed: {}", i, e),
+                    ))
+                };
+                for &card in &request.params.cards {
+                    relay.check_card(card).map_err(no_card).map_err(failed)?;
+                }
+                rpc::execute(&mut relay, &request)
+                    .map_err(io::Error::from)
+                    .map_err(failed)?;
+            }
+            (_, None) => {}
+        }
+        println!();
+        println!("{}: {:?}", i, op);
+        print!("{}", sim.panel());
+    }
+    Ok(())
+}
+
+/// print the frame of the encode command for each card, as hex and binary
+fn print_frames(args: &Args) -> io::Result<()> {
+    if args.relay_states.is_some() {
+        return Err(invalid_args(
+            "encode takes relay numbers, not relays with states",
+        ));
+    }
+    let cmd = if args.cmd_init {
+        Relay8xCmdSet::Init
+    } else if args.cmd_query {
+        Relay8xCmdSet::GetPort
+    } else if args.cmd_set {
+        Relay8xCmdSet::Set
+    } else if args.cmd_toggle {
+        Relay8xCmdSet::Toggle
+    } else {
+        Relay8xCmdSet::Reset
+    };
+    let config = config(args)?;
+    for &card in args.flag_card.as_deref().unwrap_or_default() {
+        let mut relays = relays_or_all(args.flag_relay.clone(), config.relay_count(card));
+        if args.flag_msb_first {
+            // the frame carries the relay numbers as the board counts them
+            relays = relays
+                .iter()
+                .map(|&r| if (1..=8).contains(&r) { 9 - r } else { r })
+                .collect();
+        }
+        let frame = build_frame(cmd.clone(), card, &relays)?;
+        let bits: Vec<String> = frame.iter().map(|byte| format!("{:08b}", byte)).collect();
+        print_entry(
+            args.flag_null,
+            format!("{}  {}", Frame(&frame), bits.join(" ")),
+        );
+    }
+    Ok(())
+}
+
+fn main() {
     env_logger::init();
 
-    let args: Args = Docopt::new(USAGE)
+    // print errors plainly, e.g. 'card 2 did not respond within 1000ms'
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        process::exit(ExitCode::of(&e) as i32);
+    }
+}
+
+fn run() -> io::Result<()> {
+    let mut args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
-        .unwrap_or_else(|e| e.exit());
+        .unwrap_or_else(|e| {
+            // help and version exit successfully, usage errors with InvalidArgs
+            if e.fatal() {
+                eprintln!("{}", e);
+                process::exit(ExitCode::InvalidArgs as i32);
+            }
+            e.exit()
+        });
+    // --relay lists relay numbers or, for set, relays with their states
+    if args.relay_specs.iter().any(|spec| spec.contains(':')) {
+        if !args.cmd_set {
+            return Err(invalid_args(
+                "Relays with states like 1:on only work with set",
+            ));
+        }
+        args.relay_states = Some(
+            parse_relay_states(&args.relay_specs.join(",")).map_err(mark(ExitCode::InvalidArgs))?,
+        );
+    } else {
+        let numbers = args
+            .relay_specs
+            .iter()
+            .flat_map(|spec| spec.split_whitespace());
+        let mut names = match args.flag_config {
+            Some(ref path) => Some(ConfigFile::load(path)?),
+            None => None,
+        };
+        if let Some(ref path) = args.flag_relay_alias_file {
+            let aliases = AliasFile::load(path)?;
+            names
+                .get_or_insert_with(ConfigFile::default)
+                .merge_aliases(aliases);
+        }
+        args.flag_relay = Some(match names {
+            // relay numbers win over names, so a name never shadows a relay
+            Some(names) => {
+                let relays = numbers.map(|token| names.resolve_relays(token));
+                let relays = relays.collect::<Result<Vec<RelayIndex>, _>>();
+                relays.map_err(mark(ExitCode::InvalidArgs))?.concat()
+            }
+            None => {
+                let relays = numbers.map(expand_relays);
+                let relays = relays.collect::<Result<Vec<RelayIndex>, _>>();
+                relays.map_err(mark(ExitCode::InvalidArgs))?.concat()
+            }
+        });
+    }
+    // a mask is just another way to list relays
+    if let Some(ref spec) = args.flag_mask {
+        if !args.relay_specs.is_empty() {
+            return Err(invalid_args("--mask and --relay are mutually exclusive"));
+        }
+        // the mask is the same for every card, so it must fit the smallest of them
+        let config = config(&args)?;
+        let cards = args.flag_card.clone().unwrap_or_default();
+        let relays = cards.iter().map(|&card| config.relay_count(card)).min();
+        let mask = parse_mask(spec, relays.unwrap_or(config.relays_per_card))
+            .map_err(mark(ExitCode::InvalidArgs))?;
+        let relays = iter_relays(mask).filter(|&(_, on)| on).map(|(r, _)| r);
+        args.flag_relay = Some(relays.collect());
+    }
+
+    // doesn't need a device
+    if args.flag_version || args.cmd_version {
+        return print_version(args.flag_json);
+    }
+    if args.cmd_encode {
+        return print_frames(&args);
+    }
+    if args.cmd_simulate {
+        return simulate(&args);
+    }
 
     let env_dev = var("DEVICE");
     // get the device path from flag, if its not set, check the env var
     // device, if not set, return an error
-    let device = match args.flag_dev {
+    let device = match args.flag_dev.clone() {
         Some(path) => Ok(path),
         None => env_dev.map_err(|_| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                "Failed to determine device, use env var DEVICE or flag --dev",
-            )
+            invalid_args("Failed to determine device, use env var DEVICE or flag --dev")
         }),
     }?;
 
+    let mut report = Report::of(&args, &device);
+    let result = dispatch(args, device, &mut report);
+    match report {
+        Some(report) => report.print(result),
+        None => result,
+    }
+}
+
+/// run the command of `args` on `device`
+///
+/// with --json, set, toggle, reset and status leave their output to `report`
+fn dispatch(args: Args, device: String, report: &mut Option<Report>) -> io::Result<()> {
     // check arguments
-    if args.flag_version {
-        println!("{}: {}", NAME, VERSION);
-        Ok(())
-    } else if args.flag_help {
+    if args.flag_help {
         println!("{}", USAGE);
         Ok(())
     } else if args.cmd_set {
-        // open device, address of relay is always 1 as for now
-        let mut relay = Relay8x::new(device.as_str(), 1)?;
-        relay.configure_device()?;
-        // if flag_relay is none, all relays should be set
-        let relay_numbers = args.flag_relay.unwrap_or_default();
-        // if flag_card is none, all cards should be set
+        // open device
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        // docopt defaults to the first card
         let card_numbers = args.flag_card.unwrap_or_default();
-        // map state argument to set or reset
-        match args.arg_state.as_ref() {
-            "on" => relay.set_relays(card_numbers, relay_numbers)?,
-            "off" => relay.reset_relays(card_numbers, relay_numbers)?,
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "Failed to determine state, use 'on' or 'off'.",
+        // if flag_relay is none, all relays should be set
+        let groups = relay.relay_groups(
+            &card_numbers,
+            args.flag_relay.as_deref().unwrap_or_default(),
+        );
+        let relay_numbers = relays_or_all(args.flag_relay, relay.max_relays());
+        let mut summary = Summary::new(args.flag_null, args.flag_quiet_success, args.flag_json);
+        if let Some((affect, value)) = args.relay_states {
+            if !args.arg_state.is_empty() || args.flag_broadcast || args.flag_clear_first {
+                return Err(invalid_args(
+                    "Relays with states take no <state>, --broadcast or --clear-first",
                 ));
             }
-        };
+            let result = relay.apply_relays(card_numbers.clone(), affect, value);
+            let result = summary.count(&card_numbers, affect.count_ones() as usize, result);
+            or_reset(result, &mut relay, args.flag_reset_on_error)?;
+        } else {
+            // map state argument to set or reset
+            match args.arg_state.as_ref() {
+                "on" if args.flag_broadcast => {
+                    if args.flag_clear_first {
+                        // acknowledged by every card, unlike the broadcast switching on
+                        let result = relay.reset_all();
+                        or_reset(result, &mut relay, args.flag_reset_on_error)?;
+                    }
+                    let result = relay.broadcast_relays(relay_numbers, true);
+                    or_reset(result, &mut relay, args.flag_reset_on_error)?;
+                }
+                "off" if args.flag_broadcast => {
+                    let result = relay.broadcast_relays(relay_numbers, false);
+                    or_reset(result, &mut relay, args.flag_reset_on_error)?;
+                }
+                "on" if args.flag_clear_first => {
+                    for (cards, numbers) in groups {
+                        let count = numbers.len();
+                        let result = relay.clear_and_set_relays(cards.clone(), numbers);
+                        let result = summary.count(&cards, count, result);
+                        or_reset(result, &mut relay, args.flag_reset_on_error)?;
+                    }
+                }
+                "on" => {
+                    for (cards, numbers) in groups {
+                        let count = numbers.len();
+                        let result = relay.set_relays(cards.clone(), numbers);
+                        let result = summary.count(&cards, count, result);
+                        or_reset(result, &mut relay, args.flag_reset_on_error)?;
+                    }
+                }
+                "off" => {
+                    for (cards, numbers) in groups {
+                        let count = numbers.len();
+                        let result = relay.reset_relays(cards.clone(), numbers);
+                        let result = summary.count(&cards, count, result);
+                        or_reset(result, &mut relay, args.flag_reset_on_error)?;
+                    }
+                }
+                _ => {
+                    return Err(invalid_args(
+                        "Failed to determine state, use 'on' or 'off'.",
+                    ));
+                }
+            };
+        }
+        summary.print();
+        if args.flag_show_state {
+            show_states(&mut relay, card_numbers, args.flag_null, report)?;
+        }
         Ok(())
     } else if args.cmd_toggle {
         // open device
-        let mut relay = Relay8x::new(device.as_str(), 1)?;
-        relay.configure_device()?;
-        // if flag is none, all relays should be toggeled
-        let relay_numbers = args.flag_relay.unwrap_or_default();
-        // if flag_card is none, all cards should be set
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        // docopt defaults to the first card
         let card_numbers = args.flag_card.unwrap_or_default();
-        // do the toggle
-        relay.toggle_relays(card_numbers, relay_numbers)?;
+        let mut summary = Summary::new(args.flag_null, args.flag_quiet_success, args.flag_json);
+        if args.flag_all {
+            // states are rarely known when toggling everything, show what flipped
+            let before = relay.get_relays(card_numbers.clone())?;
+            for (cards, numbers) in relay.relay_groups(&card_numbers, &[]) {
+                let count = numbers.len();
+                let result = relay.toggle_relays(cards.clone(), numbers);
+                let result = summary.count(&cards, count, result);
+                or_reset(result, &mut relay, args.flag_reset_on_error)?;
+            }
+            let after = relay.get_relays(card_numbers.clone())?;
+            for ((card, before), after) in card_numbers.iter().zip(before).zip(after) {
+                if report.is_none() {
+                    print_entry(
+                        args.flag_null,
+                        format!("card {}: {:08b} -> {:08b}", card, before, after),
+                    );
+                }
+            }
+            summary.print();
+        } else {
+            // do the toggle
+            let numbers = args.flag_relay.unwrap_or_default();
+            let count = numbers.len();
+            let result = relay.toggle_relays(card_numbers.clone(), numbers);
+            let result = summary.count(&card_numbers, count, result);
+            or_reset(result, &mut relay, args.flag_reset_on_error)?;
+            summary.print();
+            if args.flag_show_state {
+                show_states(&mut relay, card_numbers, args.flag_null, report)?;
+            }
+        }
         Ok(())
     } else if args.cmd_reset {
         // open device
-        let mut relay = Relay8x::new(device.as_str(), 1)?;
-        relay.configure_device()?;
-        // if flag is none, all relays should be reset
-        let relay_numbers = args.flag_relay.unwrap_or_default();
-        // if flag_card is none, all cards should be set
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        // docopt defaults to the first card
+        let card_numbers = args.flag_card.unwrap_or_default();
+        // do the switching, false = off; if flag is none, all relays should be reset
+        let mut summary = Summary::new(args.flag_null, args.flag_quiet_success, args.flag_json);
+        if args.flag_broadcast {
+            let result =
+                relay.broadcast_relays(relays_or_all(args.flag_relay, relay.max_relays()), false);
+            or_reset(result, &mut relay, args.flag_reset_on_error)?;
+        } else {
+            for (cards, numbers) in relay.relay_groups(
+                &card_numbers,
+                args.flag_relay.as_deref().unwrap_or_default(),
+            ) {
+                let count = numbers.len();
+                let result = relay.reset_relays(cards.clone(), numbers);
+                let result = summary.count(&cards, count, result);
+                or_reset(result, &mut relay, args.flag_reset_on_error)?;
+            }
+        }
+        summary.print();
+        if args.flag_show_state {
+            show_states(&mut relay, card_numbers, args.flag_null, report)?;
+        }
+        Ok(())
+    } else if args.cmd_pulse {
+        let duration = parse_ms(&args.flag_ms)?;
+        check_pulse(duration).map_err(mark(ExitCode::InvalidArgs))?;
+        // the relays have to go off again, ctrl-c only ends the call once they are
+        ctrlc::set_handler(|| eprintln!("Switching the relays off at the end of the pulse"))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        let card_numbers = args.flag_card.unwrap_or_default();
+        for (cards, numbers) in relay.relay_groups(
+            &card_numbers,
+            args.flag_relay.as_deref().unwrap_or_default(),
+        ) {
+            let result = relay.pulse_relays(cards, numbers, duration);
+            or_reset(result, &mut relay, args.flag_reset_on_error)?;
+        }
+        Ok(())
+    } else if args.cmd_write {
+        let mask = parse_port(&args.arg_mask).map_err(mark(ExitCode::InvalidArgs))?;
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        let card_numbers = args.flag_card.unwrap_or_default();
+        let result = relay.write_port(card_numbers.clone(), mask);
+        or_reset(result, &mut relay, args.flag_reset_on_error)?;
+        if args.flag_show_state {
+            print_states(&mut relay, card_numbers, args.flag_null)?;
+        }
+        Ok(())
+    } else if args.cmd_all_off {
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        Ok(relay.reset_all()?)
+    } else if args.cmd_replay {
+        if !(args.flag_speed.is_finite() && args.flag_speed > 0.0) {
+            return Err(invalid_args("--speed needs a factor above 0"));
+        }
+        let path = args.flag_capture.clone().unwrap_or_default();
+        let frames = read_capture(BufReader::new(File::open(&path)?))?;
+        let config = config(&args)?;
+        let _lock = lock_device(&device, args.flag_no_lock)?;
+        let mut relay =
+            Relay8x::with_config(device.as_str(), config).map_err(mark(ExitCode::OpenFailed))?;
+        relay.set_echo_frames(args.flag_echo_frames);
+        // the init frame is part of the capture, only set up the port
+        relay.configure_port()?;
+        let start = Instant::now();
+        for (due, frame) in tx_schedule(&frames, args.flag_speed) {
+            // due relative to the start, the time to send earlier frames doesn't add up
+            if args.flag_preserve_timing {
+                thread::sleep((start + due).saturating_duration_since(Instant::now()));
+            }
+            match relay.send_frame(&frame.bytes) {
+                Ok(resp) => print_entry(
+                    args.flag_null,
+                    format!("TX: {:02x?} RX: {:02x?}", frame.bytes, &resp[..]),
+                ),
+                Err(e) => print_entry(
+                    args.flag_null,
+                    format!("TX: {:02x?} RX: {}", frame.bytes, e),
+                ),
+            }
+        }
+        Ok(())
+    } else if args.cmd_counters {
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
         let card_numbers = args.flag_card.unwrap_or_default();
-        // do the switching, false = off
-        relay.reset_relays(card_numbers, relay_numbers)?;
+        let counters = relay.get_counters(card_numbers.clone())?;
+        for (card, counts) in card_numbers.iter().zip(counters) {
+            for (relay, count) in counts.iter().enumerate() {
+                print_entry(
+                    args.flag_null,
+                    format!("card {} relay {}: {}", card, relay + 1, count),
+                );
+            }
+        }
+        Ok(())
+    } else if args.cmd_faults {
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        let card_numbers = args.flag_card.unwrap_or_default();
+        if args.flag_clear {
+            return Ok(relay.clear_faults(card_numbers)?);
+        }
+        let faults = relay.get_faults(card_numbers.clone())?;
+        for (card, faults) in card_numbers.iter().zip(faults) {
+            if faults.is_empty() {
+                print_entry(args.flag_null, format!("card {}: no faults", card));
+            }
+            for fault in faults {
+                print_entry(args.flag_null, format!("card {}: {}", card, fault));
+            }
+        }
+        Ok(())
+    } else if args.cmd_locate {
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        relay.locate(args.flag_card.unwrap_or_default(), LOCATE_SECONDS)?;
+        Ok(())
+    } else if args.cmd_batch {
+        // validate the whole batch before touching the device
+        let ops = rpc::parse_batch(args.flag_ops.as_deref().unwrap_or_default())
+            .map_err(mark(ExitCode::InvalidArgs))?;
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        let card_numbers = args.flag_card.clone().unwrap_or_default();
+        let result = rpc::run_batch(&mut relay, &ops, &card_numbers);
+        for (i, states) in or_reset(result, &mut relay, args.flag_reset_on_error)?
+            .iter()
+            .enumerate()
+        {
+            for state in states {
+                print_entry(
+                    args.flag_null,
+                    format!("{}: card {}: {:08b}", i, state.card, state.state),
+                );
+            }
+        }
+        Ok(())
+    } else if args.cmd_levels {
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        let card_numbers = args.flag_card.clone().unwrap_or_default();
+        let stdin = io::stdin();
+        let result = levels::run(&mut relay, &card_numbers, stdin.lock(), io::stderr());
+        or_reset(result, &mut relay, args.flag_reset_on_error)
+    } else if args.cmd_at {
+        at(&device, &args)
+    } else if args.cmd_selftest {
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        let mut failed = false;
+        for card in args.flag_card.unwrap_or_default() {
+            println!("card {}:", card);
+            for (number, result) in relay.self_test(card)? {
+                failed |= matches!(result, RelayTest::ReadBackMismatch { .. });
+                println!("  relay {}: {}", number, result);
+            }
+        }
+        if failed {
+            return Err(io::Error::other("Self test failed"));
+        }
+        Ok(())
+    } else if args.cmd_whoami {
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        // initialising would give the cards new addresses
+        relay.configure_port()?;
+        for address in relay.query_addresses().map_err(no_card)? {
+            print_entry(args.flag_null, format!("address {}", address));
+        }
+        Ok(())
+    } else if args.cmd_ping {
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        // the cards keep the addresses and relay states of earlier calls
+        relay.configure_port()?;
+        let mut silent = Vec::new();
+        for card in args.flag_card.clone().unwrap_or_default() {
+            let answer = if relay.ping(card)? {
+                "ok"
+            } else {
+                silent.push(card);
+                "no response"
+            };
+            print_entry(args.flag_null, format!("card {}: {}", card, answer));
+        }
+        if !silent.is_empty() {
+            return Err(mark(ExitCode::NoCard)(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No response from cards {:?}", silent),
+            )));
+        }
+        Ok(())
+    } else if args.cmd_option {
+        let option = match args.flag_set {
+            Some(ref spec) => Some(parse_port(spec).map_err(|_| {
+                invalid_args(format!(
+                    "Bad option byte '{}', use e.g. 0b11, 0x03 or 3",
+                    spec
+                ))
+            })?),
+            None => None,
+        };
+        let card = args.flag_card.clone().unwrap_or_default();
+        if card.len() != 1 {
+            return Err(invalid_args(
+                "option takes a single card, pick it with --card",
+            ));
+        }
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        match option {
+            Some(option) => relay.set_option(card[0], option)?,
+            None => println!("0x{:02x}", relay.get_option(card[0])?),
+        }
+        Ok(())
+    } else if args.cmd_status {
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        let card_numbers = args.flag_card.unwrap_or_default();
+        let status = relay_status(&mut relay, &card_numbers, &args.flag_relay)?;
+        if let Some(ref mut report) = *report {
+            report.states = status;
+            return Ok(());
+        }
+        for s in status {
+            let entry = if card_numbers.len() > 1 {
+                format!("card {} relay {}: {}", s.card, s.relay, s.state)
+            } else {
+                format!("relay {}: {}", s.relay, s.state)
+            };
+            print_entry(args.flag_null, entry);
+        }
+        Ok(())
+    } else if args.cmd_latency {
+        let (_lock, mut relay) = open_device(&device, &args)?;
+        init_device(&mut relay, &args)?;
+        let mut missed = false;
+        for card in args.flag_card.clone().unwrap_or_default() {
+            for number in args.flag_relay.clone().unwrap_or_default() {
+                let entry = match relay.measure_latency(card, number, LATENCY_TIMEOUT)? {
+                    Some(latency) => format!("{} ms", latency.as_millis()),
+                    None => {
+                        missed = true;
+                        format!("not on within {} ms", LATENCY_TIMEOUT.as_millis())
+                    }
+                };
+                print_entry(
+                    args.flag_null,
+                    format!("card {} relay {}: {}", card, number, entry),
+                );
+            }
+        }
+        if missed {
+            return Err(io::Error::other("Relays didn't switch"));
+        }
         Ok(())
+    } else if args.cmd_ws {
+        serve_ws(&device, &args)
+    } else if args.cmd_daemon {
+        serve_daemon(&device, &args)
+    } else if args.cmd_mqtt {
+        serve_mqtt(&device, &args)
+    } else if args.cmd_serve {
+        serve_http(&device, &args)
+    } else if args.cmd_watch {
+        watch(&device, &args)
+    } else if args.cmd_mirror {
+        mirror(&device, &args)
     } else {
         println!("I don't know what you want to do..");
         Ok(())
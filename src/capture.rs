@@ -0,0 +1,203 @@
+//! recording of the frames on the wire for offline analysis
+//!
+//! a capture is a text file with one frame per line:
+//!
+//! ```text
+//! <seconds>.<microseconds> <TX|RX> <bytes as hex>
+//! 1539507162.052731 TX 06 01 05 02
+//! 1539507162.061207 RX f9 01 05 fd
+//! ```
+//!
+//! timestamps are seconds since the unix epoch, `TX` frames were sent to the cards and
+//! `RX` frames were received from them. The format is stable, new fields will only ever
+//! be appended to the end of a line.
+
+use frame::Frame;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufWriter, Error, ErrorKind, Write};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// direction of a captured frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// sent to the cards
+    Tx,
+    /// received from the cards
+    Rx,
+}
+
+/// a single line of a capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedFrame {
+    /// time since the unix epoch
+    pub timestamp: Duration,
+    /// sent or received
+    pub direction: Direction,
+    /// the frame as sent or received
+    pub bytes: Vec<u8>,
+}
+
+impl fmt::Display for CapturedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:06} {} {}",
+            self.timestamp.as_secs(),
+            self.timestamp.subsec_micros(),
+            match self.direction {
+                Direction::Tx => "TX",
+                Direction::Rx => "RX",
+            },
+            Frame(&self.bytes)
+        )
+    }
+}
+
+impl FromStr for CapturedFrame {
+    type Err = io::Error;
+
+    fn from_str(line: &str) -> io::Result<Self> {
+        let bad = |what: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Bad {} in capture line '{}'", what, line),
+            )
+        };
+        let mut fields = line.split_whitespace();
+        let timestamp = fields.next().ok_or_else(|| bad("timestamp"))?;
+        let mut time = timestamp.splitn(2, '.');
+        let secs = time
+            .next()
+            .and_then(|s| s.parse::<u64>().ok())
+            .ok_or_else(|| bad("timestamp"))?;
+        let micros = time
+            .next()
+            .and_then(|s| s.parse::<u32>().ok())
+            .filter(|&us| us < 1_000_000)
+            .ok_or_else(|| bad("timestamp"))?;
+        let direction = match fields.next() {
+            Some("TX") => Direction::Tx,
+            Some("RX") => Direction::Rx,
+            _ => return Err(bad("direction")),
+        };
+        let bytes = fields
+            .map(|b| u8::from_str_radix(b, 16).map_err(|_| bad("byte")))
+            .collect::<io::Result<Vec<u8>>>()?;
+        Ok(Self {
+            timestamp: Duration::new(secs, micros * 1000),
+            direction,
+            bytes,
+        })
+    }
+}
+
+/// sink recording every frame sent and received by a `Relay8x`
+pub struct Capture {
+    out: Box<dyn Write + Send>,
+}
+
+impl Capture {
+    /// record to an arbitrary writer
+    pub fn new<W: Write + Send + 'static>(out: W) -> Self {
+        Self { out: Box::new(out) }
+    }
+
+    /// record to a newly created file at `path`, an existing file is truncated
+    pub fn create(path: &str) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// append one frame stamped with the current time
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        let frame = CapturedFrame {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            direction,
+            bytes: bytes.to_vec(),
+        };
+        writeln!(self.out, "{}", frame)?;
+        self.out.flush()
+    }
+}
+
+/// parse a capture, blank lines are skipped
+pub fn read_capture<R: BufRead>(reader: R) -> io::Result<Vec<CapturedFrame>> {
+    reader
+        .lines()
+        .filter(|line| line.as_ref().map(|l| !l.trim().is_empty()).unwrap_or(true))
+        .map(|line| line.and_then(|l| l.parse()))
+        .collect()
+}
+
+/// the TX frames of a capture with when each is due, relative to the first one, at
+/// `speed` times the recorded pace
+///
+/// timestamps going backwards, e.g. after the clock was set, make a frame due at once
+pub fn tx_schedule(frames: &[CapturedFrame], speed: f64) -> Vec<(Duration, &CapturedFrame)> {
+    let mut due = Duration::from_secs(0);
+    // latest timestamp so far
+    let mut last: Option<Duration> = None;
+    frames
+        .iter()
+        .filter(|f| f.direction == Direction::Tx)
+        .map(|frame| {
+            if let Some(last) = last {
+                let gap = frame.timestamp.checked_sub(last).unwrap_or_default();
+                due += gap.div_f64(speed);
+            }
+            last = Some(last.map_or(frame.timestamp, |l| l.max(frame.timestamp)));
+            (due, frame)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn line_format_round_trips() {
+        let frame = CapturedFrame {
+            timestamp: Duration::new(1539507162, 52731000),
+            direction: Direction::Tx,
+            bytes: vec![6, 1, 5, 2],
+        };
+        let line = frame.to_string();
+        assert_eq!(line, "1539507162.052731 TX 06 01 05 02");
+        assert_eq!(line.parse::<CapturedFrame>().unwrap(), frame);
+    }
+
+    #[test]
+    fn read_capture_rejects_garbage() {
+        let capture = "1.000001 TX 06 01 05 02\n\n1.000002 RX f9 01 05 fd\n";
+        let frames = read_capture(capture.as_bytes()).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].direction, Direction::Rx);
+
+        assert!(read_capture("1.000001 XX 06".as_bytes()).is_err());
+        assert!(read_capture("1.000001 TX zz".as_bytes()).is_err());
+        assert!(read_capture("now TX 06".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn schedule_keeps_tx_gaps() {
+        let capture =
+            "10.000000 TX 06 01 05 02\n10.010000 RX f9 01 05 fd\n10.500000 TX 07 01 05 03\n\
+                       9.000000 TX 02 01 00 03\n11.500000 TX 08 01 01 08\n";
+        let frames = read_capture(capture.as_bytes()).unwrap();
+        let due = |speed| -> Vec<u128> {
+            tx_schedule(&frames, speed)
+                .iter()
+                .map(|&(due, _)| due.as_millis())
+                .collect()
+        };
+        assert_eq!(due(1.0), vec![0, 500, 500, 1500]);
+        assert_eq!(due(2.0), vec![0, 250, 250, 750]);
+        assert_eq!(tx_schedule(&frames, 1.0)[1].1.bytes, vec![7, 1, 5, 3]);
+        assert!(tx_schedule(&frames[1..2], 1.0).is_empty());
+    }
+}
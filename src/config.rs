@@ -0,0 +1,399 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use RelayIndex;
+
+/// tunables of a chain of relay cards
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relay8xConfig {
+    /// baud rate of the serial interface
+    pub baud: u32,
+    /// how long to wait for a response of a card, for every frame but the initialisation
+    pub timeout: Duration,
+    /// how long to wait for each answer to the initialisation
+    pub init_timeout: Duration,
+    /// address of the first card, succeding cards have +1 and so on
+    pub address: u8,
+    /// apply the serial settings of the card to the port, off for bridges and ports set
+    /// up by another tool
+    pub reconfigure: bool,
+    /// relays are wired active low: switching a relay on de-energizes its coil
+    pub active_low: bool,
+    /// relays wired normally closed on every card: switching one on de-energizes its
+    /// coil so the circuit closes, and its state reads as the state of the circuit
+    pub normally_closed: RelayIndex,
+    /// relay 1 is the most significant bit of the data byte instead of the least, for
+    /// boards numbering their relays the other way round
+    pub msb_first: bool,
+    /// number of relays on each card
+    pub relays_per_card: u8,
+    /// cards with another number of relays than `relays_per_card`, for mixed chains
+    pub card_relays: BTreeMap<u8, u8>,
+    /// explicit card to address map for chains with non-contiguous addresses, replaces
+    /// counting up from `address`
+    pub address_map: Option<BTreeMap<u8, u8>>,
+    /// sets of relays of which at most one may be on, checked before relays are switched
+    pub interlocks: Vec<Interlock>,
+    /// what to do when switching on a relay violates an interlock
+    pub interlock_policy: InterlockPolicy,
+    /// don't wait for the cards to acknowledge the initialisation, for boards which never
+    /// send it; the chain is neither verified nor are its cards recorded
+    pub skip_init_ack: bool,
+    /// relays which must not latch, switching one of them on pulses it for the given time
+    pub momentary: BTreeMap<u8, Duration>,
+    /// don't send set or reset frames for relays known to be switched that way already
+    pub skip_redundant: bool,
+    /// most cards expected in the chain, bounds the address scan and the initialisation
+    pub max_cards: u8,
+    /// switch several relays with one frame per relay in ascending order instead of all
+    /// of them at once
+    pub ordered: bool,
+    /// shortest time between two switches of a relay, against scripts wearing out the
+    /// mechanical relays in a tight loop; zero doesn't limit
+    ///
+    /// applies to setting, resetting and toggling relays, pulses, `locate`, self tests,
+    /// `write_port` and `reset_all` switch as they have to
+    pub min_interval: Duration,
+    /// what to do when a relay is switched again within `min_interval`
+    pub rate_limit_policy: RateLimitPolicy,
+    /// discard bytes left in the input buffer before every frame, so a stale response
+    /// isn't taken for the answer to the next frame
+    pub drain: bool,
+    /// write the frames of a switch to all cards at once and read the answers afterwards,
+    /// saving a round trip per card; switches taking more than one frame per card, e.g.
+    /// with `ordered`, `min_interval`, interlocks, normally closed or momentary relays,
+    /// still go card by card
+    pub pipeline: bool,
+}
+
+/// longest pulse accepted, anything longer is better served by switching on and off
+pub const MAX_PULSE: Duration = Duration::from_secs(60);
+
+/// checks that a pulse is neither empty nor longer than `MAX_PULSE`
+pub fn check_pulse(duration: Duration) -> io::Result<()> {
+    if duration == Duration::from_secs(0) || duration > MAX_PULSE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Pulse of {} ms out of range (1..{})",
+                duration.as_millis(),
+                MAX_PULSE.as_millis()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// baud rates cards can be jumpered to and adapters commonly support
+pub const BAUD_RATES: [u32; 5] = [9600, 19200, 38400, 57600, 115_200];
+
+/// checks that `baud` is one of `BAUD_RATES`
+pub fn check_baud(baud: u32) -> io::Result<()> {
+    if !BAUD_RATES.contains(&baud) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Unsupported baud rate {}, use one of {:?}",
+                baud, BAUD_RATES
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// checks that a response timeout is not zero, reads would give up at once
+pub fn check_timeout(timeout: Duration) -> io::Result<()> {
+    if timeout == Duration::from_secs(0) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Timeout of 0 ms, the cards need time to answer",
+        ));
+    }
+    Ok(())
+}
+
+/// relays of which at most one may be on at a time, e.g. forward and reverse of a motor
+///
+/// interlocks apply to the relay numbers of every card
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Interlock {
+    /// at most one of these relays is on at a time
+    pub relays: RelayIndex,
+}
+
+impl Interlock {
+    /// interlock of at least two distinct relays
+    pub fn new(relays: RelayIndex) -> io::Result<Self> {
+        let interlock = Self { relays };
+        interlock.validate()?;
+        Ok(interlock)
+    }
+
+    /// checks that the interlock names at least two distinct, existing relays
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidInput, msg));
+        if let Some(relay) = self.relays.iter().find(|&&r| r == 0 || r > 8) {
+            return invalid(format!("Interlocked relay {} out of range (1..8)", relay));
+        }
+        if self.mask().count_ones() < 2 || self.mask().count_ones() as usize != self.relays.len() {
+            return invalid(format!(
+                "Interlock {:?} needs at least two distinct relays",
+                self.relays
+            ));
+        }
+        Ok(())
+    }
+
+    /// the interlocked relays as bits, bit 0 is relay 1
+    pub fn mask(&self) -> u8 {
+        self.relays
+            .iter()
+            .filter(|&&r| r > 0 && r <= 8)
+            .fold(0, |mask, r| mask | 1 << (r - 1))
+    }
+}
+
+/// how a switch violating an interlock is handled
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InterlockPolicy {
+    /// refuse the switch with an error, nothing is written
+    Reject,
+    /// switch the conflicting relays off first
+    Clear,
+}
+
+/// how a switch within the minimum interval of a relay is handled
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitPolicy {
+    /// wait until the interval passed, then switch
+    Delay,
+    /// refuse the switch with an error, nothing is written
+    Reject,
+}
+
+impl Default for Relay8xConfig {
+    /// settings of the relay card as shipped, see doc of relay card
+    fn default() -> Self {
+        Self {
+            baud: 19200,
+            timeout: Duration::from_millis(1000),
+            init_timeout: Duration::from_millis(1000),
+            address: 1,
+            reconfigure: true,
+            active_low: false,
+            normally_closed: Vec::new(),
+            msb_first: false,
+            relays_per_card: 8,
+            card_relays: BTreeMap::new(),
+            address_map: None,
+            interlocks: Vec::new(),
+            interlock_policy: InterlockPolicy::Reject,
+            skip_init_ack: false,
+            momentary: BTreeMap::new(),
+            skip_redundant: false,
+            max_cards: 8,
+            ordered: false,
+            min_interval: Duration::from_secs(0),
+            rate_limit_policy: RateLimitPolicy::Delay,
+            drain: true,
+            pipeline: false,
+        }
+    }
+}
+
+impl Relay8xConfig {
+    /// number of relays on a card, from `card_relays` or else `relays_per_card`
+    pub fn relay_count(&self, card: u8) -> u8 {
+        self.card_relays
+            .get(&card)
+            .cloned()
+            .unwrap_or(self.relays_per_card)
+    }
+
+    /// number of relays on the largest card of the chain
+    pub fn max_relays(&self) -> u8 {
+        let counts = self.card_relays.values().cloned();
+        counts.fold(self.relays_per_card, u8::max)
+    }
+}
+
+/// parses a card to address map like `1:1,2:3,3:7`
+///
+/// cards start at 1, address 0 is reserved for broadcasts and neither cards nor
+/// addresses may appear twice
+pub fn parse_address_map(spec: &str) -> io::Result<BTreeMap<u8, u8>> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidInput, msg);
+    let mut map = BTreeMap::new();
+    for entry in spec.split(',').map(str::trim) {
+        let mut parts = entry.splitn(2, ':');
+        let (card, address) = match (parts.next(), parts.next()) {
+            (Some(card), Some(address)) => {
+                (card.trim().parse::<u8>(), address.trim().parse::<u8>())
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "Bad address map entry '{}', use <card>:<address>",
+                    entry
+                )))
+            }
+        };
+        let (card, address) = match (card, address) {
+            (Ok(card), Ok(address)) if card > 0 && address > 0 => (card, address),
+            _ => {
+                return Err(invalid(format!(
+                    "Bad address map entry '{}', card and address must be 1..255",
+                    entry
+                )))
+            }
+        };
+        if map.values().any(|&a| a == address) {
+            return Err(invalid(format!("Address {} is mapped twice", address)));
+        }
+        if map.insert(card, address).is_some() {
+            return Err(invalid(format!("Card {} is mapped twice", card)));
+        }
+    }
+    Ok(map)
+}
+
+/// parses per card relay counts like `1:8,2:4`
+///
+/// cards start at 1, a card has 1..8 relays and may only be listed once
+pub fn parse_card_relays(spec: &str) -> io::Result<BTreeMap<u8, u8>> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidInput, msg);
+    let mut counts = BTreeMap::new();
+    for entry in spec.split(',').map(str::trim) {
+        let (card, count) = match entry.split_once(':') {
+            Some((card, count)) => (card.trim().parse::<u8>(), count.trim().parse::<u8>()),
+            None => {
+                return Err(invalid(format!(
+                    "Bad relay count '{}', use <card>:<relays>",
+                    entry
+                )))
+            }
+        };
+        let (card, count) = match (card, count) {
+            (Ok(card), Ok(count)) if card > 0 && count > 0 && count <= 8 => (card, count),
+            _ => {
+                return Err(invalid(format!(
+                    "Bad relay count '{}', card must be 1..255 and relays 1..8",
+                    entry
+                )))
+            }
+        };
+        if counts.insert(card, count).is_some() {
+            return Err(invalid(format!("Card {} has two relay counts", card)));
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_matches_card_defaults() {
+        let config = Relay8xConfig::default();
+        assert_eq!(config.baud, 19200);
+        assert_eq!(config.timeout, Duration::from_millis(1000));
+        assert_eq!(config.init_timeout, Duration::from_millis(1000));
+        assert_eq!(config.address, 1);
+        assert!(config.reconfigure);
+        assert!(!config.active_low);
+        assert!(config.normally_closed.is_empty());
+        assert!(!config.msb_first);
+        assert_eq!(config.relays_per_card, 8);
+        assert!(config.card_relays.is_empty());
+        assert_eq!(config.address_map, None);
+        assert!(!config.skip_init_ack);
+        assert!(config.momentary.is_empty());
+        assert!(!config.skip_redundant);
+        assert_eq!(config.max_cards, 8);
+        assert!(!config.ordered);
+        assert!(config.drain);
+        assert_eq!(config.min_interval, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn relay_count_falls_back_to_relays_per_card() {
+        let mut config = Relay8xConfig {
+            relays_per_card: 4,
+            ..Relay8xConfig::default()
+        };
+        config.card_relays.insert(2, 8);
+        assert_eq!(config.relay_count(1), 4);
+        assert_eq!(config.relay_count(2), 8);
+    }
+
+    #[test]
+    fn pulse_is_bounded() {
+        assert!(check_pulse(Duration::from_millis(500)).is_ok());
+        assert!(check_pulse(MAX_PULSE).is_ok());
+        assert!(check_pulse(Duration::from_secs(0)).is_err());
+        assert!(check_pulse(MAX_PULSE + Duration::from_millis(1)).is_err());
+    }
+
+    #[test]
+    fn baud_rate_is_a_common_one() {
+        for &baud in &BAUD_RATES {
+            assert!(check_baud(baud).is_ok());
+        }
+        let err = check_baud(14400).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "Unsupported baud rate 14400, use one of [9600, 19200, 38400, 57600, 115200]"
+        );
+    }
+
+    #[test]
+    fn timeout_is_not_zero() {
+        assert!(check_timeout(Duration::from_millis(1)).is_ok());
+        assert!(check_timeout(Duration::from_secs(0)).is_err());
+    }
+
+    #[test]
+    fn interlock_is_validated() {
+        assert_eq!(Interlock::new(vec![1, 3]).unwrap().mask(), 0b101);
+        assert!(Interlock::new(vec![1]).is_err());
+        assert!(Interlock::new(vec![1, 1]).is_err());
+        assert!(Interlock::new(vec![1, 9]).is_err());
+        assert!(Interlock::new(vec![0, 2]).is_err());
+    }
+
+    #[test]
+    fn address_map_parses_and_validates() {
+        let map = parse_address_map("1:1, 2:3,3:7").unwrap();
+        assert_eq!(map.get(&2), Some(&3));
+        assert_eq!(map.get(&3), Some(&7));
+        assert_eq!(map.len(), 3);
+
+        assert!(parse_address_map("1").is_err());
+        assert!(parse_address_map("0:1").is_err());
+        assert!(parse_address_map("1:0").is_err());
+        assert!(parse_address_map("1:300").is_err());
+        assert!(parse_address_map("1:2,1:3").is_err());
+        assert!(parse_address_map("1:2,2:2").is_err());
+    }
+
+    #[test]
+    fn card_relays_parse_and_validate() {
+        let counts = parse_card_relays("1:8, 2:4").unwrap();
+        assert_eq!(counts.get(&1), Some(&8));
+        assert_eq!(counts.get(&2), Some(&4));
+
+        for spec in &["2", "0:4", "2:0", "2:9", "2:4,2:8", "a:4"] {
+            assert!(
+                parse_card_relays(spec).is_err(),
+                "{} should not parse",
+                spec
+            );
+        }
+    }
+}
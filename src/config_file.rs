@@ -0,0 +1,522 @@
+//! relay names and chain layout loaded from a TOML file
+//!
+//! ```toml
+//! # card to address map, same syntax as --address-map
+//! address_map = "1:1,2:3"
+//!
+//! [aliases]
+//! pump = 3
+//!
+//! [groups]
+//! lights = [1, 2, 5]
+//!
+//! # forward and reverse of a motor, never both on
+//! interlock_policy = "reject"
+//! [[interlocks]]
+//! relays = [1, 2]
+//!
+//! # door strike, "on" only ever opens it for half a second
+//! [[momentary]]
+//! relays = [4]
+//! duration_ms = 500
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use config::{check_pulse, parse_address_map, Interlock, InterlockPolicy, Relay8xConfig};
+use toml;
+use {expand_relays, ParseError, Relay8x, RelayIndex};
+
+/// contents of a config file
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// names for single relays
+    #[serde(default)]
+    pub aliases: BTreeMap<String, u8>,
+    /// names for sets of relays
+    #[serde(default)]
+    pub groups: BTreeMap<String, RelayIndex>,
+    /// card to address map, e.g. `1:1,2:3`
+    #[serde(default)]
+    pub address_map: Option<String>,
+    /// sets of mutually exclusive relays
+    #[serde(default)]
+    pub interlocks: Vec<Interlock>,
+    /// `reject` (default) or `clear`
+    #[serde(default)]
+    pub interlock_policy: Option<InterlockPolicy>,
+    /// relays which are pulsed instead of latched
+    #[serde(default)]
+    pub momentary: Vec<Momentary>,
+}
+
+/// relay names kept apart from the device settings, e.g. in a file shared by a team
+///
+/// ```toml
+/// [aliases]
+/// pump = 3
+///
+/// [groups]
+/// lights = [1, 2, 5]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AliasFile {
+    /// names for single relays
+    #[serde(default)]
+    pub aliases: BTreeMap<String, u8>,
+    /// names for sets of relays
+    #[serde(default)]
+    pub groups: BTreeMap<String, RelayIndex>,
+}
+
+impl AliasFile {
+    /// parse and validate the names of an alias file
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let file: AliasFile = toml::from_str(contents)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Bad alias file: {}", e)))?;
+        let mut config = ConfigFile::default();
+        config.merge_aliases(file.clone());
+        config.validate()?;
+        Ok(file)
+    }
+
+    /// load and validate the alias file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        Self::parse(&contents)
+    }
+}
+
+/// relays which only ever switch on for `duration_ms`, e.g. door strikes
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Momentary {
+    /// the relays, of every card
+    pub relays: RelayIndex,
+    /// how long they stay on
+    pub duration_ms: u64,
+}
+
+impl ConfigFile {
+    /// parse and validate a config
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let config: ConfigFile = toml::from_str(contents)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Bad config: {}", e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// load and validate the config at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        Self::parse(&contents)
+    }
+
+    /// checks relay numbers, names and the address map
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidData, msg));
+        let names = self.aliases.keys().chain(self.groups.keys());
+        for name in names {
+            if name.parse::<u32>().is_ok() {
+                return invalid(format!("Name '{}' collides with a relay number", name));
+            }
+        }
+        if let Some(name) = self.aliases.keys().find(|n| self.groups.contains_key(*n)) {
+            return invalid(format!("'{}' is both an alias and a group", name));
+        }
+        let relays = self.aliases.iter().map(|(n, &r)| (n, r)).chain(
+            self.groups
+                .iter()
+                .flat_map(|(n, rs)| rs.iter().map(move |&r| (n, r))),
+        );
+        for (name, relay) in relays {
+            if relay == 0 || relay > 8 {
+                return invalid(format!("Relay {} of '{}' out of range (1..8)", relay, name));
+            }
+        }
+        if let Some(ref spec) = self.address_map {
+            parse_address_map(spec)?;
+        }
+        for interlock in &self.interlocks {
+            interlock
+                .validate()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        }
+        let mut momentary = Vec::new();
+        for m in &self.momentary {
+            check_pulse(Duration::from_millis(m.duration_ms))
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            for &relay in &m.relays {
+                if relay == 0 || relay > 8 {
+                    return invalid(format!("Momentary relay {} out of range (1..8)", relay));
+                }
+                if momentary.contains(&relay) {
+                    return invalid(format!("Momentary relay {} is declared twice", relay));
+                }
+                momentary.push(relay);
+            }
+        }
+        Ok(())
+    }
+
+    /// relays behind an alias or group name
+    pub fn resolve(&self, name: &str) -> Option<RelayIndex> {
+        self.aliases
+            .get(name)
+            .map(|&relay| vec![relay])
+            .or_else(|| self.groups.get(name).cloned())
+    }
+
+    /// adds the names of an alias file, they replace names of the config already taken
+    pub fn merge_aliases(&mut self, file: AliasFile) {
+        for name in file.aliases.keys().chain(file.groups.keys()) {
+            self.aliases.remove(name);
+            self.groups.remove(name);
+        }
+        self.aliases.extend(file.aliases);
+        self.groups.extend(file.groups);
+    }
+
+    /// relays of a token given by the user, relay numbers like `1-3,5` or else a name
+    pub fn resolve_relays(&self, token: &str) -> Result<RelayIndex, ParseError> {
+        expand_relays(token.trim()).or_else(|e| {
+            self.resolve(token.trim())
+                .ok_or_else(|| ParseError::new(token, format!("{}, nor a known name", e.reason)))
+        })
+    }
+
+    /// pulse length of every momentary relay
+    pub fn momentary(&self) -> BTreeMap<u8, Duration> {
+        self.momentary
+            .iter()
+            .flat_map(|m| {
+                m.relays
+                    .iter()
+                    .map(move |&r| (r, Duration::from_millis(m.duration_ms)))
+            })
+            .collect()
+    }
+
+    /// sets the interlocks and their policy and the momentary relays of `config` to those
+    /// of the file, and the address map if the file has one
+    pub fn apply(&self, config: &mut Relay8xConfig) {
+        if let Some(map) = self.address_map() {
+            config.address_map = Some(map);
+        }
+        config.interlocks = self.interlocks.clone();
+        config.interlock_policy = self.interlock_policy.unwrap_or(InterlockPolicy::Reject);
+        config.momentary = self.momentary();
+    }
+
+    /// parsed card to address map, if any
+    pub fn address_map(&self) -> Option<BTreeMap<u8, u8>> {
+        self.address_map
+            .as_ref()
+            .and_then(|spec| parse_address_map(spec).ok())
+    }
+}
+
+/// keeps a config file up to date for long running modes
+///
+/// the file is checked for changes on every `poll`, an invalid new version is rejected
+/// and the previous config stays active
+pub struct ConfigWatcher {
+    path: PathBuf,
+    // modification time and size of the loaded version
+    stamp: Option<(SystemTime, u64)>,
+    config: ConfigFile,
+    // address map given apart from the file, which then must not have one
+    address_map: Option<BTreeMap<u8, u8>>,
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    fs::metadata(path)
+        .ok()
+        .and_then(|m| m.modified().ok().map(|t| (t, m.len())))
+}
+
+impl ConfigWatcher {
+    /// loads the config, which has to be valid initially
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let stamp = stamp(&path);
+        let config = ConfigFile::load(&path)?;
+        Ok(Self {
+            path,
+            stamp,
+            config,
+            address_map: None,
+        })
+    }
+
+    /// sets an address map given apart from the file, e.g. on the command line
+    ///
+    /// it is active while the file has none; a file with one is rejected, now and on the
+    /// following reloads
+    pub fn set_address_map(&mut self, map: BTreeMap<u8, u8>) -> io::Result<()> {
+        if self.config.address_map.is_some() {
+            return Err(self.conflict());
+        }
+        self.address_map = Some(map);
+        Ok(())
+    }
+
+    fn conflict(&self) -> Error {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} has an address map, but one is given apart from it",
+                self.path.display()
+            ),
+        )
+    }
+
+    /// the active config
+    pub fn config(&self) -> &ConfigFile {
+        &self.config
+    }
+
+    /// reload the config if the file changed, returns true if a new config is active
+    pub fn poll(&mut self) -> bool {
+        let stamp = stamp(&self.path);
+        if stamp == self.stamp {
+            return false;
+        }
+        self.stamp = stamp;
+        let loaded = ConfigFile::load(&self.path).and_then(|config| {
+            match (config.address_map.is_some(), self.address_map.is_some()) {
+                (true, true) => Err(self.conflict()),
+                _ => Ok(config),
+            }
+        });
+        match loaded {
+            Ok(ref config) if *config == self.config => false,
+            Ok(config) => {
+                info!("Reloaded config {}", self.path.display());
+                self.config = config;
+                true
+            }
+            Err(e) => {
+                error!(
+                    "Ignoring changed config {}, keeping the previous one: {}",
+                    self.path.display(),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// `poll` and apply a new config to the settings of `relay`, returns true if it did
+    pub fn update(&mut self, relay: &mut Relay8x) -> bool {
+        if !self.poll() {
+            return false;
+        }
+        // a map the file dropped falls back to the one given apart from it
+        relay.config.address_map = self.address_map.clone();
+        self.config.apply(&mut relay.config);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env::temp_dir;
+    use std::io::Write;
+    use std::process;
+
+    use sim::SimulatedRelays;
+
+    #[test]
+    fn parses_aliases_and_groups() {
+        let config = ConfigFile::parse(
+            "address_map = \"1:1,2:3\"\n[aliases]\npump = 3\n[groups]\nlights = [1, 2, 5]\n",
+        )
+        .unwrap();
+        assert_eq!(config.resolve("pump"), Some(vec![3]));
+        assert_eq!(config.resolve("lights"), Some(vec![1, 2, 5]));
+        assert_eq!(config.resolve("fan"), None);
+        assert_eq!(config.address_map().unwrap().get(&2), Some(&3));
+
+        let config =
+            ConfigFile::parse("interlock_policy = \"clear\"\n[[interlocks]]\nrelays = [1, 2]\n")
+                .unwrap();
+        assert_eq!(config.interlocks[0].mask(), 0b11);
+        assert_eq!(config.interlock_policy, Some(InterlockPolicy::Clear));
+
+        let config =
+            ConfigFile::parse("[[momentary]]\nrelays = [4, 5]\nduration_ms = 500\n").unwrap();
+        assert_eq!(
+            config.momentary().get(&5),
+            Some(&Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn applied_interlock_rejects_set() {
+        let file = ConfigFile::parse(
+            "address_map = \"1:3\"\ninterlock_policy = \"reject\"\n[[interlocks]]\nrelays = [1, 2]\n",
+        )
+        .unwrap();
+        let mut config = Relay8xConfig::default();
+        file.apply(&mut config);
+        assert_eq!(
+            config.address_map.as_ref().and_then(|m| m.get(&1)),
+            Some(&3)
+        );
+
+        let sim = SimulatedRelays::new(3);
+        let mut relay = Relay8x::from_transport_with_config(sim.clone(), config);
+        relay.set_relays(vec![1], vec![1]).unwrap();
+        assert!(relay.set_relays(vec![1], vec![2]).is_err());
+        assert_eq!(sim.relays(3), Some(0b1));
+    }
+
+    #[test]
+    fn applied_momentary_relay_pulses() {
+        let file = ConfigFile::parse("[[momentary]]\nrelays = [4]\nduration_ms = 20\n").unwrap();
+        let mut config = Relay8xConfig::default();
+        file.apply(&mut config);
+        assert_eq!(config.momentary.get(&4), Some(&Duration::from_millis(20)));
+
+        let sim = SimulatedRelays::new(1);
+        let mut relay = Relay8x::from_transport_with_config(sim.clone(), config);
+        relay.set_relays(vec![1], vec![4]).unwrap();
+        assert_eq!(sim.relays(1), Some(0));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(ConfigFile::parse("[aliases]\npump = 9\n").is_err());
+        assert!(ConfigFile::parse("[aliases]\n3 = 1\n").is_err());
+        assert!(ConfigFile::parse("[groups]\nall = [1, 0]\n").is_err());
+        assert!(ConfigFile::parse("[aliases]\na = 1\n[groups]\na = [2]\n").is_err());
+        assert!(ConfigFile::parse("address_map = \"1:0\"\n").is_err());
+        assert!(ConfigFile::parse("unknown = 1\n").is_err());
+        assert!(ConfigFile::parse("[[interlocks]]\nrelays = [1]\n").is_err());
+        assert!(ConfigFile::parse("[[momentary]]\nrelays = [4]\nduration_ms = 0\n").is_err());
+        assert!(ConfigFile::parse(
+            "[[momentary]]\nrelays = [4]\nduration_ms = 5\n[[momentary]]\nrelays = [4]\nduration_ms = 9\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn alias_file_takes_precedence_over_config() {
+        let mut config =
+            ConfigFile::parse("[aliases]\npump = 3\nfan = 4\n[groups]\nlights = [1, 2]\n").unwrap();
+        let file = AliasFile::parse("[aliases]\nlights = 7\n[groups]\npump = [5, 6]\n").unwrap();
+        config.merge_aliases(file);
+        config.validate().unwrap();
+
+        assert_eq!(config.resolve_relays("pump"), Ok(vec![5, 6]));
+        assert_eq!(config.resolve_relays("lights"), Ok(vec![7]));
+        assert_eq!(config.resolve_relays("fan"), Ok(vec![4]));
+        assert_eq!(config.resolve_relays(" 2 "), Ok(vec![2]));
+        assert_eq!(config.resolve_relays("1-3,8"), Ok(vec![1, 2, 3, 8]));
+        assert_eq!(
+            config.resolve_relays("4-2").unwrap_err().reason,
+            "range '4-2' is descending, nor a known name"
+        );
+        assert!(config.resolve_relays("door").is_err());
+
+        assert!(AliasFile::parse("[aliases]\n3 = 1\n").is_err());
+        assert!(AliasFile::parse("[groups]\n12 = [1]\n").is_err());
+        assert!(AliasFile::parse("address_map = \"1:1\"\n").is_err());
+    }
+
+    #[test]
+    fn watcher_reloads_and_keeps_last_good_config() {
+        let path = temp_dir().join(format!("relay8x-config-test-{}.toml", process::id()));
+        let write = |contents: &str| {
+            File::create(&path)
+                .unwrap()
+                .write_all(contents.as_bytes())
+                .unwrap()
+        };
+        write("[aliases]\npump = 3\n");
+        let mut watcher = ConfigWatcher::new(&path).unwrap();
+        assert!(!watcher.poll());
+
+        write("[aliases]\npump = 4\nfan = 5\n");
+        assert!(watcher.poll());
+        assert_eq!(watcher.config().resolve("fan"), Some(vec![5]));
+
+        write("[aliases]\npump = 42\n");
+        assert!(!watcher.poll());
+        assert_eq!(watcher.config().resolve("pump"), Some(vec![4]));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn watcher_updates_the_relay() {
+        let path = temp_dir().join(format!("relay8x-update-test-{}.toml", process::id()));
+        let write = |contents: &str| {
+            File::create(&path)
+                .unwrap()
+                .write_all(contents.as_bytes())
+                .unwrap()
+        };
+        write("[aliases]\npump = 3\n");
+        let mut watcher = ConfigWatcher::new(&path).unwrap();
+        let sim = SimulatedRelays::new(1);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        assert!(!watcher.update(&mut relay));
+
+        write("[[interlocks]]\nrelays = [1, 2]\n");
+        assert!(watcher.update(&mut relay));
+        relay.set_relays(vec![1], vec![1]).unwrap();
+        assert!(relay.set_relays(vec![1], vec![2]).is_err());
+        assert_eq!(sim.relays(1), Some(0b1));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn watcher_drops_a_removed_address_map() {
+        let path = temp_dir().join(format!("relay8x-map-test-{}.toml", process::id()));
+        let write = |contents: &str| {
+            File::create(&path)
+                .unwrap()
+                .write_all(contents.as_bytes())
+                .unwrap()
+        };
+        let map = |spec: &str| Some(parse_address_map(spec).unwrap());
+        write("[aliases]\npump = 3\n");
+        let mut watcher = ConfigWatcher::new(&path).unwrap();
+        let mut relay = Relay8x::from_transport(SimulatedRelays::new(3), 1);
+
+        write("address_map = \"1:3\"\n");
+        assert!(watcher.update(&mut relay));
+        assert_eq!(relay.config.address_map, map("1:3"));
+        write("[aliases]\npump = 4\n");
+        assert!(watcher.update(&mut relay));
+        assert_eq!(relay.config.address_map, None);
+
+        // with a map given apart from the file, a file with one is rejected
+        watcher.set_address_map(map("1:2").unwrap()).unwrap();
+        write("address_map = \"1:3\"\n");
+        assert!(!watcher.update(&mut relay));
+        write("[aliases]\npump = 5\n");
+        assert!(watcher.update(&mut relay));
+        assert_eq!(relay.config.address_map, map("1:2"));
+
+        write("address_map = \"1:3\"\n");
+        let mut watcher = ConfigWatcher::new(&path).unwrap();
+        assert!(watcher.set_address_map(map("1:2").unwrap()).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+//! relay commands over a Unix domain socket, one command per line
+//!
+//! ```text
+//! set 1 3 on
+//! toggle 2
+//! reset 1-4
+//! ```
+//!
+//! switches relays 1 and 3 of the cards on, toggles relay 2 and switches relays 1 to 4
+//! off; every line is answered with `ok` or `error: <reason>`, the port stays open and
+//! initialised between the commands
+
+use std::fs::remove_file;
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use {expand_relays, CardIndex, ConfigWatcher, ParseError, Relay8x, RelayIndex};
+
+/// a command read from the socket
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// switch the relays on (`true`) or off
+    Set(RelayIndex, bool),
+    /// toggle the relays
+    Toggle(RelayIndex),
+    /// switch the relays off
+    Reset(RelayIndex),
+}
+
+/// parses a line like `set 1 3 on`, blank lines are `None`
+///
+/// relays are given like for `--relay`, e.g. `set 1-3,5 on`
+pub fn parse_command(line: &str) -> Result<Option<Command>, ParseError> {
+    let mut words: Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() {
+        return Ok(None);
+    }
+    let op = words.remove(0);
+    let state = match op {
+        "set" => match words.pop() {
+            Some("on") => Some(true),
+            Some("off") => Some(false),
+            _ => return Err(ParseError::new(line, "set ends with 'on' or 'off'")),
+        },
+        "toggle" | "reset" => None,
+        _ => {
+            return Err(ParseError::new(
+                line,
+                format!("'{}' is none of set, toggle and reset", op),
+            ))
+        }
+    };
+    if words.is_empty() {
+        return Err(ParseError::new(line, "name the relays, e.g. set 1 3 on"));
+    }
+    let relays = words
+        .iter()
+        .map(|&word| expand_relays(word))
+        .collect::<Result<Vec<RelayIndex>, _>>()
+        .map_err(|e| ParseError::new(line, e.reason))?
+        .concat();
+    Ok(Some(match (op, state) {
+        (_, Some(on)) => Command::Set(relays, on),
+        ("toggle", _) => Command::Toggle(relays),
+        _ => Command::Reset(relays),
+    }))
+}
+
+/// runs the commands read from `input` on the cards until it ends, answering each line
+/// on `output`
+///
+/// neither a malformed line nor a failing card ends the loop, both are answered with an
+/// error
+pub fn handle<R: BufRead, W: Write>(
+    relay: &mut Relay8x,
+    cards: &CardIndex,
+    input: R,
+    mut output: W,
+) -> io::Result<()> {
+    for line in input.lines() {
+        let result = match parse_command(&line?) {
+            Ok(Some(Command::Set(relays, true))) => relay.set_relays(cards.clone(), relays),
+            Ok(Some(Command::Set(relays, false))) | Ok(Some(Command::Reset(relays))) => {
+                relay.reset_relays(cards.clone(), relays)
+            }
+            Ok(Some(Command::Toggle(relays))) => relay.toggle_relays(cards.clone(), relays),
+            Ok(None) => continue,
+            Err(e) => {
+                writeln!(output, "error: {}", e)?;
+                continue;
+            }
+        };
+        match result {
+            Ok(_) => writeln!(output, "ok")?,
+            Err(e) => writeln!(output, "error: {}", e)?,
+        }
+    }
+    Ok(())
+}
+
+/// listens on the socket at `path`
+///
+/// a socket file left behind by a daemon which died is replaced, a socket some process
+/// still accepts connections on is reported as in use
+pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+    let path = path.as_ref();
+    match UnixListener::bind(path) {
+        Err(ref e) if e.kind() == ErrorKind::AddrInUse => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("{} is in use by another daemon", path.display()),
+                ));
+            }
+            remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+/// serves the connections to `listener` one after another, forever
+///
+/// a connection failing, e.g. a client hanging up early, is logged and dropped; a changed
+/// config of `watcher` is applied to `relay` before each connection is served
+pub fn serve(
+    relay: &mut Relay8x,
+    cards: &CardIndex,
+    listener: UnixListener,
+    mut watcher: Option<ConfigWatcher>,
+) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        if let Some(ref mut watcher) = watcher {
+            watcher.update(relay);
+        }
+        let result = stream
+            .try_clone()
+            .and_then(|input| handle(relay, cards, BufReader::new(input), stream));
+        if let Err(e) = result {
+            warn!("Connection failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sim::SimulatedRelays;
+    use std::env::temp_dir;
+    use std::process;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse_command("set 1 3 on"),
+            Ok(Some(Command::Set(vec![1, 3], true)))
+        );
+        assert_eq!(
+            parse_command(" set 1-3 off "),
+            Ok(Some(Command::Set(vec![1, 2, 3], false)))
+        );
+        assert_eq!(
+            parse_command("toggle 2"),
+            Ok(Some(Command::Toggle(vec![2])))
+        );
+        assert_eq!(
+            parse_command("reset 4,8"),
+            Ok(Some(Command::Reset(vec![4, 8])))
+        );
+        assert_eq!(parse_command("  "), Ok(None));
+        for line in &["set 1", "set on", "set 1 up", "toggle", "reset 9", "flip 1"] {
+            assert!(parse_command(line).is_err(), "{} should not parse", line);
+        }
+    }
+
+    #[test]
+    fn answers_every_line() {
+        let sim = SimulatedRelays::new(2);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        let mut output = Vec::new();
+        let input = "set 1 3 on\n\ntoggle 2\nflip 1\nreset 1\n";
+        handle(&mut relay, &vec![1, 2], input.as_bytes(), &mut output).unwrap();
+        assert_eq!(sim.relays(1), Some(0b110));
+        assert_eq!(sim.relays(2), Some(0b110));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "ok\nok\nerror: Can't parse 'flip 1': 'flip' is none of set, toggle and reset\nok\n"
+        );
+    }
+
+    #[test]
+    fn stale_socket_is_replaced() {
+        let path = temp_dir().join(format!("relay8x-daemon-test-{}.sock", process::id()));
+        let _ = remove_file(&path);
+        let listener = bind(&path).unwrap();
+        assert_eq!(bind(&path).unwrap_err().kind(), ErrorKind::AddrInUse);
+        drop(listener);
+        // the file is still there, but nothing listens anymore
+        assert!(path.exists());
+        bind(&path).unwrap();
+        remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use ParseError;
+
+/// parses a duration like `500ms`, `30s`, `5m`, `2h` or a combination like `1m30s`
+///
+/// combined parts have to go from larger to smaller units, each unit at most once
+pub fn parse_duration(spec: &str) -> Result<Duration, ParseError> {
+    let trimmed = spec.trim();
+    let invalid = || ParseError::new(spec, "use e.g. 500ms, 30s, 5m, 2h or 1m30s");
+    if trimmed.is_empty() {
+        return Err(invalid());
+    }
+    let mut rest = trimmed;
+    let mut millis: u64 = 0;
+    // milliseconds per unit of the previous part, the next one has to be smaller
+    let mut previous = u64::MAX;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let units = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = match &rest[..units] {
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[units..];
+        if unit >= previous {
+            return Err(invalid());
+        }
+        previous = unit;
+        millis = number
+            .checked_mul(unit)
+            .and_then(|part| millis.checked_add(part))
+            .ok_or_else(invalid)?;
+    }
+    Ok(Duration::from_millis(millis))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration(" 0s ").unwrap(), Duration::from_secs(0));
+
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("5x").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn parses_combined_units() {
+        assert_eq!(parse_duration("1m30s").unwrap(), Duration::from_secs(90));
+        assert_eq!(
+            parse_duration("1h2m3s4ms").unwrap(),
+            Duration::from_millis(3_723_004)
+        );
+        assert_eq!(
+            parse_duration("2s500ms").unwrap(),
+            Duration::from_millis(2500)
+        );
+
+        for spec in &["30s1m", "1s1s", "1m 30s", "1m30", "1.5s", "1m30x", "ms5"] {
+            assert!(parse_duration(spec).is_err(), "{} should not parse", spec);
+        }
+        let err = parse_duration("5x").unwrap_err();
+        assert_eq!(err.input, "5x");
+        assert_eq!(
+            err.to_string(),
+            "Can't parse '5x': use e.g. 500ms, 30s, 5m, 2h or 1m30s"
+        );
+        assert!(parse_duration(&format!("{}h", u64::MAX)).is_err());
+    }
+}
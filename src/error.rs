@@ -0,0 +1,221 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use frame::Frame;
+use Relay8xCmdSet;
+
+/// failures of the methods of `Relay8x`, of `build_frame` and of `rpc::execute`
+///
+/// parsers of user input like `parse_mask`, `parse_port` or `ConfigFile::parse` never
+/// reach a card, they keep returning an `io::Result` with `InvalidInput` or `InvalidData`,
+/// or a `ParseError`
+#[derive(Debug)]
+pub enum Relay8xError {
+    /// the card didn't answer within the configured timeout
+    Timeout {
+        /// the card which didn't answer
+        card: u8,
+        /// how long it was waited for
+        timeout: Duration,
+    },
+    /// the first byte of the response isn't the inverted command
+    BadFirstByte {
+        /// the first byte received
+        got: u8,
+        /// the inverted command sent
+        expected: u8,
+    },
+    /// the response comes from another address than the frame was sent to
+    WrongAddress {
+        /// the address of the response
+        got: u8,
+        /// the address sent to
+        expected: u8,
+    },
+    /// the response was garbled, retrying may help
+    BadChecksum(ChecksumError),
+    /// a relay number the card doesn't have, retrying won't help
+    RelayOutOfRange {
+        /// the relay number given
+        relay: u8,
+        /// the card it was given for
+        card: u8,
+        /// the highest relay number of the card
+        max: u8,
+    },
+    /// any other failure of the serial port or the protocol
+    Io(io::Error),
+    /// some cards of a multi-card command failed, the others were switched
+    Partial {
+        /// cards switched successfully
+        done: Vec<u8>,
+        /// cards which failed and why
+        failed: Vec<(u8, Relay8xError)>,
+    },
+}
+
+impl Relay8xError {
+    /// kind of the equivalent `io::Error`
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            Relay8xError::Timeout { .. } => ErrorKind::TimedOut,
+            Relay8xError::BadFirstByte { .. } | Relay8xError::WrongAddress { .. } => {
+                ErrorKind::Other
+            }
+            Relay8xError::BadChecksum(_) => ErrorKind::InvalidData,
+            Relay8xError::RelayOutOfRange { .. } => ErrorKind::InvalidInput,
+            Relay8xError::Io(ref e) => e.kind(),
+            Relay8xError::Partial { ref failed, .. } => failed
+                .first()
+                .map(|(_, e)| e.kind())
+                .unwrap_or(ErrorKind::Other),
+        }
+    }
+}
+
+impl fmt::Display for Relay8xError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Relay8xError::Timeout { card, timeout } => write!(
+                f,
+                "card {} did not respond within {}ms",
+                card,
+                timeout.as_millis()
+            ),
+            Relay8xError::BadFirstByte { got, expected } => {
+                write!(f, "Bad first byte: is {}, should be {}", got, expected)
+            }
+            Relay8xError::WrongAddress { got, expected } => write!(
+                f,
+                "Wrong Adress: 0x{:02x} instead of 0x{:02x}",
+                got, expected
+            ),
+            Relay8xError::BadChecksum(ref e) => write!(f, "{}", e),
+            Relay8xError::RelayOutOfRange { relay, card, max } => write!(
+                f,
+                "Relay number {} out of range (1..{}) of card {}",
+                relay, max, card
+            ),
+            Relay8xError::Io(ref e) => write!(f, "{}", e),
+            Relay8xError::Partial {
+                ref done,
+                ref failed,
+            } => {
+                write!(f, "cards switched: {:?}", done)?;
+                for &(card, ref e) in failed {
+                    write!(f, ", card {} failed: {}", card, e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for Relay8xError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            Relay8xError::Io(ref e) => Some(e),
+            Relay8xError::BadChecksum(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Relay8xError {
+    /// unwraps the errors `From<Relay8xError> for io::Error` wrapped, so they can be
+    /// matched again after passing through code returning `io::Result`
+    fn from(e: io::Error) -> Self {
+        if let Some(&checksum) = e.get_ref().and_then(|e| e.downcast_ref::<ChecksumError>()) {
+            return Relay8xError::BadChecksum(checksum);
+        }
+        if e.get_ref().is_some_and(|e| e.is::<Relay8xError>()) {
+            let inner = e.into_inner().expect("checked above");
+            return *inner.downcast::<Relay8xError>().expect("checked above");
+        }
+        Relay8xError::Io(e)
+    }
+}
+
+impl From<Relay8xError> for io::Error {
+    fn from(e: Relay8xError) -> Self {
+        match e {
+            Relay8xError::Io(e) => e,
+            Relay8xError::BadChecksum(e) => e.into(),
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+/// a malformed relay list, duration or similar given by the user
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// the text which failed to parse
+    pub input: String,
+    /// what is wrong with it
+    pub reason: String,
+}
+
+impl ParseError {
+    /// `input` failed to parse for `reason`
+    pub fn new<S: Into<String>>(input: &str, reason: S) -> Self {
+        Self {
+            input: input.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Can't parse '{}': {}", self.input, self.reason)
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> Self {
+        io::Error::new(ErrorKind::InvalidInput, e.to_string())
+    }
+}
+
+/// a frame whose last byte isn't the XOR of the others, e.g. garbled on a noisy line
+///
+/// the switching methods fail with `Relay8xError::BadChecksum`, the others carry it as
+/// the inner error of an `io::Error` of kind `InvalidData`, so it can be told apart from
+/// other invalid data with `get_ref`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChecksumError {
+    /// the frame as received
+    pub frame: [u8; 4],
+}
+
+impl ChecksumError {
+    /// the XOR the last byte should have
+    pub fn expected(&self) -> u8 {
+        Relay8xCmdSet::checksummed(&self.frame[..3])
+    }
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Bad checksum in frame {}: {:02x}, expected {:02x}",
+            Frame(&self.frame),
+            self.frame[3],
+            self.expected()
+        )
+    }
+}
+
+impl Error for ChecksumError {}
+
+impl From<ChecksumError> for io::Error {
+    fn from(e: ChecksumError) -> Self {
+        io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
@@ -0,0 +1,115 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+
+use {ChecksumError, Relay8xCmdSet};
+
+/// most bytes of a buffer shown by `Frame`, the rest is cut off
+const MAX_DUMP_BYTES: usize = 16;
+
+/// displays raw bytes of a frame as space separated hex, e.g. `06 01 04 03`
+///
+/// buffers longer than 16 bytes, e.g. several frames read at once, are cut off with an
+/// ellipsis and their length
+pub struct Frame<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Display for Frame<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().take(MAX_DUMP_BYTES).enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        if self.0.len() > MAX_DUMP_BYTES {
+            write!(f, " ... ({} bytes)", self.0.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// a frame split into its fields, the inverse of `Relay8xCmdSet::encode`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandFrame {
+    /// command byte, inverted in responses
+    pub cmd: u8,
+    /// address of the card
+    pub address: u8,
+    /// relay mask or other payload
+    pub data: u8,
+    /// XOR of the other three bytes
+    pub xor: u8,
+}
+
+impl TryFrom<[u8; 4]> for CommandFrame {
+    type Error = io::Error;
+
+    /// parses a raw frame, failing with a `ChecksumError` if the XOR doesn't match
+    fn try_from(bytes: [u8; 4]) -> io::Result<Self> {
+        let [cmd, address, data, xor] = bytes;
+        if xor != Relay8xCmdSet::checksummed(&bytes[..3]) {
+            return Err(ChecksumError { frame: bytes }.into());
+        }
+        Ok(Self {
+            cmd,
+            address,
+            data,
+            xor,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn displays_hex_bytes() {
+        assert_eq!(Frame(&[6, 1, 4, 3]).to_string(), "06 01 04 03");
+        assert_eq!(Frame(&[0xf9]).to_string(), "f9");
+        assert_eq!(Frame(&[]).to_string(), "");
+        let long = [0xaa; 40];
+        assert_eq!(
+            Frame(&long).to_string(),
+            format!("{} ... (40 bytes)", Frame(&long[..16]))
+        );
+    }
+
+    fn encoded(cmd_set: Relay8xCmdSet, relays: Option<&Vec<u8>>) -> [u8; 4] {
+        let mut bytes = BytesMut::with_capacity(4);
+        cmd_set.encode(&mut bytes, 1, Some(2), relays).unwrap();
+        [bytes[0], bytes[1], bytes[2], bytes[3]]
+    }
+
+    #[test]
+    fn parses_encoded_frames() {
+        let relays = vec![1, 3];
+        let cases = vec![
+            (Relay8xCmdSet::Init, None, 1, 1, 0),
+            (Relay8xCmdSet::GetPort, None, 2, 2, 0),
+            (Relay8xCmdSet::SetPort, Some(&relays), 3, 2, 0b101),
+            (Relay8xCmdSet::GetOption, None, 4, 2, 0),
+            (Relay8xCmdSet::SetOption(0b10), None, 5, 2, 0b10),
+            (Relay8xCmdSet::Set, Some(&relays), 6, 2, 0b101),
+            (Relay8xCmdSet::Reset, Some(&relays), 7, 2, 0b101),
+            (Relay8xCmdSet::Toggle, Some(&relays), 8, 2, 0b101),
+        ];
+        for (cmd_set, relays, cmd, address, data) in cases {
+            let bytes = encoded(cmd_set, relays);
+            let frame = CommandFrame::try_from(bytes).unwrap();
+            assert_eq!((frame.cmd, frame.address, frame.data), (cmd, address, data));
+            assert_eq!(frame.xor, bytes[3]);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let err = CommandFrame::try_from([6, 1, 4, 0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let checksum = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ChecksumError>());
+        assert_eq!(checksum.map(|c| c.expected()), Some(6 ^ 1 ^ 4));
+    }
+}
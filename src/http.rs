@@ -0,0 +1,252 @@
+//! REST API for dashboards
+//!
+//! - `GET /relays` lists the states of all relays of the cards
+//! - `POST /relays/<n>` with a body `{"state": "on"}` or `{"state": "off"}` switches
+//!   relay `n` of the cards
+//! - `POST /relays/<n>/toggle` toggles relay `n` of the cards
+//!
+//! every answer is JSON, the relay states after the request or `{"error": "<reason>"}`;
+//! requests are handled by several threads, taking turns on the port
+
+use std::io;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use serde_json;
+use tiny_http::{Header, Method, Response, Server};
+
+use {iter_relays, parse_relay, CardIndex, ConfigWatcher, Relay8x, Relay8xError};
+
+/// threads answering requests, a slow client doesn't hold up the others
+const WORKERS: usize = 4;
+
+/// state of one relay, entry of every successful answer
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RelayState {
+    /// card the relay is on
+    pub card: u8,
+    /// relay number on the card
+    pub relay: u8,
+    /// `on` or `off`
+    pub state: &'static str,
+}
+
+/// body of `POST /relays/<n>`
+#[derive(Deserialize)]
+struct SwitchBody {
+    state: String,
+}
+
+/// body of a failed request
+#[derive(Serialize)]
+struct Failure {
+    error: String,
+}
+
+/// status code and reason of a failed request
+type Rejection = (u16, String);
+
+/// answers requests to `server` until it fails
+///
+/// `cards` are the cards the relays are switched and listed of, a changed config of
+/// `watcher` is applied to `relay` before a request is answered
+pub fn serve(
+    relay: Relay8x,
+    server: Server,
+    cards: CardIndex,
+    watcher: Option<ConfigWatcher>,
+) -> io::Result<()> {
+    let relay = Arc::new(Mutex::new(relay));
+    let server = Arc::new(server);
+    let watcher = Arc::new(Mutex::new(watcher));
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let (relay, server, cards) = (relay.clone(), server.clone(), cards.clone());
+            let watcher = watcher.clone();
+            thread::spawn(move || work(&relay, &server, &cards, &watcher))
+        })
+        .collect();
+    for worker in workers {
+        worker
+            .join()
+            .map_err(|_| io::Error::other("HTTP worker panicked"))??;
+    }
+    Ok(())
+}
+
+/// answers requests one after another until the server fails
+fn work(
+    relay: &Mutex<Relay8x>,
+    server: &Server,
+    cards: &CardIndex,
+    watcher: &Mutex<Option<ConfigWatcher>>,
+) -> io::Result<()> {
+    let json = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .expect("static header is valid");
+    loop {
+        let mut request = server.recv()?;
+        if let Some(ref mut watcher) = *watcher.lock().unwrap_or_else(PoisonError::into_inner) {
+            watcher.update(&mut relay.lock().unwrap_or_else(PoisonError::into_inner));
+        }
+        let mut body = String::new();
+        let (code, text) = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => answer(relay, cards, request.method(), request.url(), &body),
+            Err(e) => (400, failure(format!("Unreadable body: {}", e))),
+        };
+        let response = Response::from_string(text)
+            .with_status_code(code)
+            .with_header(json.clone());
+        if let Err(e) = request.respond(response) {
+            warn!("Failed to answer a request: {}", e);
+        }
+    }
+}
+
+/// status code and JSON text answering `method` on `url` with `body`
+pub fn answer(
+    relay: &Mutex<Relay8x>,
+    cards: &CardIndex,
+    method: &Method,
+    url: &str,
+    body: &str,
+) -> (u16, String) {
+    match route(relay, cards, method, url, body) {
+        Ok(states) => match serde_json::to_string(&states) {
+            Ok(text) => (200, text),
+            Err(e) => (500, failure(e.to_string())),
+        },
+        Err((code, reason)) => (code, failure(reason)),
+    }
+}
+
+/// runs the request and reads back the relay states
+fn route(
+    relay: &Mutex<Relay8x>,
+    cards: &CardIndex,
+    method: &Method,
+    url: &str,
+    body: &str,
+) -> Result<Vec<RelayState>, Rejection> {
+    let path = url.split('?').next().unwrap_or_default().trim_matches('/');
+    let segments: Vec<&str> = path.split('/').collect();
+    let bad = |reason: String| (400, reason);
+    // one request at a time on the port, also when another worker panicked
+    let mut relay = relay.lock().unwrap_or_else(PoisonError::into_inner);
+    match (method, segments.as_slice()) {
+        (&Method::Get, ["relays"]) => {}
+        (&Method::Post, ["relays", number]) => {
+            let number = vec![parse_relay(number).map_err(|e| bad(e.to_string()))?];
+            let body: SwitchBody = serde_json::from_str(body)
+                .map_err(|e| bad(format!("Expected {{\"state\": \"on\"}}: {}", e)))?;
+            let result = match body.state.as_str() {
+                "on" => relay.set_relays(cards.clone(), number),
+                "off" => relay.reset_relays(cards.clone(), number),
+                other => return Err(bad(format!("State '{}' is neither on nor off", other))),
+            };
+            result.map_err(failed)?;
+        }
+        (&Method::Post, ["relays", number, "toggle"]) => {
+            let number = vec![parse_relay(number).map_err(|e| bad(e.to_string()))?];
+            relay.toggle_relays(cards.clone(), number).map_err(failed)?;
+        }
+        (_, ["relays"]) | (_, ["relays", _]) | (_, ["relays", _, "toggle"]) => {
+            return Err((405, format!("{} is not allowed on {}", method, url)));
+        }
+        _ => return Err((404, format!("No such resource {}", url))),
+    }
+    states(&mut relay, cards).map_err(failed)
+}
+
+/// states of all relays of the cards
+fn states(relay: &mut Relay8x, cards: &CardIndex) -> Result<Vec<RelayState>, Relay8xError> {
+    let mut states = Vec::new();
+    for (&card, state) in cards.iter().zip(relay.get_relays(cards.clone())?) {
+        let relays = relay.all_relays(card);
+        for (number, on) in iter_relays(state).filter(|&(r, _)| relays.contains(&r)) {
+            states.push(RelayState {
+                card,
+                relay: number,
+                state: if on { "on" } else { "off" },
+            });
+        }
+    }
+    Ok(states)
+}
+
+/// a request the cards failed, 400 if the request asked for the impossible, e.g. a relay
+/// the card doesn't have, 502 if the cards didn't answer properly
+fn failed(e: Relay8xError) -> Rejection {
+    let e: io::Error = e.into();
+    match e.kind() {
+        ErrorKind::InvalidInput => (400, e.to_string()),
+        _ => (502, e.to_string()),
+    }
+}
+
+/// JSON text of a failure
+fn failure(reason: String) -> String {
+    serde_json::to_string(&Failure { error: reason })
+        .unwrap_or_else(|_| "{\"error\":\"unknown\"}".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sim::SimulatedRelays;
+
+    #[test]
+    fn switches_and_lists_relays() {
+        let sim = SimulatedRelays::new(2);
+        let relay = Mutex::new(Relay8x::from_transport(sim.clone(), 1));
+        let cards = vec![2];
+
+        let (code, text) = answer(
+            &relay,
+            &cards,
+            &Method::Post,
+            "/relays/3",
+            r#"{"state":"on"}"#,
+        );
+        assert_eq!(code, 200, "{}", text);
+        let (code, _) = answer(&relay, &cards, &Method::Post, "/relays/1/toggle", "");
+        assert_eq!(code, 200);
+        assert_eq!(sim.relays(1), Some(0));
+        assert_eq!(sim.relays(2), Some(0b101));
+
+        let (code, text) = answer(&relay, &cards, &Method::Get, "/relays", "");
+        assert_eq!(code, 200);
+        assert!(text.starts_with(
+            r#"[{"card":2,"relay":1,"state":"on"},{"card":2,"relay":2,"state":"off"},"#
+        ));
+        let (code, _) = answer(
+            &relay,
+            &cards,
+            &Method::Post,
+            "/relays/3",
+            r#"{"state":"off"}"#,
+        );
+        assert_eq!(code, 200);
+        assert_eq!(sim.relays(2), Some(0b001));
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let sim = SimulatedRelays::new(1);
+        let relay = Mutex::new(Relay8x::from_transport(sim.clone(), 1));
+        let cards = vec![1];
+        let cases = [
+            (Method::Post, "/relays/9/toggle", "", 400),
+            (Method::Post, "/relays/3", r#"{"state":"up"}"#, 400),
+            (Method::Post, "/relays/3", "on", 400),
+            (Method::Delete, "/relays", "", 405),
+            (Method::Get, "/cards", "", 404),
+        ];
+        for &(ref method, url, body, code) in &cases {
+            let (got, text) = answer(&relay, &cards, method, url, body);
+            assert_eq!(got, code, "{} {}: {}", method, url, text);
+            assert!(text.starts_with(r#"{"error":"#), "{}", text);
+        }
+        assert_eq!(sim.relays(1), Some(0));
+    }
+}
@@ -0,0 +1,89 @@
+//! relays driven by level changes, one `<relay>=<level>` line each
+//!
+//! ```text
+//! 3=1
+//! 3=0
+//! ```
+//!
+//! switches relay 3 of the cards on and off again, another program can drive relays by
+//! writing such lines to a pipe
+
+use std::io;
+use std::io::{BufRead, Write};
+
+use {parse_relay, CardIndex, ParseError, Relay8x};
+
+/// parses a line like `3=1`, blank lines are `None`
+pub fn parse_level(line: &str) -> Result<Option<(u8, bool)>, ParseError> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let (relay, level) = line
+        .split_once('=')
+        .ok_or_else(|| ParseError::new(line, "use <relay>=<0|1>, e.g. 3=1"))?;
+    let relay = parse_relay(relay).map_err(|e| ParseError::new(line, e.reason))?;
+    match level.trim() {
+        "1" => Ok(Some((relay, true))),
+        "0" => Ok(Some((relay, false))),
+        other => Err(ParseError::new(
+            line,
+            format!("level '{}' is neither 0 nor 1", other),
+        )),
+    }
+}
+
+/// switches the relays of the cards for every line read from `input` until it ends
+///
+/// malformed lines are reported to `errors` and skipped, a failing card stops the loop
+pub fn run<R: BufRead, W: Write>(
+    relay: &mut Relay8x,
+    cards: &CardIndex,
+    input: R,
+    mut errors: W,
+) -> io::Result<()> {
+    for (i, line) in input.lines().enumerate() {
+        match parse_level(&line?) {
+            Ok(Some((number, true))) => {
+                relay.set_relays(cards.clone(), vec![number])?;
+            }
+            Ok(Some((number, false))) => {
+                relay.reset_relays(cards.clone(), vec![number])?;
+            }
+            Ok(None) => {}
+            Err(e) => writeln!(errors, "Line {}: {}", i + 1, e)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sim::SimulatedRelays;
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!(parse_level("3=1"), Ok(Some((3, true))));
+        assert_eq!(parse_level(" 8 = 0 "), Ok(Some((8, false))));
+        assert_eq!(parse_level("  "), Ok(None));
+        for line in &["3", "3=2", "0=1", "9=0", "a=1", "=1", "3=on"] {
+            assert!(parse_level(line).is_err(), "{} should not parse", line);
+        }
+    }
+
+    #[test]
+    fn switches_relays_and_skips_bad_lines() {
+        let sim = SimulatedRelays::new(2);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        let mut errors = Vec::new();
+        let input = "3=1\n\n2=1\nx\n3=0\n";
+        run(&mut relay, &vec![1, 2], input.as_bytes(), &mut errors).unwrap();
+        assert_eq!(sim.relays(1), Some(0b10));
+        assert_eq!(sim.relays(2), Some(0b10));
+        assert_eq!(
+            String::from_utf8(errors).unwrap(),
+            "Line 4: Can't parse 'x': use <relay>=<0|1>, e.g. 3=1\n"
+        );
+    }
+}
@@ -1,49 +1,391 @@
+//! library behind the `relay8x` command line tool, to switch the relays of a chain of
+//! Conrad 8 channel relay cards from your own programs
+//!
+//! ```no_run
+//! use relay8x::Relay8x;
+//!
+//! let mut relay = Relay8x::new("/dev/ttyUSB0", 1).unwrap();
+//! relay.configure_device().unwrap();
+//! relay.set_relays(vec![1], vec![1, 3]).unwrap();
+//! assert_eq!(relay.get_relays(vec![1]).unwrap(), vec![0b101]);
+//! ```
+#![deny(missing_docs)]
+
 extern crate bytes;
+extern crate fs2;
 extern crate serial;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "mqtt")]
+extern crate rumqttc;
+extern crate serde;
+#[cfg(feature = "http")]
+extern crate tiny_http;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
+#[cfg(feature = "ws")]
+extern crate tungstenite;
 
 use bytes::{BufMut, BytesMut};
-use serial::prelude::*;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
 use std::io;
-use std::io::{Error, ErrorKind};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::thread;
 use std::time::{Duration, Instant};
 
-/// type alias for relay vecs
+pub mod audit;
+mod backoff;
+pub mod capture;
+mod config;
+mod config_file;
+#[cfg(unix)]
+pub mod daemon;
+mod duration;
+mod error;
+mod frame;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod levels;
+mod lock;
+#[cfg(test)]
+mod mock;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod rpc;
+pub mod sim;
+mod transport;
+mod watch;
+#[cfg(feature = "ws")]
+pub mod ws;
+
+pub use backoff::Backoff;
+pub use capture::Capture;
+pub use config::{
+    check_baud, check_pulse, check_timeout, parse_address_map, parse_card_relays, Interlock,
+    InterlockPolicy, RateLimitPolicy, Relay8xConfig, BAUD_RATES, MAX_PULSE,
+};
+pub use config_file::{AliasFile, ConfigFile, ConfigWatcher, Momentary};
+pub use duration::parse_duration;
+pub use error::{ChecksumError, ParseError, Relay8xError};
+pub use frame::{CommandFrame, Frame};
+pub use lock::DeviceLock;
+pub use transport::{IoTransport, RelayTransport, SerialTransport, TcpTransport, TCP_SCHEME};
+pub use watch::Debouncer;
+
+/// relay numbers, 1 to 8 on a card with 8 relays
 pub type RelayIndex = Vec<u8>;
-/// type alias for card index vecs
+/// card numbers, counting from 1 along the chain
 pub type CardIndex = Vec<u8>;
 
+/// yields `(relay_number, on)` for relays 1..=8 of a relay mask
+///
+/// bit 0 of the mask is relay 1, bit 7 is relay 8:
+///
+/// ```
+/// let states: Vec<(u8, bool)> = relay8x::iter_relays(0b0000_0101).collect();
+/// assert_eq!(states[0], (1, true));
+/// assert_eq!(states[1], (2, false));
+/// assert_eq!(states[2], (3, true));
+/// assert_eq!(states.len(), 8);
+/// ```
+pub fn iter_relays(mask: u8) -> impl Iterator<Item = (u8, bool)> {
+    (1..=8).map(move |relay| (relay, mask & (1 << (relay - 1)) != 0))
+}
+
+/// relay numbers of the bits set in a mask, e.g. a state read by `get_relays`
+///
+/// the inverse of the mask a frame carries for a relay list:
+///
+/// ```
+/// assert_eq!(relay8x::u8_as_relays(0b1000_0101), vec![1, 3, 8]);
+/// ```
+pub fn u8_as_relays(mask: u8) -> RelayIndex {
+    iter_relays(mask)
+        .filter(|&(_, on)| on)
+        .map(|(relay, _)| relay)
+        .collect()
+}
+
+/// bit of a relay number in a mask, nothing for numbers outside 1..8
+fn relay_bit(relay: u8) -> u8 {
+    if (1..=8).contains(&relay) {
+        1 << (relay - 1)
+    } else {
+        0
+    }
+}
+
+/// parses a single relay number 1..8, e.g. `3`, ` 3 ` or `03`
+///
+/// anything but digits around the whitespace is rejected, naming the token
+pub fn parse_relay(token: &str) -> Result<u8, ParseError> {
+    let digits = token.trim();
+    let number = if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        digits.parse::<u8>().ok()
+    } else {
+        None
+    };
+    match number {
+        Some(r) if r > 0 && r <= 8 => Ok(r),
+        _ => Err(ParseError::new(
+            token,
+            format!("'{}' is no relay number (1..8)", digits),
+        )),
+    }
+}
+
+/// expands a relay list like `1-3,5` into the relay numbers `[1, 2, 3, 5]`
+///
+/// entries are single relays or ascending ranges of relays 1..8, each relay may only
+/// be listed once
+pub fn expand_relays(spec: &str) -> Result<RelayIndex, ParseError> {
+    let relay = |s: &str| parse_relay(s).map_err(|e| ParseError::new(spec, e.reason));
+    let mut relays = RelayIndex::new();
+    for entry in spec.split(',') {
+        let (start, end) = match entry.split_once('-') {
+            Some((start, end)) => (relay(start)?, relay(end)?),
+            None => (relay(entry)?, relay(entry)?),
+        };
+        if start > end {
+            return Err(ParseError::new(
+                spec,
+                format!("range '{}' is descending", entry.trim()),
+            ));
+        }
+        if let Some(r) = (start..=end).find(|r| relays.contains(r)) {
+            return Err(ParseError::new(
+                spec,
+                format!("relay {} is listed twice", r),
+            ));
+        }
+        relays.extend(start..=end);
+    }
+    Ok(relays)
+}
+
+/// parses per-relay states like `1:on,3:off` into the relays to change and their
+/// desired states, as masks
+///
+/// relays not listed keep their state, each relay may only be listed once
+pub fn parse_relay_states(spec: &str) -> Result<(u8, u8), ParseError> {
+    let (mut affect, mut value) = (0u8, 0u8);
+    for entry in spec.split(',') {
+        let (relay, state) = entry
+            .split_once(':')
+            .ok_or_else(|| ParseError::new(spec, "use <relay>:<on|off>, e.g. 1:on,3:off"))?;
+        let bit = match parse_relay(relay) {
+            Ok(r) => 1 << (r - 1),
+            Err(e) => return Err(ParseError::new(spec, e.reason)),
+        };
+        if affect & bit != 0 {
+            return Err(ParseError::new(
+                spec,
+                format!("relay {} is listed twice", relay.trim()),
+            ));
+        }
+        affect |= bit;
+        match state.trim() {
+            "on" => value |= bit,
+            "off" => {}
+            other => {
+                return Err(ParseError::new(
+                    spec,
+                    format!("state '{}' is neither on nor off", other),
+                ))
+            }
+        }
+    }
+    Ok((affect, value))
+}
+
+/// parses a relay mask given as binary (`0b00001010`), hex (`0x0a`) or decimal (`10`)
+///
+/// bit 0 is relay 1, the mask must select at least one of the first `relays` relays and
+/// nothing above them
+pub fn parse_mask(spec: &str, relays: u8) -> io::Result<u8> {
+    let spec = spec.trim();
+    let mask = parse_port(spec)?;
+    if mask == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Mask '{}' selects no relay", spec),
+        ));
+    }
+    if relays < 8 && mask >> relays != 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Mask '{}' exceeds the {} relays of a card", spec, relays),
+        ));
+    }
+    Ok(mask)
+}
+
+/// parses the states of all relays of a card for `write_port`, written like a mask of
+/// `parse_mask`; 0 switches all relays off
+pub fn parse_port(spec: &str) -> io::Result<u8> {
+    let spec = spec.trim();
+    let parsed = if let Some(bin) = spec.strip_prefix("0b") {
+        u8::from_str_radix(bin, 2)
+    } else if let Some(hex) = spec.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16)
+    } else {
+        spec.parse::<u8>()
+    };
+    parsed.map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Bad mask '{}', use e.g. 0b00001010, 0x0a or 10", spec),
+        )
+    })
+}
+
+/// result of exercising a single relay in `self_test`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelayTest {
+    /// the relay read back as on after switching it on and as off after switching it off
+    Pass,
+    /// the card reported other relay states than expected, bitmasks like `get_relays`
+    ReadBackMismatch {
+        /// the states the relays should have
+        expected: u8,
+        /// the states read back
+        got: u8,
+    },
+    /// momentary relays aren't switched on for testing
+    Skipped,
+}
+
+impl fmt::Display for RelayTest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RelayTest::Pass => write!(f, "PASS"),
+            RelayTest::ReadBackMismatch { expected, got } => write!(
+                f,
+                "FAIL (read-back mismatch: {:08b} instead of {:08b})",
+                got, expected
+            ),
+            RelayTest::Skipped => write!(f, "SKIPPED (momentary)"),
+        }
+    }
+}
+
+/// what a card reported during initialisation
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardInfo {
+    /// address the card was assigned
+    pub address: u8,
+    /// version of the microcontroller software
+    pub firmware: u8,
+    /// number of relays on the card
+    ///
+    /// the init response of the known firmwares (conrad 197720 and 197730) carries only
+    /// the software version and no other command reports capabilities, so the relay count
+    /// can't be detected and falls back to the configured `card_relays` of the n-th card
+    /// or `relays_per_card`
+    pub relays: u8,
+}
+
+/// what happens to the relays when a `Relay8x` is dropped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropBehavior {
+    /// the relays keep their states
+    Leave,
+    /// every card is switched off like by `reset_all`
+    AllOff,
+}
+
+/// callback for switched relays: card, relay number and whether the relay is on now
+pub type OnChange = dyn FnMut(u8, u8, bool) + Send;
+
+/// replacement for the response check: sent frame, received frame
+///
+/// an error rejects the response just like a bad frame checked by the default validation,
+/// which expects the inverted command, the address sent to and a matching XOR
+pub type ResponseValidator = dyn Fn(&[u8], &[u8]) -> Result<(), Relay8xError> + Send;
+
 /// Provides access to serial port
+///
+/// the port is owned, so a `Relay8x` can be handed to another thread, e.g. a worker
+/// switching the relays for the rest of a program
 pub struct Relay8x {
-    // address of the first card, succeding card has +1 and so on
-    start_address: u8,
+    // address of the first card, baud rate, timeouts and the like
+    config: Relay8xConfig,
+    // path of the serial device, for error messages
+    device: Option<String>,
     // struct containing the serial port settings and stuff
-    port: Box<SerialPort>,
+    port: Box<dyn RelayTransport + Send>,
+    // records every frame on the wire if set
+    capture: Option<Capture>,
+    // print every frame on the wire to stderr
+    echo_frames: bool,
+    // cards which responded to the last initialisation
+    cards: Vec<CardInfo>,
+    // called with card, relay and new state for every relay switched
+    on_change: Option<Box<OnChange>>,
+    // relay states known from previous frames per address: which bits are known and
+    // their raw port value
+    known: BTreeMap<u8, (u8, u8)>,
+    // checks responses instead of `check_response` if set
+    validator: Option<Box<ResponseValidator>>,
+    // when each relay was last switched per address, for `min_interval`
+    last_switched: BTreeMap<u8, [Option<Instant>; 8]>,
+    // whether the relays are switched off on drop
+    drop_behavior: DropBehavior,
+    // resends of a frame whose answer was garbled
+    retries: u8,
 }
 
+/// time given to the cards to process the init frame when its acknowledgement is skipped
+const INIT_ACK_DELAY: Duration = Duration::from_millis(100);
+
+/// wire protocol spoken to the cards, changes with incompatible frames
+pub const PROTOCOL: &str = "conrad-8relay-v1";
+
+/// address every card executes a frame for
+const BROADCAST_ADDRESS: u8 = 0;
+
+/// on and off times of the locate pattern, a double flash repeated every second
+const LOCATE_PATTERN: [(bool, u64); 4] = [(true, 100), (false, 100), (true, 100), (false, 700)];
+
+/// pause between the queries of `measure_latency`, keeps it from flooding the bus
+const LATENCY_POLL: Duration = Duration::from_millis(5);
+
 /// enum for all possbile commands
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Relay8xCmdSet {
-    // initialisation command
+    /// initialisation command
     Init,
-    // switch relays on or off
+    /// query the relay states
+    GetPort,
+    /// switch the given relays on and all others off
+    SetPort,
+    /// read the option byte
+    GetOption,
+    /// write the option byte
+    SetOption(u8),
+    /// switch relays on or off
     Set,
-    // toggle relays
+    /// toggle relays
     Toggle,
-    // reset (switch off) relays
+    /// reset (switch off) relays
     Reset,
 }
 
 impl Relay8xCmdSet {
     /// based on command, address and card, returns the command frame for the relay card
+    ///
+    /// set port, set, reset and toggle need `relays`, each of them in 1..8
     pub fn encode(
-        self,
+        &self,
         bytes: &mut BytesMut,
         start_address: u8,
         card: Option<u8>,
         relays: Option<&RelayIndex>,
-    ) -> io::Result<()> {
+    ) -> Result<(), Relay8xError> {
         match self {
             Relay8xCmdSet::Init => {
                 let cmd = 1; // init command: 1
@@ -52,60 +394,112 @@ impl Relay8xCmdSet {
                 bytes.put_u8(0); // third: dont care
                 let checksum = Relay8xCmdSet::checksummed(&bytes[..]); // fourth: XOR
                 bytes.put_u8(checksum);
-                debug!(
-                    "Init command: {:02x} {:02x} {:02x} {:02x}",
-                    &bytes[0], &bytes[1], &bytes[2], &bytes[3]
-                );
+                debug!("Init command: {}", Frame(&bytes[..]));
+            }
+            Relay8xCmdSet::GetPort => {
+                let cmd = 2; // get port command: 2
+                bytes.put_u8(cmd); // first byte: command
+                let address = Relay8xCmdSet::addressed(start_address, card)?;
+                bytes.put_u8(address); // second byte: address of card
+                bytes.put_u8(0); // third: dont care
+                let checksum = Relay8xCmdSet::checksummed(&bytes[..]); // fourth: XOR
+                bytes.put_u8(checksum);
+                debug!("GetPort command: {}", Frame(&bytes[..]));
+            }
+            Relay8xCmdSet::SetPort => {
+                let cmd = 3; // set port command: 3
+                bytes.put_u8(cmd); // first byte: command
+                let address = Relay8xCmdSet::addressed(start_address, card)?;
+                bytes.put_u8(address); // second byte: address of card
+                let relay_bin = self.relay_data(card, relays)?;
+                debug!("Port to write: {:08b}", relay_bin);
+                bytes.put_u8(relay_bin); // third byte: data of relays
+                let checksum = Relay8xCmdSet::checksummed(&bytes[..]); // fourth: XOR
+                bytes.put_u8(checksum);
+                debug!("SetPort command: {}", Frame(&bytes[..]));
+            }
+            Relay8xCmdSet::GetOption => {
+                let cmd = 4; // get option command: 4
+                bytes.put_u8(cmd); // first byte: command
+                let address = Relay8xCmdSet::addressed(start_address, card)?;
+                bytes.put_u8(address); // second byte: address of card
+                bytes.put_u8(0); // third: dont care
+                let checksum = Relay8xCmdSet::checksummed(&bytes[..]); // fourth: XOR
+                bytes.put_u8(checksum);
+                debug!("GetOption command: {}", Frame(&bytes[..]));
+            }
+            Relay8xCmdSet::SetOption(option) => {
+                let cmd = 5; // set option command: 5
+                bytes.put_u8(cmd); // first byte: command
+                let address = Relay8xCmdSet::addressed(start_address, card)?;
+                bytes.put_u8(address); // second byte: address of card
+                bytes.put_u8(*option); // third byte: option byte
+                let checksum = Relay8xCmdSet::checksummed(&bytes[..]); // fourth: XOR
+                bytes.put_u8(checksum);
+                debug!("SetOption command: {}", Frame(&bytes[..]));
             }
             Relay8xCmdSet::Set => {
                 let cmd = 6; // command for turning on: 6
                 bytes.put_u8(cmd); // first byte: command
-                let address = Relay8xCmdSet::addressed(start_address, card);
+                let address = Relay8xCmdSet::addressed(start_address, card)?;
                 bytes.put_u8(address); // second byte: address of card
-                let relay_bin = Relay8xCmdSet::relay_as_u8(relays.unwrap());
+                let relay_bin = self.relay_data(card, relays)?;
                 debug!("Relays to set: {:08b}", relay_bin);
                 bytes.put_u8(relay_bin); // third byte: data of relays
                 let checksum = Relay8xCmdSet::checksummed(&bytes[..]); // fourth: XOR
                 bytes.put_u8(checksum);
-                debug!(
-                    "Set command: {:02x} {:02x} {:02x} {:02x}",
-                    &bytes[0], &bytes[1], &bytes[2], &bytes[3]
-                );
+                debug!("Set command: {}", Frame(&bytes[..]));
             }
             Relay8xCmdSet::Toggle => {
                 let cmd = 8; // command for turning on
                 bytes.put_u8(cmd); // first byte: command
-                let address = Relay8xCmdSet::addressed(start_address, card);
+                let address = Relay8xCmdSet::addressed(start_address, card)?;
                 bytes.put_u8(address); // second byte: address of card
-                let relay_bin = Relay8xCmdSet::relay_as_u8(relays.unwrap());
+                let relay_bin = self.relay_data(card, relays)?;
                 debug!("Relays to set: {:08b}", relay_bin);
                 bytes.put_u8(relay_bin); // third byte: data of relays
                 let checksum = Relay8xCmdSet::checksummed(&bytes[..]); // fourth: XOR
                 bytes.put_u8(checksum);
-                debug!(
-                    "Toggle command: {:02x} {:02x} {:02x} {:02x}",
-                    &bytes[0], &bytes[1], &bytes[2], &bytes[3]
-                );
+                debug!("Toggle command: {}", Frame(&bytes[..]));
             }
             Relay8xCmdSet::Reset => {
                 let cmd = 7; // command for turning on
                 bytes.put_u8(cmd);
-                let address = Relay8xCmdSet::addressed(start_address, card);
+                let address = Relay8xCmdSet::addressed(start_address, card)?;
                 bytes.put_u8(address); // second byte: address of card
-                let relay_bin = Relay8xCmdSet::relay_as_u8(relays.unwrap());
+                let relay_bin = self.relay_data(card, relays)?;
                 debug!("Relays to set: {:08b}", relay_bin);
                 bytes.put_u8(relay_bin); // third byte: data of relays
                 let checksum = Relay8xCmdSet::checksummed(&bytes[..]); // fourth: XOR
                 bytes.put_u8(checksum);
-                debug!(
-                    "Reset command: {:02x} {:02x} {:02x} {:02x}",
-                    &bytes[0], &bytes[1], &bytes[2], &bytes[3]
-                );
+                debug!("Reset command: {}", Frame(&bytes[..]));
             }
         }
         Ok(())
     }
 
+    /// data byte of a command switching `relays`, rejecting relays a card can't have
+    fn relay_data(
+        &self,
+        card: Option<u8>,
+        relays: Option<&RelayIndex>,
+    ) -> Result<u8, Relay8xError> {
+        let relays = relays.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} needs the relays to switch", self),
+            )
+        })?;
+        if let Some(&relay) = relays.iter().find(|&&r| r == 0 || r > 8) {
+            return Err(Relay8xError::RelayOutOfRange {
+                relay,
+                card: card.unwrap_or(1),
+                max: 8,
+            });
+        }
+        Ok(Relay8xCmdSet::relay_as_u8(relays))
+    }
+
     /// calculates the data byte based on the relays to be switchted
     fn relay_as_u8(vec: &RelayIndex) -> u8 {
         let mut relay_bin = 0b00000000;
@@ -121,234 +515,3341 @@ impl Relay8xCmdSet {
     }
 
     /// calculates the address for each card based on starting address of first card
-    fn addressed(address: u8, card: Option<u8>) -> u8 {
-        address + card.unwrap_or(1) - 1
+    ///
+    /// cards count from 1, `None` is the first card; card 0 and cards beyond address 255
+    /// are rejected
+    fn addressed(address: u8, card: Option<u8>) -> io::Result<u8> {
+        let card = card.unwrap_or(1);
+        if card == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Card 0 doesn't exist, cards count from 1",
+            ));
+        }
+        address.checked_add(card - 1).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Card {} is beyond the last address starting at {}",
+                    card, address
+                ),
+            )
+        })
     }
 }
 
+/// the frame `cmd` sends to `card` of a chain addressed from 1 on, without any port
+///
+/// for docs or to compare against a logic analyzer capture; init and get port ignore
+/// `relays`
+pub fn build_frame(
+    cmd: Relay8xCmdSet,
+    card: u8,
+    relays: &RelayIndex,
+) -> Result<[u8; 4], Relay8xError> {
+    if let Some(&relay) = relays.iter().find(|&&r| r == 0 || r > 8) {
+        return Err(Relay8xError::RelayOutOfRange {
+            relay,
+            card,
+            max: 8,
+        });
+    }
+    let mut bytes = BytesMut::with_capacity(4);
+    match cmd {
+        // the init frame carries the address of the first card it reaches
+        Relay8xCmdSet::Init => {
+            let address = Relay8xCmdSet::addressed(1, Some(card))?;
+            cmd.encode(&mut bytes, address, None, None)?
+        }
+        _ => cmd.encode(&mut bytes, 1, Some(card), Some(relays))?,
+    }
+    Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
 impl Relay8x {
     /// constructor for a new Relay Card
     pub fn new(device_name: &str, address: u8) -> Result<Self, io::Error> {
-        let port = ::serial::open(device_name)?;
-        Ok(Self {
-            port: Box::new(port),
-            start_address: address,
-        })
+        Self::with_config(
+            device_name,
+            Relay8xConfig {
+                address,
+                ..Default::default()
+            },
+        )
     }
 
-    /// initialise device with correct params
+    /// constructor for a new Relay Card with custom settings
     ///
-    /// sets device address, function can be used to re-set it
-    pub fn configure_device(&mut self) -> io::Result<BytesMut> {
-        let port = &mut self.port;
-        // configure interface with its params, see doc of relay card
-        port.reconfigure(&|settings| {
-            settings.set_baud_rate(::serial::Baud19200)?;
-            settings.set_char_size(::serial::Bits8);
-            settings.set_parity(::serial::ParityNone);
-            settings.set_stop_bits(::serial::Stop1);
-            settings.set_flow_control(::serial::FlowNone);
-            Ok(())
-        })?;
+    /// a device name like `tcp://192.168.1.50:5000` connects to a serial-to-Ethernet
+    /// bridge instead of opening a serial port
+    pub fn with_config(device_name: &str, config: Relay8xConfig) -> Result<Self, io::Error> {
+        let mut relay = match device_name.strip_prefix(TCP_SCHEME) {
+            Some(address) => {
+                Self::from_transport_with_config(TcpTransport::connect(address)?, config)
+            }
+            None => {
+                let port = ::serial::open(device_name)?;
+                Self::from_transport_with_config(SerialTransport::new(port), config)
+            }
+        };
+        relay.device = Some(device_name.to_string());
+        Ok(relay)
+    }
+
+    /// constructor for a Relay Card on a serial port opened by the caller, e.g. with
+    /// custom settings or a port type other than the one `new` opens
+    ///
+    /// `configure_port` still applies the card's settings to it, unless `reconfigure` is off
+    pub fn from_port<P: ::serial::SerialPort + Send + 'static>(port: P, address: u8) -> Self {
+        Self::from_transport(SerialTransport::new(port), address)
+    }
+
+    /// constructor for a Relay Card attached to an arbitrary transport
+    pub fn from_transport<T: RelayTransport + Send + 'static>(transport: T, address: u8) -> Self {
+        Self::from_transport_with_config(
+            transport,
+            Relay8xConfig {
+                address,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// constructor for a Relay Card attached to any byte stream, see `IoTransport`
+    pub fn from_io<T: Read + Write + Send + 'static>(io: T, address: u8) -> Self {
+        Self::from_transport(IoTransport::new(io), address)
+    }
+
+    /// constructor for a Relay Card attached to an arbitrary transport with custom settings
+    pub fn from_transport_with_config<T: RelayTransport + Send + 'static>(
+        transport: T,
+        config: Relay8xConfig,
+    ) -> Self {
+        Self {
+            port: Box::new(transport),
+            config,
+            device: None,
+            capture: None,
+            echo_frames: false,
+            cards: Vec::new(),
+            known: BTreeMap::new(),
+            on_change: None,
+            validator: None,
+            last_switched: BTreeMap::new(),
+            drop_behavior: DropBehavior::Leave,
+            retries: 0,
+        }
+    }
+
+    /// record all frames sent and received from now on
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(capture);
+    }
+
+    /// call `f` with card, relay number and new state for every relay switched by
+    /// `set_relays`, `clear_relays`, `toggle_relays` and `pulse_relays`
+    ///
+    /// it runs after the card acknowledged the switch; with a callback, toggling queries
+    /// the new states from the card
+    pub fn set_on_change<F: FnMut(u8, u8, bool) + Send + 'static>(&mut self, f: F) {
+        self.on_change = Some(Box::new(f));
+    }
+
+    /// check responses with `f` instead of the default validation, for clone boards that
+    /// almost but not quite follow the protocol
+    ///
+    /// `f` gets the sent and the received frame; frames of other cards are still discarded
+    /// by address before `f` sees them
+    pub fn set_response_validator<F>(&mut self, f: F)
+    where
+        F: Fn(&[u8], &[u8]) -> Result<(), Relay8xError> + Send + 'static,
+    {
+        self.validator = Some(Box::new(f));
+    }
+
+    /// switch every relay off when this `Relay8x` is dropped, or leave them as they are,
+    /// which is the default
+    ///
+    /// a fail-safe for valves and the like if the program ends while they are on; it only
+    /// runs on a clean drop, e.g. returning from `main` or unwinding from a panic, not when
+    /// the process is killed (`SIGKILL`), aborts or calls `std::process::exit`; a failure
+    /// to switch off is logged, `Drop` can't return it
+    pub fn set_drop_behavior(&mut self, behavior: DropBehavior) {
+        self.drop_behavior = behavior;
+    }
+
+    /// resend a frame up to `count` times if its answer has a bad checksum or comes from
+    /// another card, none by default
+    ///
+    /// each retry is logged as a warning; errors a resend can't fix, like a relay the card
+    /// doesn't have, fail at once, and toggles are never resent: the card may have run the
+    /// first one, a second would switch the relays back
+    pub fn set_retries(&mut self, count: u8) {
+        self.retries = count;
+    }
+
+    /// print every frame sent and received to stderr, independent of the log level
+    pub fn set_echo_frames(&mut self, echo: bool) {
+        self.echo_frames = echo;
+    }
+
+    /// apply baud rate, timeout and the other interface params to the port
+    ///
+    /// without `reconfigure` only the timeout is set, the port keeps its settings; baud
+    /// rates other than `BAUD_RATES` and a zero timeout are rejected
+    pub fn configure_port(&mut self) -> Result<(), Relay8xError> {
+        check_timeout(self.config.timeout)?;
+        if self.config.reconfigure {
+            check_baud(self.config.baud)?;
+            self.port.configure(self.config.baud)?;
+        }
+        Ok(self.port.set_timeout(self.config.timeout)?)
+    }
 
-        port.set_timeout(Duration::from_millis(1000))?;
+    /// initialise device with correct params, returns the number of cards in the chain
+    ///
+    /// answers are awaited for `init_timeout` instead of `timeout`
+    ///
+    /// sets device address, function can be used to re-set it; fails if more than
+    /// `max_cards` cards answer
+    ///
+    /// fails with `NotFound` if the first answer is nothing, no valid acknowledgement or
+    /// the init frame echoed back, the device is then most likely no relay card
+    ///
+    /// with `skip_init_ack` the init frame is sent and, after a fixed delay, assumed to
+    /// have succeeded, so a missing or miswired card only shows on the next command; no
+    /// card is counted then and the result is 0
+    pub fn configure_device(&mut self) -> Result<u8, Relay8xError> {
+        self.configure_port()?;
+        // every card of the chain answers the init, on long chains that takes longer
+        // than the answer to a switch
+        self.port.set_timeout(self.config.init_timeout)?;
+        let result = self.init_chain();
+        self.port.set_timeout(self.config.timeout)?;
+        Ok(result?)
+    }
 
+    /// sends the init frame and records the cards answering it, returns their number
+    fn init_chain(&mut self) -> io::Result<u8> {
         // init relay card
         let mut cmd = BytesMut::with_capacity(4);
         Relay8xCmdSet::encode(
-            Relay8xCmdSet::Init,
+            &Relay8xCmdSet::Init,
             &mut cmd,
-            self.start_address,
+            self.config.address,
             None,
             None,
         )?;
 
-        port.write(&cmd[..])?;
+        self.write_frame(&cmd[..])?;
         debug!("Wrote init message..");
-        let mut resp = BytesMut::new();
-        resp.put_u32_le(0);
+        self.cards.clear();
+        self.forget_states();
+        if self.config.skip_init_ack {
+            thread::sleep(INIT_ACK_DELAY);
+            debug!("Skipped init acknowledgement");
+            return Ok(0);
+        }
         let now = Instant::now();
-        // read until last card has responded
+        // every card responds, the last one passes the init frame back
         loop {
-            port.read(&mut resp[..])?;
-            debug!(
-                "Response init: {:02x} {:02x} {:02x} {:02x}",
-                &resp[0], &resp[1], &resp[2], &resp[3]
-            );
-            if *resp.first().unwrap() == self.start_address {
+            // without a valid acknowledgement so far the device is most likely no relay
+            // card at all, e.g. a GPS receiver on the wrong tty
+            let resp = match self.read_frame() {
+                Ok(resp) => resp,
+                Err(e) if self.cards.is_empty() => return Err(self.no_card_detected(&e)),
+                Err(e) => return Err(e),
+            };
+            debug!("Response init: {}", Frame(&resp));
+            if self.cards.is_empty() {
+                match CommandFrame::try_from(resp) {
+                    Ok(frame) if frame.cmd == !cmd[0] || frame.cmd == cmd[0] => {}
+                    Ok(_) => return Err(self.no_card_detected(&"unexpected command")),
+                    Err(e) => return Err(self.no_card_detected(&e)),
+                }
+            }
+            if resp[0] == !cmd[0] {
+                // the cards count up from the first address, each acknowledging with its own
+                let address = self.config.address.wrapping_add(self.cards.len() as u8);
+                let expected = BytesMut::from(&[cmd[0], address, 0, 0][..]);
+                Relay8x::check_response(&BytesMut::from(&resp[..]), &expected)?;
+                if self.cards.len() >= self.config.max_cards as usize {
+                    return Err(Error::other(format!(
+                        "More than {} cards answered the initialisation, raise the maximum",
+                        self.config.max_cards
+                    )));
+                }
+                self.cards.push(CardInfo {
+                    address: resp[1],
+                    firmware: resp[2],
+                    relays: self.relay_count(self.cards.len() as u8 + 1),
+                });
+            } else if resp[0] == cmd[0] {
                 break;
-            } else if now.elapsed().as_secs() > 30 {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "Initialisation took to long..",
-                ));
             }
+            if now.elapsed().as_secs() > 30 {
+                return Err(Error::other("Initialisation took to long.."));
+            }
+        }
+        // the init frame came back without any card acknowledging it, e.g. from a loopback
+        // cable or a device echoing everything
+        if self.cards.is_empty() {
+            return Err(self.no_card_detected(&"init frame echoed without acknowledgement"));
         }
 
-        Ok(cmd)
+        Ok(self.cards.len() as u8)
     }
 
-    /// switch arbitrary relays on
+    /// probes the addresses 1..=`max_cards` and makes the first one answering the first
+    /// card
     ///
-    /// - numbers: Vector containing all relay numbers (1..8)
-    /// - state: true for switching on, false for off
-    pub fn set_relays(&mut self, cards: CardIndex, numbers: RelayIndex) -> io::Result<BytesMut> {
-        let port = &mut self.port;
-        let start_address = self.start_address;
-        // with capacity makes it only working for current relay card, but it ensures the
-        // right length
-        let mut cmd = BytesMut::with_capacity(4);
+    /// frames to addresses without a card travel through the chain unchanged, so each
+    /// probe is answered right away; a probe without any answer means there is no chain
+    /// at all and stops the scan
+    pub fn auto_address(&mut self) -> Result<u8, Relay8xError> {
+        for address in 1..=self.config.max_cards {
+            match self.command(Relay8xCmdSet::GetPort, address, None) {
+                Ok(_) => {
+                    info!("Found card at address {}", address);
+                    self.config.address = address;
+                    return Ok(address);
+                }
+                Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => debug!("No card at address {}: {}", address, e),
+            }
+        }
+        Err(Error::new(ErrorKind::NotFound, "No card responded to the scan").into())
+    }
 
-        for &card in cards.iter() {
-            Relay8xCmdSet::encode(
-                Relay8xCmdSet::Set,
-                &mut cmd,
-                start_address,
-                Some(card),
-                Some(&numbers),
-            )?;
-            port.write(&cmd[..])?;
-            let sent_cmd = cmd.clone();
-            port.read(&mut cmd[..])?;
-            debug!(
-                "Set Relays response: {:02x} {:02x} {:02x} {:02x}",
-                &cmd[0], &cmd[1], &cmd[2], &cmd[3]
-            );
-            Relay8x::check_response(&cmd, &sent_cmd)?;
-            cmd.clear();
+    /// whether `card` answers a query of its relay states with a valid frame
+    ///
+    /// unlike the initialisation, nothing is sent which changes addresses or relays;
+    /// a timeout or a garbled answer is `false`, only failures of the port are errors
+    pub fn ping(&mut self, card: u8) -> Result<bool, Relay8xError> {
+        let address = self.card_address(card)?;
+        match self.command(Relay8xCmdSet::GetPort, address, None) {
+            Ok(_) => Ok(true),
+            Err(ref e)
+                if e.kind() == ErrorKind::TimedOut
+                    || e.kind() == ErrorKind::InvalidData
+                    || e.kind() == ErrorKind::Other =>
+            {
+                debug!("Card {} failed the ping: {}", card, e);
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
         }
-        Ok(cmd)
     }
 
-    /// switch arbitrary relays off
+    /// cards which responded to the last initialisation, in chain order
+    pub fn cards(&self) -> &[CardInfo] {
+        &self.cards
+    }
+
+    /// checks that `card` has an address and, if the chain was initialised, that a card
+    /// answered the initialisation at that address
+    pub fn check_card(&self, card: u8) -> Result<(), Relay8xError> {
+        let address = self.card_address(card)?;
+        if !self.cards.is_empty() && !self.cards.iter().any(|c| c.address == address) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Card {} at address 0x{:02x} didn't answer the initialisation",
+                    card, address
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// error for an initialisation without any valid acknowledgement
+    fn no_card_detected(&self, cause: &dyn fmt::Display) -> Error {
+        debug!("No valid init acknowledgement: {}", cause);
+        Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "No relay card detected on {}, check the device path",
+                self.device.as_deref().unwrap_or("the port")
+            ),
+        )
+    }
+
+    /// switch arbitrary relays on
+    ///
+    /// relays interlocked with one of `numbers` are handled according to the
+    /// interlock policy before anything is switched, momentary relays are pulsed
+    /// instead of latched
+    ///
+    /// all relays of a card switch at once with a single frame, the card gives no order
+    /// among them; with `ordered` each relay gets a frame of its own, in ascending order
+    ///
+    /// a failing card doesn't stop the others from being switched, if some cards fail
+    /// the error is `Relay8xError::Partial` listing the switched and the failed cards;
+    /// momentary relays are only pulsed once the latching relays of all cards are on
+    ///
+    /// with `pipeline` the frames for all cards are written at once, see `pipelined`
     ///
     /// - numbers: Vector containing all relay numbers (1..8)
-    /// - state: true for switching on, false for off
-    pub fn reset_relays(&mut self, cards: CardIndex, numbers: RelayIndex) -> io::Result<BytesMut> {
-        let port = &mut self.port;
-        let start_address = self.start_address;
-        // with capacity makes it only working for current relay card, but it ensures the
-        // right length
-        let mut cmd = BytesMut::with_capacity(4);
+    pub fn set_relays(
+        &mut self,
+        cards: CardIndex,
+        numbers: RelayIndex,
+    ) -> Result<BytesMut, Relay8xError> {
+        self.check_relays(&cards, &numbers)?;
+        if self.can_pipeline(&cards, &numbers) {
+            let cmd_set = self.on_command();
+            return self.pipelined(cmd_set, &cards, &numbers, Some(0xff));
+        }
+        let (momentary, latching): (RelayIndex, RelayIndex) = numbers
+            .iter()
+            .partition(|r| self.config.momentary.contains_key(r));
+        let mut resp = BytesMut::with_capacity(4);
 
-        for &card in cards.iter() {
-            Relay8xCmdSet::encode(
-                Relay8xCmdSet::Reset,
-                &mut cmd,
-                start_address,
-                Some(card),
-                Some(&numbers),
-            )?;
-            port.write(&cmd[..])?;
-            let sent_cmd = cmd.clone();
-            port.read(&mut cmd[..])?;
-            debug!(
-                "Reset Relays response: {:02x} {:02x} {:02x} {:02x}",
-                &cmd[0], &cmd[1], &cmd[2], &cmd[3]
-            );
-            Relay8x::check_response(&cmd, &sent_cmd)?;
-            cmd.clear();
+        if !latching.is_empty() || momentary.is_empty() {
+            let mut done = Vec::new();
+            let mut failed = Vec::new();
+            for &card in cards.iter() {
+                let result = self.on_card(card, |relay, address| {
+                    let mask = Relay8xCmdSet::relay_as_u8(&latching);
+                    let cleared = relay.enforce_interlocks(address, mask, false)?;
+                    relay.notify(card, cleared, 0);
+                    let cmd_set = relay.on_command();
+                    let mut resp = BytesMut::new();
+                    for relays in relay.frames(&latching) {
+                        let r = relay.switch(cmd_set.clone(), address, &relays)?;
+                        if !r.is_empty() {
+                            relay.notify(card, Relay8xCmdSet::relay_as_u8(&relays), 0xff);
+                            resp = r;
+                        }
+                    }
+                    Ok(resp)
+                });
+                match result {
+                    Ok(r) => {
+                        resp = r;
+                        done.push(card);
+                    }
+                    Err(e) => failed.push((card, e)),
+                }
+            }
+            if done.is_empty() && failed.len() == 1 {
+                return Err(failed.remove(0).1);
+            }
+            if !failed.is_empty() {
+                return Err(Relay8xError::Partial { done, failed });
+            }
         }
-        Ok(cmd)
+        // one pulse for all momentary relays sharing a duration
+        let mut durations: Vec<Duration> =
+            momentary.iter().map(|r| self.config.momentary[r]).collect();
+        durations.sort();
+        durations.dedup();
+        for duration in durations {
+            let relays = momentary
+                .iter()
+                .cloned()
+                .filter(|r| self.config.momentary[r] == duration)
+                .collect();
+            resp = self.pulse_relays(cards.clone(), relays, duration)?;
+        }
+        Ok(resp)
     }
 
-    /// toggle aribtrary relays
+    /// switch all relays off, then the given ones on
     ///
-    /// numbers: vector containing all relay numbers (1..8)
-    pub fn toggle_relays(&mut self, cards: CardIndex, numbers: RelayIndex) -> io::Result<BytesMut> {
-        let port = &mut self.port;
-        let start_address = self.start_address;
-        // with capacity makes it only working for current relay card, but it ensures the
-        // right length
-        let mut cmd = BytesMut::with_capacity(4);
-
+    /// for starting from a known state: all relays of the cards are briefly off in
+    /// between, even those which are on before and after
+    ///
+    /// - numbers: Vector containing all relay numbers (1..8)
+    pub fn clear_and_set_relays(
+        &mut self,
+        cards: CardIndex,
+        numbers: RelayIndex,
+    ) -> Result<BytesMut, Relay8xError> {
+        self.check_relays(&cards, &numbers)?;
         for &card in cards.iter() {
-            Relay8xCmdSet::encode(
-                Relay8xCmdSet::Toggle,
-                &mut cmd,
-                start_address,
-                Some(card),
-                Some(&numbers),
-            )?;
-            port.write(&cmd[..])?;
-            let sent_cmd = cmd.clone();
-            port.read(&mut cmd[..])?;
-            debug!(
-                "Toggle Relays response: {:02x} {:02x} {:02x} {:02x}",
-                &cmd[0], &cmd[1], &cmd[2], &cmd[3]
-            );
-            Relay8x::check_response(&cmd, &sent_cmd)?;
-            cmd.clear();
+            let all = self.all_relays(card);
+            self.clear_relays(vec![card], all)?;
         }
-        Ok(cmd)
+        self.set_relays(cards, numbers)
     }
 
-    /// simple response checker of recieved frame
-    fn check_response(msg: &BytesMut, sent_msg: &BytesMut) -> io::Result<()> {
-        // check first byte
-        let checker_byte = sent_msg.get(0).unwrap_or(&1);
-        let checked_bytes = msg.get(0).unwrap_or(&1);
-        if *checked_bytes != !checker_byte {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!(
-                    "Bad first byte: is {}, should be {}",
-                    checked_bytes, !checker_byte
-                ),
-            ));
+    /// switch the relays in `affect` to the states in `value`, all others stay as they are
+    ///
+    /// takes at most two frames per card: a reset for the relays going off, then a set
+    /// for those going on; bits of `value` outside `affect` are ignored
+    pub fn apply_relays(
+        &mut self,
+        cards: CardIndex,
+        affect: u8,
+        value: u8,
+    ) -> Result<BytesMut, Relay8xError> {
+        let off = u8_as_relays(affect & !value);
+        let on = u8_as_relays(affect & value);
+        let mut resp = BytesMut::new();
+        if !off.is_empty() {
+            resp = self.reset_relays(cards.clone(), off)?;
         }
-        // second byte: adress
-        let resp_addr = msg.get(1).unwrap_or(&0);
-        let sent_addr = sent_msg.get(1).unwrap_or(&1);
-        // weird error, it prints the same addresses but says there are not equal...
-        if resp_addr != sent_addr && *sent_addr != 0u8 {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!(
-                    "Wrong Adress: 0x{:02x} instead of 0x{:02x}",
-                    resp_addr, sent_addr
-                ),
-            ));
+        if !on.is_empty() {
+            resp = self.set_relays(cards, on)?;
         }
-        // last byte: XOR
-        if *msg.get(3).unwrap_or(&0)
-            != (*msg.get(0).unwrap_or(&1) ^ *msg.get(1).unwrap_or(&0) ^ *msg.get(2).unwrap_or(&0))
-        {
-            return Err(Error::new(ErrorKind::Other, "XOR in last byte is wrong"));
+        Ok(resp)
+    }
+
+    /// read-modify-write of `card`: switches its relays to `modify` of their current
+    /// states, returns the new states
+    ///
+    /// only the relays which change are switched; the cycle is atomic against other
+    /// processes as long as all of them hold a `DeviceLock` on the device around it, like
+    /// the command line tool does, without a lock it is best effort
+    pub fn modify_relays<F: FnOnce(u8) -> u8>(
+        &mut self,
+        card: u8,
+        modify: F,
+    ) -> Result<u8, Relay8xError> {
+        let current = self.get_relays(vec![card])?[0];
+        let wanted = modify(current) & Relay8xCmdSet::relay_as_u8(&self.all_relays(card));
+        if wanted != current {
+            self.apply_relays(vec![card], wanted ^ current, wanted)?;
         }
-        debug!("Check ok");
-        Ok(())
+        Ok(wanted)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// one pass of mirroring `source` onto `target`: switches the relays of `target`
+    /// which differ from `source`, returns the mirrored state if anything was switched
+    ///
+    /// both cards are read on every pass, so a target changed by hand is synced again
+    pub fn mirror_card(&mut self, source: u8, target: u8) -> Result<Option<u8>, Relay8xError> {
+        let states = self.get_relays(vec![source, target])?;
+        let (wanted, current) = (states[0], states[1]);
+        if wanted == current {
+            return Ok(None);
+        }
+        self.apply_relays(vec![target], wanted ^ current, wanted)?;
+        Ok(Some(wanted))
+    }
 
-    #[test]
-    fn connect_to_card() {
-        let device = if cfg!(unix) {
-            "/dev/ttyUSB".to_string()
-        } else if cfg!(windows) {
-            "COM".to_string()
-        } else {
-            panic!("Could not determine platform");
-        };
+    /// switch arbitrary relays on and, after `duration`, off again
+    ///
+    /// all cards are switched on before the delay, so their pulses overlap; if a card
+    /// fails to switch on, the cards switched so far are switched off again at once, the
+    /// failing one included, as its frame may have arrived without an answer
+    ///
+    /// - numbers: Vector containing all relay numbers (1..8)
+    pub fn pulse_relays(
+        &mut self,
+        cards: CardIndex,
+        numbers: RelayIndex,
+        duration: Duration,
+    ) -> Result<BytesMut, Relay8xError> {
+        self.check_relays(&cards, &numbers)?;
+        check_pulse(duration)?;
+        for &card in cards.iter() {
+            self.card_address(card)?;
+        }
 
-        let mut i = 0;
-        let mut dev = format!("{}{}", &device, &i);
-        while let Err(_) = Relay8x::new(&dev, 1) {
-            i = i + 1;
-            dev = format!("{}{}", &device, &i);
-            if i > 256 {
-                // number of COM ports on win
-                panic!("Failed to init device: Found none");
+        let mut switched = Vec::with_capacity(cards.len());
+        let mut on = Ok(());
+        for &card in cards.iter() {
+            switched.push(card);
+            on = self.on_card(card, |relay, address| {
+                let mask = Relay8xCmdSet::relay_as_u8(&numbers);
+                let cleared = relay.enforce_interlocks(address, mask, false)?;
+                relay.notify(card, cleared, 0);
+                let cmd_set = relay.on_command();
+                relay.command(cmd_set, address, Some(&numbers))?;
+                relay.notify(card, mask, 0xff);
+                Ok(())
+            });
+            if on.is_err() {
+                break;
             }
         }
-
-        println!("Detected device at {}", &dev);
-        let mut relay = Relay8x::new(&dev, 1).expect("Failed to init device");
-        let _init_cmd = relay.configure_device().expect("Failed to init device");
+        if on.is_ok() {
+            thread::sleep(duration);
+        }
+        // every card gets its off frame, even if another one fails
+        let mut resp = BytesMut::with_capacity(4);
+        let mut off = Ok(());
+        for card in switched {
+            let result = self.on_card(card, |relay, address| {
+                let cmd_set = relay.off_command();
+                let resp = relay.command(cmd_set, address, Some(&numbers))?;
+                relay.notify(card, Relay8xCmdSet::relay_as_u8(&numbers), 0);
+                Ok(resp)
+            });
+            match result {
+                Ok(r) => resp = r,
+                Err(e) => off = off.and(Err(e)),
+            }
+        }
+        on.and(off).map(|_| resp)
     }
 
+    /// switch relays of every card in the chain on (or off) with a single broadcast frame
+    ///
+    /// much faster than addressing each card for uniform operations, but unchecked: cards
+    /// answer a broadcast one after another, depending on their options, and the last
+    /// one passes the frame itself back; these frames are read until the broadcast is
+    /// back or the timeout elapses but not checked, so a missing card goes unnoticed
+    ///
+    /// the known relay states are forgotten; interlocked and momentary relays need to
+    /// know the state of each card and are refused
+    ///
+    /// - numbers: Vector containing all relay numbers (1..8)
+    pub fn broadcast_relays(&mut self, numbers: RelayIndex, on: bool) -> Result<(), Relay8xError> {
+        // the relays have to exist on the largest card, the others ignore the rest
+        let max = self.max_relays();
+        if let Some(x) = numbers.iter().find(|&&x| x == 0 || x > max) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Relay number {} out of range (1..{})", x, max),
+            )
+            .into());
+        }
+        let mask = Relay8xCmdSet::relay_as_u8(&numbers);
+        let interlocked = self.config.interlocks.iter().any(|i| i.mask() & mask != 0);
+        let momentary = numbers
+            .iter()
+            .any(|r| self.config.momentary.contains_key(r));
+        if on && (interlocked || momentary) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Interlocked or momentary relays can't be switched on by a broadcast",
+            )
+            .into());
+        }
+        let cmd_set = if on {
+            self.on_command()
+        } else {
+            self.off_command()
+        };
+        let mut cmd = BytesMut::with_capacity(4);
+        Relay8xCmdSet::encode(&cmd_set, &mut cmd, BROADCAST_ADDRESS, None, Some(&numbers))?;
+        self.forget_states();
+        self.broadcast(&cmd[..])?;
+        Ok(())
+    }
+
+    /// addresses of the cards in the chain as reported by the cards themselves
+    ///
+    /// the address a card answers with may differ from the one the software expects,
+    /// e.g. after a power cycle; a chain with broadcasts disabled doesn't answer, then
+    /// only a scan with `auto_address` finds the cards
+    pub fn query_addresses(&mut self) -> Result<Vec<u8>, Relay8xError> {
+        let mut cmd = BytesMut::with_capacity(4);
+        Relay8xCmdSet::encode(
+            &Relay8xCmdSet::GetPort,
+            &mut cmd,
+            BROADCAST_ADDRESS,
+            None,
+            None,
+        )?;
+        let addresses: Vec<u8> = self
+            .broadcast(&cmd[..])?
+            .iter()
+            .filter(|f| f[0] == !cmd[0] && Relay8xCmdSet::checksummed(&f[..3]) == f[3])
+            .map(|frame| frame[1])
+            .collect();
+        if addresses.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "No card answered the broadcast, scan for the cards instead",
+            )
+            .into());
+        }
+        Ok(addresses)
+    }
+
+    /// writes a broadcast frame and returns the answers of the cards
+    ///
+    /// they are read until the frame itself is back or the timeout elapses, so they
+    /// aren't taken for responses to the next frames
+    fn broadcast(&mut self, cmd: &[u8]) -> io::Result<Vec<[u8; 4]>> {
+        self.write_frame(cmd)?;
+        let mut answers = Vec::new();
+        let now = Instant::now();
+        while now.elapsed() <= self.config.timeout {
+            match self.read_frame() {
+                Ok(frame) if frame[..] == cmd[..] => break,
+                Ok(frame) => {
+                    debug!("Broadcast answered: {}", Frame(&frame));
+                    answers.push(frame);
+                }
+                Err(_) => break,
+            }
+        }
+        Ok(answers)
+    }
+
+    /// switch the relays in `mask` on and all other relays of the cards off, with a single
+    /// frame per card
+    ///
+    /// the only way to set the whole state of a card at once, no relay passes through
+    /// another state in between; bit 0 of the mask is relay 1 like for `get_relays`.
+    /// momentary relays can't be latched on and the mask may switch on just one relay of
+    /// every interlock, the rate limit of `min_interval` doesn't apply
+    pub fn write_port(&mut self, cards: CardIndex, mask: u8) -> Result<BytesMut, Relay8xError> {
+        let numbers = u8_as_relays(mask);
+        self.check_relays(&cards, &numbers)?;
+        if let Some(relay) = numbers
+            .iter()
+            .find(|r| self.config.momentary.contains_key(r))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Relay {} is momentary, it can only be pulsed", relay),
+            )
+            .into());
+        }
+        for lock in self.config.interlocks.iter().map(Interlock::mask) {
+            if (lock & mask).count_ones() > 1 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Relays {:?} are interlocked, only one of them may be on",
+                        u8_as_relays(lock)
+                    ),
+                )
+                .into());
+            }
+        }
+        let mut resp = BytesMut::new();
+        for &card in cards.iter() {
+            let all = Relay8xCmdSet::relay_as_u8(&self.all_relays(card));
+            resp = self.on_card(card, |relay, address| {
+                // coil states, the inverse of reading them back
+                let port = relay.logical_state(mask) & all;
+                relay.command(Relay8xCmdSet::SetPort, address, Some(&u8_as_relays(port)))
+            })?;
+            self.notify(card, all, mask);
+        }
+        Ok(resp)
+    }
+
+    /// switch arbitrary relays off
+    ///
+    /// same as `clear_relays`
+    pub fn reset_relays(
+        &mut self,
+        cards: CardIndex,
+        numbers: RelayIndex,
+    ) -> Result<BytesMut, Relay8xError> {
+        self.clear_relays(cards, numbers)
+    }
+
+    /// switch arbitrary relays off, leaving all others as they are
+    ///
+    /// the reset command (7) only clears the relays whose bits are set in its data byte,
+    /// so this is the counterpart of `set_relays`
+    ///
+    /// - numbers: Vector containing all relay numbers (1..8)
+    pub fn clear_relays(
+        &mut self,
+        cards: CardIndex,
+        numbers: RelayIndex,
+    ) -> Result<BytesMut, Relay8xError> {
+        self.check_relays(&cards, &numbers)?;
+        if self.can_pipeline(&cards, &numbers) {
+            let cmd_set = self.off_command();
+            return self.pipelined(cmd_set, &cards, &numbers, Some(0));
+        }
+        let mut resp = BytesMut::with_capacity(4);
+
+        for &card in cards.iter() {
+            resp = self.on_card(card, |relay, address| {
+                let cmd_set = relay.off_command();
+                let resp = relay.switch(cmd_set, address, &numbers)?;
+                if !resp.is_empty() {
+                    relay.notify(card, Relay8xCmdSet::relay_as_u8(&numbers), 0);
+                }
+                Ok(resp)
+            })?;
+        }
+        Ok(resp)
+    }
+
+    /// switch every relay of every card found by the initialisation off
+    ///
+    /// the emergency stop: each card gets a frame clearing all of its relays, regardless
+    /// of `skip_redundant`, and has to acknowledge it; a failing or slow card doesn't keep
+    /// the others from being switched off and gets a second frame after them, if that
+    /// fails too the error is `Relay8xError::Partial`
+    ///
+    /// without a card list, e.g. with `skip_init_ack`, the relays are switched off by a
+    /// broadcast, which no card acknowledges
+    pub fn reset_all(&mut self) -> Result<(), Relay8xError> {
+        if self.cards.is_empty() {
+            let max = self.max_relays();
+            return self.broadcast_relays((1..=max).collect(), false);
+        }
+        let mut pending: Vec<(u8, u8, RelayIndex)> = self
+            .cards
+            .iter()
+            .enumerate()
+            .map(|(i, info)| (i as u8 + 1, info.address, (1..=info.relays).collect()))
+            .collect();
+        let mut done = Vec::new();
+        let mut failed = Vec::new();
+        // slow cards get their second frame once the others are off
+        for _ in 0..2 {
+            failed.clear();
+            let mut retry = Vec::new();
+            for (card, address, all) in pending {
+                let cmd_set = self.off_command();
+                match self.command(cmd_set, address, Some(&all)) {
+                    Ok(_) => {
+                        self.notify(card, Relay8xCmdSet::relay_as_u8(&all), 0);
+                        done.push(card);
+                    }
+                    Err(e) => {
+                        warn!("Card {} didn't switch off: {}", card, e);
+                        failed.push((card, Relay8xError::from(e)));
+                        retry.push((card, address, all));
+                    }
+                }
+            }
+            pending = retry;
+            if pending.is_empty() {
+                return Ok(());
+            }
+        }
+        done.sort_unstable();
+        Err(Relay8xError::Partial { done, failed })
+    }
+
+    /// toggle aribtrary relays
+    ///
+    /// relays toggled on are subject to the interlocks just like in `set_relays`
+    ///
+    /// numbers: vector containing all relay numbers (1..8)
+    pub fn toggle_relays(
+        &mut self,
+        cards: CardIndex,
+        numbers: RelayIndex,
+    ) -> Result<BytesMut, Relay8xError> {
+        self.check_relays(&cards, &numbers)?;
+        // the new states would have to be queried card by card for the change callback
+        if self.on_change.is_none() && self.can_pipeline(&cards, &numbers) {
+            return self.pipelined(Relay8xCmdSet::Toggle, &cards, &numbers, None);
+        }
+        let mut resp = BytesMut::with_capacity(4);
+
+        for &card in cards.iter() {
+            resp = self.on_card(card, |relay, address| {
+                let mask = Relay8xCmdSet::relay_as_u8(&numbers);
+                let cleared = relay.enforce_interlocks(address, mask, true)?;
+                relay.notify(card, cleared, 0);
+                let resp = relay.limited_command(Relay8xCmdSet::Toggle, address, &numbers)?;
+                if relay.on_change.is_some() {
+                    // the new states are only known from the card
+                    let state = relay.query(address)?;
+                    relay.notify(card, mask, state);
+                }
+                Ok(resp)
+            })?;
+        }
+        Ok(resp)
+    }
+
+    /// query the relay states of the cards
+    ///
+    /// returns one bitmask per card, bit 0 corresponds to relay 1
+    pub fn get_relays(&mut self, cards: CardIndex) -> Result<Vec<u8>, Relay8xError> {
+        let mut states = Vec::with_capacity(cards.len());
+
+        for &card in cards.iter() {
+            states.push(self.on_card(card, |relay, address| relay.query(address))?);
+        }
+        Ok(states)
+    }
+
+    /// queries the relay states of all `cards` in one sweep, every query is sent before
+    /// the first answer is read
+    ///
+    /// the answers may arrive in any order and are matched by address; returns address
+    /// and relay states of every card in the order of `cards`, the states are `None` if
+    /// the card didn't answer validly within the timeout
+    pub fn sweep(&mut self, cards: CardIndex) -> Result<Vec<(u8, Option<u8>)>, Relay8xError> {
+        let addresses = cards
+            .iter()
+            .map(|&card| self.card_address(card))
+            .collect::<io::Result<Vec<u8>>>()?;
+        self.drain_stale()?;
+        let mut sent = BTreeMap::new();
+        for &address in &addresses {
+            let mut frame = BytesMut::with_capacity(4);
+            Relay8xCmdSet::GetPort.encode(&mut frame, address, None, None)?;
+            self.put_frame(&frame)?;
+            sent.insert(address, frame);
+        }
+
+        let mut answers = BTreeMap::new();
+        let deadline = Instant::now() + self.config.timeout;
+        while answers.len() < sent.len() && Instant::now() < deadline {
+            let resp = match self.read_frame() {
+                Ok(resp) => BytesMut::from(&resp[..]),
+                Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => return Err(e.into()),
+            };
+            let valid = match sent.get(&resp[1]) {
+                Some(query) => match self.validator {
+                    Some(ref validator) => validator(query, &resp),
+                    None => Relay8x::check_response(&resp, query),
+                },
+                None => Err(Error::other("no query sent to this address").into()),
+            };
+            match valid {
+                Ok(()) => {
+                    let port = self.board_bits(resp[2]);
+                    self.remember(resp[1], 2, 0, port);
+                    answers.insert(resp[1], port);
+                }
+                Err(e) => debug!("Discarding answer {}: {}", Frame(&resp), e),
+            }
+        }
+
+        Ok(addresses
+            .into_iter()
+            .map(|address| match answers.get(&address) {
+                Some(&port) => (address, Some(self.logical_state(port))),
+                None => {
+                    debug!("No answer from 0x{:02x} to the sweep", address);
+                    self.known.remove(&address);
+                    (address, None)
+                }
+            })
+            .collect())
+    }
+
+    /// whether switching `numbers` on `cards` takes a single frame per card, with nothing
+    /// to check or wait for in between, so `pipelined` can write all of them at once
+    fn can_pipeline(&self, cards: &CardIndex, numbers: &RelayIndex) -> bool {
+        if !self.config.pipeline
+            || cards.len() < 2
+            || self.config.ordered
+            || self.config.min_interval > Duration::from_secs(0)
+            || !self.config.interlocks.is_empty()
+            || self.normally_closed_mask() & Relay8xCmdSet::relay_as_u8(numbers) != 0
+            || numbers
+                .iter()
+                .any(|r| self.config.momentary.contains_key(r))
+        {
+            return false;
+        }
+        // answers are told apart by address
+        let mut addresses = Vec::with_capacity(cards.len());
+        for &card in cards {
+            match self.card_address(card) {
+                Ok(address) if !addresses.contains(&address) => addresses.push(address),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// sends `cmd_set` for `numbers` to all `cards` in a single write before the first
+    /// answer is read
+    ///
+    /// the answers may arrive in any order and are matched by address like in `sweep`,
+    /// each is checked like any other response; the change callback is told `state` for
+    /// the switched relays, if given. A card without a valid answer within the timeout
+    /// fails, the error is `Relay8xError::Partial` if other cards switched
+    fn pipelined(
+        &mut self,
+        cmd_set: Relay8xCmdSet,
+        cards: &CardIndex,
+        numbers: &RelayIndex,
+        state: Option<u8>,
+    ) -> Result<BytesMut, Relay8xError> {
+        let data = Relay8xCmdSet::relay_as_u8(numbers);
+        let relays = self.board_relays(numbers);
+        let mut frames = BytesMut::with_capacity(4 * cards.len());
+        let mut sent = BTreeMap::new();
+        let mut done = Vec::new();
+        for &card in cards {
+            let address = self.card_address(card)?;
+            if self.redundant(&cmd_set, address, data) {
+                info!("No change at 0x{:02x}, skipping", address);
+                done.push(card);
+                continue;
+            }
+            let mut frame = BytesMut::with_capacity(4);
+            cmd_set.encode(&mut frame, address, None, Some(&relays))?;
+            frames.extend_from_slice(&frame);
+            sent.insert(address, (card, frame));
+        }
+        let mut resp = BytesMut::new();
+        if sent.is_empty() {
+            return Ok(resp);
+        }
+        self.drain_stale()?;
+        self.put_frame(&frames)?;
+
+        // last reason an answer of a card was discarded
+        let mut invalid = BTreeMap::new();
+        let deadline = Instant::now() + self.config.timeout;
+        while !sent.is_empty() && Instant::now() < deadline {
+            let answer = match self.read_frame() {
+                Ok(answer) => BytesMut::from(&answer[..]),
+                Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => return Err(e.into()),
+            };
+            let valid = match sent.get(&answer[1]) {
+                Some((_, frame)) => match self.validator {
+                    Some(ref validator) => validator(frame, &answer),
+                    None => Relay8x::check_response(&answer, frame),
+                },
+                None => Err(Error::other("no frame sent to this address").into()),
+            };
+            match valid {
+                Ok(()) => {
+                    if let Some((card, frame)) = sent.remove(&answer[1]) {
+                        let (bits, port) = (self.board_bits(frame[2]), self.board_bits(answer[2]));
+                        self.remember(answer[1], frame[0], bits, port);
+                        if let Some(state) = state {
+                            self.notify(card, data, state);
+                        }
+                        done.push(card);
+                        resp = answer;
+                    }
+                }
+                Err(e) => {
+                    debug!("Discarding answer {}: {}", Frame(&answer), e);
+                    // a valid answer may still follow
+                    if sent.contains_key(&answer[1]) {
+                        invalid.insert(answer[1], e);
+                    }
+                }
+            }
+        }
+
+        let mut failed = Vec::new();
+        for (address, (card, _)) in sent {
+            // the frame may or may not have been executed
+            self.known.remove(&address);
+            let e = match invalid.remove(&address) {
+                Some(e) => e,
+                None => Relay8xError::Timeout {
+                    card,
+                    timeout: self.config.timeout,
+                },
+            };
+            failed.push((card, e));
+        }
+        if failed.is_empty() {
+            return Ok(resp);
+        }
+        if done.is_empty() && failed.len() == 1 {
+            return Err(failed.remove(0).1);
+        }
+        done.sort_unstable();
+        failed.sort_by_key(|&(card, _)| card);
+        Err(Relay8xError::Partial { done, failed })
+    }
+
+    /// read the option byte of a card, which the card keeps across power cycles
+    ///
+    /// see the manual of the card for its bits; on the original cards bit 0 set executes
+    /// broadcasts and bit 1 set keeps them from being passed on to the next card
+    pub fn get_option(&mut self, card: u8) -> Result<u8, Relay8xError> {
+        self.on_card(card, |relay, address| {
+            Ok(relay.command(Relay8xCmdSet::GetOption, address, None)?[2])
+        })
+    }
+
+    /// write the option byte of a card, see `get_option`
+    pub fn set_option(&mut self, card: u8, option: u8) -> Result<(), Relay8xError> {
+        self.on_card(card, |relay, address| {
+            relay
+                .command(Relay8xCmdSet::SetOption(option), address, None)
+                .map(|_| ())
+        })
+    }
+
+    /// query whether a single relay of a card is on
+    pub fn is_relay_on(&mut self, card: u8, relay: u8) -> Result<bool, Relay8xError> {
+        self.check_relays(&[card], &vec![relay])?;
+        let state = self.get_relays(vec![card])?[0];
+        Ok(state & 1 << (relay - 1) != 0)
+    }
+
+    /// calls the change callback for the relays in `mask`, which are on if set in `state`
+    fn notify(&mut self, card: u8, mask: u8, state: u8) {
+        if let Some(ref mut on_change) = self.on_change {
+            for (number, _) in iter_relays(mask).filter(|&(_, switched)| switched) {
+                on_change(card, number, state & 1 << (number - 1) != 0);
+            }
+        }
+    }
+
+    /// runs `f` with the address of `card`, telling a card which doesn't respond apart
+    /// from other errors
+    fn on_card<T, F>(&mut self, card: u8, f: F) -> Result<T, Relay8xError>
+    where
+        F: FnOnce(&mut Self, u8) -> io::Result<T>,
+    {
+        let address = self.card_address(card)?;
+        f(self, address).map_err(|e| match e.kind() {
+            ErrorKind::TimedOut => Relay8xError::Timeout {
+                card,
+                timeout: self.config.timeout,
+            },
+            _ => e.into(),
+        })
+    }
+
+    /// switch every relay of a card on and off again on its own, checking the states
+    /// read back after each step
+    ///
+    /// all relays are off during the test, so interlocks can't be violated, and the
+    /// previous states are restored afterwards; returns one result per relay
+    pub fn self_test(&mut self, card: u8) -> Result<Vec<(u8, RelayTest)>, Relay8xError> {
+        let original = self.get_relays(vec![card])?[0];
+        let all = self.all_relays(card);
+        self.clear_relays(vec![card], all.clone())?;
+
+        let mut results = Vec::with_capacity(all.len());
+        for &number in all.iter() {
+            if self.config.momentary.contains_key(&number) {
+                results.push((number, RelayTest::Skipped));
+                continue;
+            }
+            let result = self.on_card(card, |relay, address| {
+                let bit = 1 << (number - 1);
+                let cmd_set = relay.on_command();
+                relay.command(cmd_set, address, Some(&vec![number]))?;
+                let on = relay.query(address)?;
+                let cmd_set = relay.off_command();
+                relay.command(cmd_set, address, Some(&vec![number]))?;
+                let off = relay.query(address)?;
+                Ok(if on != bit {
+                    RelayTest::ReadBackMismatch {
+                        expected: bit,
+                        got: on,
+                    }
+                } else if off != 0 {
+                    RelayTest::ReadBackMismatch {
+                        expected: 0,
+                        got: off,
+                    }
+                } else {
+                    RelayTest::Pass
+                })
+            })?;
+            results.push((number, result));
+        }
+
+        let on = u8_as_relays(original);
+        if !on.is_empty() {
+            self.on_card(card, |relay, address| {
+                let cmd_set = relay.on_command();
+                relay.command(cmd_set, address, Some(&on))
+            })?;
+        }
+        Ok(results)
+    }
+
+    /// how long a relay takes to read back as on after it was switched on
+    ///
+    /// measured from sending the set frame, so the round trip of the frame is included;
+    /// polls the card every few ms until the relay reads back on, `None` if it doesn't
+    /// within `timeout`; a relay which was off is switched off again afterwards
+    pub fn measure_latency(
+        &mut self,
+        card: u8,
+        number: u8,
+        timeout: Duration,
+    ) -> Result<Option<Duration>, Relay8xError> {
+        self.check_relays(&[card], &vec![number])?;
+        if self.config.momentary.contains_key(&number) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Relay {} is momentary, its latency can't be measured",
+                    number
+                ),
+            )
+            .into());
+        }
+        self.on_card(card, |relay, address| {
+            let bit = 1 << (number - 1);
+            let before = relay.query(address)?;
+            let cleared = relay.enforce_interlocks(address, bit, false)?;
+            relay.notify(card, cleared, 0);
+            let start = Instant::now();
+            let cmd_set = relay.on_command();
+            relay.command(cmd_set, address, Some(&vec![number]))?;
+            let latency = loop {
+                if relay.query(address)? & bit != 0 {
+                    break Some(start.elapsed());
+                }
+                if start.elapsed() > timeout {
+                    break None;
+                }
+                thread::sleep(LATENCY_POLL);
+            };
+            if before & bit == 0 {
+                let cmd_set = relay.off_command();
+                relay.command(cmd_set, address, Some(&vec![number]))?;
+            }
+            Ok(latency)
+        })
+    }
+
+    /// strobe the relays of the cards in a double flash pattern to spot them in a rack
+    ///
+    /// runs for `seconds` and restores the previous relay states afterwards, also when
+    /// strobing fails; interlocked and momentary relays are left alone
+    ///
+    /// every card is restored even if one of them fails, those which did are listed in
+    /// `Relay8xError::Partial`
+    pub fn locate(&mut self, cards: CardIndex, seconds: u32) -> Result<(), Relay8xError> {
+        let mut fixed = 0u8;
+        for lock in self.config.interlocks.iter() {
+            fixed |= lock.mask();
+        }
+        for relay in self.config.momentary.keys() {
+            fixed |= 1 << (relay - 1);
+        }
+        let mut states = Vec::with_capacity(cards.len());
+        for &card in cards.iter() {
+            let all = Relay8xCmdSet::relay_as_u8(&self.all_relays(card));
+            let address = self.card_address(card)?;
+            states.push((card, address, all & !fixed));
+        }
+        if states.iter().all(|&(_, _, strobed)| strobed == 0) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "No relays to strobe, all are interlocked or momentary",
+            )
+            .into());
+        }
+        let mut before = Vec::with_capacity(states.len());
+        for &(_, address, _) in states.iter() {
+            before.push(self.query(address)?);
+        }
+
+        let mut result = Ok(());
+        'strobe: for _ in 0..seconds {
+            for &(on, millis) in LOCATE_PATTERN.iter() {
+                let cmd_set = if on {
+                    self.on_command()
+                } else {
+                    self.off_command()
+                };
+                for &(_, address, strobed) in states.iter().filter(|s| s.2 != 0) {
+                    let relays = u8_as_relays(strobed);
+                    if let Err(e) = self.command(cmd_set.clone(), address, Some(&relays)) {
+                        result = Err(e);
+                        break 'strobe;
+                    }
+                }
+                thread::sleep(Duration::from_millis(millis));
+            }
+        }
+
+        let mut done = Vec::new();
+        let mut failed = Vec::new();
+        for ((card, address, strobed), state) in states.into_iter().zip(before) {
+            let on = u8_as_relays(state & strobed);
+            let off = u8_as_relays(!state & strobed);
+            let mut restored = Ok(());
+            for (relays, cmd_set) in [(on, self.on_command()), (off, self.off_command())] {
+                if !relays.is_empty() && restored.is_ok() {
+                    restored = self.command(cmd_set, address, Some(&relays)).map(|_| ());
+                }
+            }
+            match restored {
+                Ok(_) => done.push(card),
+                Err(e) => {
+                    warn!("Card {} wasn't restored: {}", card, e);
+                    failed.push((card, Relay8xError::from(e)));
+                }
+            }
+        }
+        if !failed.is_empty() {
+            if let Err(e) = result {
+                warn!("Strobing failed: {}", e);
+            }
+            return Err(Relay8xError::Partial { done, failed });
+        }
+        Ok(result?)
+    }
+
+    /// query the switch counters of every relay of the cards
+    ///
+    /// none of the known firmwares (conrad 197720 and 197730) counts switching cycles,
+    /// their command set ends at toggle (8), so this always fails with `Unsupported`
+    /// without sending anything
+    pub fn get_counters(&mut self, cards: CardIndex) -> Result<Vec<Vec<u32>>, Relay8xError> {
+        for &card in cards.iter() {
+            self.card_address(card)?;
+        }
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "Switch counters are not supported by this firmware",
+        )
+        .into())
+    }
+
+    /// query the faults each card reports, e.g. over-current, as readable strings
+    ///
+    /// the known firmwares have no fault or status register: a frame with a bad checksum
+    /// is answered with an error frame right away, nothing is latched to be read later;
+    /// this always fails with `Unsupported` without sending anything
+    pub fn get_faults(&mut self, cards: CardIndex) -> Result<Vec<Vec<String>>, Relay8xError> {
+        Ok(self.faults_unsupported(&cards)?)
+    }
+
+    /// reset the faults of the cards, unsupported just like `get_faults`
+    pub fn clear_faults(&mut self, cards: CardIndex) -> Result<(), Relay8xError> {
+        Ok(self.faults_unsupported(&cards)?)
+    }
+
+    fn faults_unsupported<T>(&self, cards: &CardIndex) -> io::Result<T> {
+        for &card in cards.iter() {
+            self.card_address(card)?;
+        }
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "Fault registers are not supported by this firmware",
+        ))
+    }
+
+    /// relay states of the card at `address`
+    fn query(&mut self, address: u8) -> io::Result<u8> {
+        let resp = self.command(Relay8xCmdSet::GetPort, address, None)?;
+        Ok(self.logical_state(self.board_bits(resp[2])))
+    }
+
+    /// relay states as switched from the coil states of a card
+    fn logical_state(&self, port: u8) -> u8 {
+        // active low cards report released coils as switched on
+        let port = if self.config.active_low { !port } else { port };
+        // a released normally closed relay closes its circuit
+        port ^ self.normally_closed_mask()
+    }
+
+    /// relay numbers as the board counts them, relay 1 is relay 8 with `msb_first`
+    fn board_relays(&self, relays: &RelayIndex) -> RelayIndex {
+        if !self.config.msb_first {
+            return relays.clone();
+        }
+        relays
+            .iter()
+            .map(|&r| if (1..=8).contains(&r) { 9 - r } else { r })
+            .collect()
+    }
+
+    /// converts a data byte between the board's bit order and bit 0 being relay 1, the
+    /// mapping is its own inverse
+    fn board_bits(&self, data: u8) -> u8 {
+        if self.config.msb_first {
+            data.reverse_bits()
+        } else {
+            data
+        }
+    }
+
+    /// command switching relays on, active low cards do so by releasing the coil
+    fn on_command(&self) -> Relay8xCmdSet {
+        if self.config.active_low {
+            Relay8xCmdSet::Reset
+        } else {
+            Relay8xCmdSet::Set
+        }
+    }
+
+    /// command switching relays off
+    fn off_command(&self) -> Relay8xCmdSet {
+        if self.config.active_low {
+            Relay8xCmdSet::Set
+        } else {
+            Relay8xCmdSet::Reset
+        }
+    }
+
+    /// forget the relay states known from previous frames
+    ///
+    /// needed with `skip_redundant` when relays may have been switched by someone else,
+    /// initialising the cards does so as well
+    pub fn forget_states(&mut self) {
+        self.known.clear();
+    }
+
+    /// sends a set or reset command, unless `skip_redundant` is set and the relays are
+    /// known to be switched that way already
+    fn switch(
+        &mut self,
+        cmd_set: Relay8xCmdSet,
+        address: u8,
+        relays: &RelayIndex,
+    ) -> io::Result<BytesMut> {
+        if self.redundant(&cmd_set, address, Relay8xCmdSet::relay_as_u8(relays)) {
+            info!("No change at 0x{:02x}, skipping", address);
+            return Ok(BytesMut::new());
+        }
+        self.limited_command(cmd_set, address, relays)
+    }
+
+    /// whether `skip_redundant` is set and the relays in `data` are known to be switched
+    /// by `cmd_set` already
+    fn redundant(&self, cmd_set: &Relay8xCmdSet, address: u8, data: u8) -> bool {
+        if !self.config.skip_redundant {
+            return false;
+        }
+        match self.known.get(&address) {
+            Some(&(known, port)) => {
+                // coil states, normally closed relays are switched the other way round
+                let wanted = match *cmd_set {
+                    Relay8xCmdSet::Set => data & !self.normally_closed_mask(),
+                    _ => data & self.normally_closed_mask(),
+                };
+                known & data == data && port & data == wanted
+            }
+            None => false,
+        }
+    }
+
+    /// sends a switching command, keeping `min_interval` between two switches of a relay
+    fn limited_command(
+        &mut self,
+        cmd_set: Relay8xCmdSet,
+        address: u8,
+        relays: &RelayIndex,
+    ) -> io::Result<BytesMut> {
+        let min = self.config.min_interval;
+        if min == Duration::from_secs(0) {
+            return self.command(cmd_set, address, Some(relays));
+        }
+        let last = self
+            .last_switched
+            .get(&address)
+            .cloned()
+            .unwrap_or([None; 8]);
+        let wait = relays
+            .iter()
+            .filter_map(|&r| last[r as usize - 1].map(|at| (r, at)))
+            .map(|(r, at)| (r, min.checked_sub(at.elapsed()).unwrap_or_default()))
+            .filter(|&(_, wait)| wait > Duration::from_secs(0))
+            .max_by_key(|&(_, wait)| wait);
+        if let Some((relay, wait)) = wait {
+            match self.config.rate_limit_policy {
+                RateLimitPolicy::Reject => {
+                    return Err(Error::other(format!(
+                        "Relay {} at 0x{:02x} switched again within {} ms",
+                        relay,
+                        address,
+                        min.as_millis()
+                    )))
+                }
+                RateLimitPolicy::Delay => {
+                    debug!("Delaying relay {} by {} ms", relay, wait.as_millis());
+                    thread::sleep(wait);
+                }
+            }
+        }
+        let resp = self.command(cmd_set, address, Some(relays))?;
+        let now = Instant::now();
+        let last = self.last_switched.entry(address).or_insert([None; 8]);
+        for &r in relays {
+            last[r as usize - 1] = Some(now);
+        }
+        Ok(resp)
+    }
+
+    /// relays switched by each frame: all in one, or with `ordered` one frame per relay in
+    /// ascending order
+    fn frames(&self, numbers: &RelayIndex) -> Vec<RelayIndex> {
+        if !self.config.ordered || numbers.is_empty() {
+            return vec![numbers.clone()];
+        }
+        let mut sorted = numbers.clone();
+        sorted.sort();
+        sorted.dedup();
+        sorted.into_iter().map(|r| vec![r]).collect()
+    }
+
+    /// sends a command to the card at `address` and returns its checked response
+    ///
+    /// a set or reset of normally closed relays next to normally open ones takes two
+    /// frames, the normally closed relays are switched the other way round
+    fn command(
+        &mut self,
+        cmd_set: Relay8xCmdSet,
+        address: u8,
+        relays: Option<&RelayIndex>,
+    ) -> io::Result<BytesMut> {
+        let inverse = match cmd_set {
+            Relay8xCmdSet::Set => Relay8xCmdSet::Reset,
+            Relay8xCmdSet::Reset => Relay8xCmdSet::Set,
+            _ => return self.frame_command(cmd_set, address, relays),
+        };
+        let relays = match relays {
+            Some(relays) => relays,
+            None => return self.frame_command(cmd_set, address, None),
+        };
+        let nc = self.normally_closed_mask();
+        let (closed, open): (RelayIndex, RelayIndex) =
+            relays.iter().partition(|&&r| nc & relay_bit(r) != 0);
+        let mut resp = BytesMut::new();
+        if !open.is_empty() {
+            resp = self.frame_command(cmd_set, address, Some(&open))?;
+        }
+        if !closed.is_empty() {
+            resp = self.frame_command(inverse, address, Some(&closed))?;
+        }
+        Ok(resp)
+    }
+
+    /// bits of the normally closed relays
+    fn normally_closed_mask(&self) -> u8 {
+        self.config
+            .normally_closed
+            .iter()
+            .fold(0, |mask, &r| mask | relay_bit(r))
+    }
+
+    /// sends a single frame to the card at `address` and returns its checked response
+    fn frame_command(
+        &mut self,
+        cmd_set: Relay8xCmdSet,
+        address: u8,
+        relays: Option<&RelayIndex>,
+    ) -> io::Result<BytesMut> {
+        let name = format!("{:?}", cmd_set);
+        let mut cmd = BytesMut::with_capacity(4);
+        let relays = relays.map(|relays| self.board_relays(relays));
+        Relay8xCmdSet::encode(&cmd_set, &mut cmd, address, None, relays.as_ref())?;
+        let mut attempt = 0;
+        let resp = loop {
+            let resp = self
+                .write_frame(&cmd[..])
+                .and_then(|_| self.read_response(address))
+                .and_then(|resp| {
+                    debug!("{} response: {}", name, Frame(&resp));
+                    match self.validator {
+                        Some(ref validator) => validator(&cmd, &resp),
+                        None => Relay8x::check_response(&resp, &cmd),
+                    }
+                    .map_err(io::Error::from)
+                    .map(|_| resp)
+                });
+            match resp {
+                Err(ref e) if attempt < self.retries && retryable(&cmd_set, e) => {
+                    attempt += 1;
+                    warn!(
+                        "{} at 0x{:02x} failed: {}, retry {} of {}",
+                        name, address, e, attempt, self.retries
+                    );
+                }
+                resp => break resp,
+            }
+        };
+        match resp {
+            Ok(resp) => {
+                let (data, port) = (self.board_bits(cmd[2]), self.board_bits(resp[2]));
+                self.remember(address, cmd[0], data, port);
+                Ok(resp)
+            }
+            Err(e) => {
+                // the frame may or may not have been executed
+                self.known.remove(&address);
+                Err(e)
+            }
+        }
+    }
+
+    /// tracks the relay states after a successful command
+    fn remember(&mut self, address: u8, cmd: u8, data: u8, resp: u8) {
+        let (known, port) = self.known.get(&address).cloned().unwrap_or((0, 0));
+        let state = match cmd {
+            2 => (0xff, resp),
+            3 => (0xff, data),
+            6 => (known | data, port | data),
+            7 => (known | data, port & !data),
+            8 => (known, port ^ data),
+            _ => return,
+        };
+        self.known.insert(address, state);
+    }
+
+    /// checks the interlocks before the relays in `mask` are switched on (or toggled)
+    ///
+    /// the card is only queried if an interlock is affected; relays of an interlock that
+    /// would still be on next to a newly switched on one are either reported as error or
+    /// switched off first, depending on the interlock policy
+    ///
+    /// returns the relays switched off
+    fn enforce_interlocks(&mut self, address: u8, mask: u8, toggle: bool) -> io::Result<u8> {
+        let locks: Vec<u8> = self
+            .config
+            .interlocks
+            .iter()
+            .map(Interlock::mask)
+            .filter(|lock| lock & mask != 0)
+            .collect();
+        if locks.is_empty() {
+            return Ok(0);
+        }
+        let state = self.query(address)?;
+        let (on, off) = if toggle {
+            (mask & !state, mask & state)
+        } else {
+            (mask, 0)
+        };
+        let after = (state | on) & !off;
+        let mut conflicting = 0;
+        for lock in locks {
+            if (lock & on).count_ones() > 1 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Relays {:?} are interlocked, only one of them may be on",
+                        u8_as_relays(lock)
+                    ),
+                ));
+            }
+            if lock & on != 0 {
+                conflicting |= lock & after & !on;
+            }
+        }
+        if conflicting == 0 {
+            return Ok(0);
+        }
+        match self.config.interlock_policy {
+            InterlockPolicy::Reject => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Switching on relays {:?} at 0x{:02x} violates the interlock with relays {:?}",
+                    u8_as_relays(on),
+                    address,
+                    u8_as_relays(conflicting)
+                ),
+            )),
+            InterlockPolicy::Clear => {
+                warn!(
+                    "Switching off relays {:?} at 0x{:02x} due to interlock",
+                    u8_as_relays(conflicting),
+                    address
+                );
+                let cmd_set = self.off_command();
+                self.command(cmd_set, address, Some(&u8_as_relays(conflicting)))?;
+                Ok(conflicting)
+            }
+        }
+    }
+
+    /// number of relays on a card, from `card_relays` or else `relays_per_card`
+    pub fn relay_count(&self, card: u8) -> u8 {
+        self.config.relay_count(card)
+    }
+
+    /// number of relays on the largest card of the chain, the most a broadcast switches
+    pub fn max_relays(&self) -> u8 {
+        self.config.max_relays()
+    }
+
+    /// numbers of all relays on a card
+    pub fn all_relays(&self, card: u8) -> RelayIndex {
+        (1..=self.relay_count(card)).collect()
+    }
+
+    /// the relays `numbers` for all cards or, if none are given, every relay of each card
+    ///
+    /// cards with the same number of relays are grouped, to be switched together
+    pub fn relay_groups(&self, cards: &[u8], numbers: &[u8]) -> Vec<(CardIndex, RelayIndex)> {
+        if !numbers.is_empty() {
+            return vec![(cards.to_vec(), numbers.to_vec())];
+        }
+        let mut groups: Vec<(CardIndex, RelayIndex)> = Vec::new();
+        for &card in cards {
+            let all = self.all_relays(card);
+            match groups.iter_mut().find(|(_, relays)| *relays == all) {
+                Some((cards, _)) => cards.push(card),
+                None => groups.push((vec![card], all)),
+            }
+        }
+        groups
+    }
+
+    /// address of a card, looked up in the address map if there is one
+    ///
+    /// frames are encoded with this address as start address and no card
+    fn card_address(&self, card: u8) -> io::Result<u8> {
+        match self.config.address_map {
+            Some(ref map) => map.get(&card).cloned().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Card {} is not in the address map", card),
+                )
+            }),
+            None => Relay8xCmdSet::addressed(self.config.address, Some(card)),
+        }
+    }
+
+    /// checks that all relay numbers exist on each of the cards
+    fn check_relays(&self, cards: &[u8], numbers: &RelayIndex) -> io::Result<()> {
+        for &card in cards {
+            let max = self.relay_count(card);
+            if let Some(&relay) = numbers.iter().find(|&&x| x == 0 || x > max) {
+                return Err(Relay8xError::RelayOutOfRange { relay, card, max }.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// send a raw frame and return the response of the card it is addressed to
+    ///
+    /// the frame is written as is, neither its checksum nor the response are checked
+    pub fn send_frame(&mut self, frame: &[u8]) -> Result<BytesMut, Relay8xError> {
+        self.write_frame(frame)?;
+        let address = frame.get(1).cloned().unwrap_or(0);
+        Ok(self.read_response(address)?)
+    }
+
+    /// writes a frame to the port
+    fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.drain_stale()?;
+        self.put_frame(frame)
+    }
+
+    /// discards stale bytes waiting on the port, if `drain` is set
+    fn drain_stale(&mut self) -> io::Result<()> {
+        if self.config.drain {
+            let drained = self.port.drain()?;
+            if drained > 0 {
+                debug!("Discarded {} stale bytes", drained);
+            }
+        }
+        Ok(())
+    }
+
+    /// writes one or more frames at once without draining, answers to frames written
+    /// before stay readable
+    fn put_frame(&mut self, frames: &[u8]) -> io::Result<()> {
+        self.port.write_all(frames)?;
+        for frame in frames.chunks(4) {
+            if self.echo_frames {
+                eprintln!("TX: {}", Frame(frame));
+            }
+            if let Some(ref mut capture) = self.capture {
+                capture.record(capture::Direction::Tx, frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// reads a single frame from the port
+    fn read_frame(&mut self) -> io::Result<[u8; 4]> {
+        let mut frame = [0u8; 4];
+        self.port.read_exact(&mut frame)?;
+        if self.echo_frames {
+            eprintln!("RX: {}", Frame(&frame));
+        }
+        if let Some(ref mut capture) = self.capture {
+            capture.record(capture::Direction::Rx, &frame)?;
+        }
+        Ok(frame)
+    }
+
+    /// reads the response frame of the card at `address`
+    ///
+    /// when several cards are written in a tight loop, the input buffer may still hold
+    /// frames meant for a previous card, these are discarded until the frame of `address`
+    /// shows up or the timeout elapses; broadcasts (address 0) accept any frame
+    fn read_response(&mut self, address: u8) -> io::Result<BytesMut> {
+        let now = Instant::now();
+        loop {
+            let resp = self.read_frame()?;
+            if address == 0 || resp[1] == address {
+                return Ok(BytesMut::from(&resp[..]));
+            }
+            debug!(
+                "Discarding frame of address 0x{:02x}: {}",
+                resp[1],
+                Frame(&resp)
+            );
+            if now.elapsed() > self.config.timeout {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("No response from card at address 0x{:02x}", address),
+                ));
+            }
+        }
+    }
+
+    /// simple response checker of recieved frame
+    fn check_response(msg: &BytesMut, sent_msg: &BytesMut) -> Result<(), Relay8xError> {
+        // check first byte
+        let checker_byte = sent_msg.first().unwrap_or(&1);
+        let checked_bytes = msg.first().unwrap_or(&1);
+        if *checked_bytes != !checker_byte {
+            return Err(Relay8xError::BadFirstByte {
+                got: *checked_bytes,
+                expected: !checker_byte,
+            });
+        }
+        // second byte: adress
+        let resp_addr = msg.get(1).unwrap_or(&0);
+        let sent_addr = sent_msg.get(1).unwrap_or(&1);
+        // weird error, it prints the same addresses but says there are not equal...
+        if resp_addr != sent_addr && *sent_addr != 0u8 {
+            return Err(Relay8xError::WrongAddress {
+                got: *resp_addr,
+                expected: *sent_addr,
+            });
+        }
+        // last byte: XOR
+        if *msg.get(3).unwrap_or(&0)
+            != (*msg.first().unwrap_or(&1) ^ *msg.get(1).unwrap_or(&0) ^ *msg.get(2).unwrap_or(&0))
+        {
+            let frame = [
+                *msg.first().unwrap_or(&1),
+                *msg.get(1).unwrap_or(&0),
+                *msg.get(2).unwrap_or(&0),
+                *msg.get(3).unwrap_or(&0),
+            ];
+            return Err(Relay8xError::BadChecksum(ChecksumError { frame }));
+        }
+        debug!("Check ok");
+        Ok(())
+    }
+}
+
+/// whether resending `cmd_set` may get past `e`: the answer was garbled or came from
+/// another card, and the command does the same when the card runs it twice
+fn retryable(cmd_set: &Relay8xCmdSet, e: &io::Error) -> bool {
+    if let Relay8xCmdSet::Toggle = *cmd_set {
+        return false;
+    }
+    match e.get_ref() {
+        Some(inner) if inner.is::<ChecksumError>() => true,
+        Some(inner) => matches!(
+            inner.downcast_ref::<Relay8xError>(),
+            Some(&Relay8xError::WrongAddress { .. }) | Some(&Relay8xError::BadChecksum(_))
+        ),
+        None => false,
+    }
+}
+
+impl Drop for Relay8x {
+    fn drop(&mut self) {
+        if self.drop_behavior == DropBehavior::AllOff {
+            if let Err(e) = self.reset_all() {
+                error!("Failed to switch the relays off on drop: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mock::{response, MockTransport};
+
+    #[test]
+    fn connect_to_card() {
+        let mock = MockTransport::new();
+        // the card acknowledges with the inverted command (254), the init frame itself
+        // comes back from the end of the chain with the next free address
+        mock.respond_with(response(1, 3, 0x0b))
+            .respond_with([1, 4, 0, 1 ^ 4]);
+        let mut relay = Relay8x::from_transport(mock.clone(), 3);
+
+        let cards = relay.configure_device().expect("Failed to init device");
+        assert_eq!(cards, 1);
+        assert_eq!(mock.written_frames(), vec![[1, 3, 0, 1 ^ 3]]);
+        assert_eq!(mock.pending(), 0);
+    }
+
+    #[test]
+    fn set_relays_writes_frame_and_reads_response() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(6, 1, 0b101));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+
+        relay
+            .set_relays(vec![1], vec![1, 3])
+            .expect("Failed to set relays");
+
+        assert_eq!(mock.written_frames(), vec![[6, 1, 0b101, 6 ^ 1 ^ 0b101]]);
+        assert_eq!(mock.pending(), 0);
+    }
+
+    #[test]
+    fn set_relays_skips_stale_frame() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(6, 1, 0b101))
+            // echo of card 1 still sitting in the buffer when card 2 is read
+            .respond_with(response(6, 1, 0b101))
+            .respond_with(response(6, 2, 0b101));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+
+        relay
+            .set_relays(vec![1, 2], vec![1, 3])
+            .expect("Failed to set relays");
+
+        assert_eq!(
+            mock.written_frames(),
+            vec![[6, 1, 0b101, 6 ^ 1 ^ 0b101], [6, 2, 0b101, 6 ^ 2 ^ 0b101]]
+        );
+        assert_eq!(mock.pending(), 0);
+    }
+
+    #[test]
+    fn set_relays_times_out_without_matching_frame() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(6, 1, 0b1));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+
+        match relay.set_relays(vec![2], vec![1]).unwrap_err() {
+            Relay8xError::Timeout { card, timeout } => {
+                assert_eq!(card, 2);
+                assert_eq!(timeout, Duration::from_millis(1000));
+            }
+            e => panic!("expected a timeout, got {:?}", e),
+        }
+
+        // a wrong answer is not a timeout
+        let mock = MockTransport::new();
+        mock.respond_with(response(8, 1, 0b1));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        match relay.set_relays(vec![1], vec![1]).unwrap_err() {
+            Relay8xError::BadFirstByte { got, expected } => assert_eq!((got, expected), (!8, !6)),
+            e => panic!("expected a bad first byte, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn failures_can_be_told_apart() {
+        let mock = MockTransport::new();
+        mock.respond_with([!6, 1, 1, 0]);
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        match relay.set_relays(vec![1], vec![1]).unwrap_err() {
+            Relay8xError::BadChecksum(e) => assert_eq!(e.frame, [!6, 1, 1, 0]),
+            e => panic!("expected a bad checksum, got {:?}", e),
+        }
+        match relay.set_relays(vec![1], vec![9]).unwrap_err() {
+            Relay8xError::RelayOutOfRange { relay, card, max } => {
+                assert_eq!((relay, card, max), (9, 1, 8))
+            }
+            e => panic!("expected a relay out of range, got {:?}", e),
+        }
+
+        // the variants survive a trip through io::Error
+        let e = io::Error::from(Relay8xError::WrongAddress {
+            got: 2,
+            expected: 1,
+        });
+        assert_eq!(e.to_string(), "Wrong Adress: 0x02 instead of 0x01");
+        match Relay8xError::from(e) {
+            Relay8xError::WrongAddress { got, expected } => assert_eq!((got, expected), (2, 1)),
+            e => panic!("expected a wrong address, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn set_relays_reports_partial_failure() {
+        // the middle card sends the frame back unchanged instead of acknowledging it
+        let mock = MockTransport::new();
+        mock.respond_with(response(6, 1, 0b1))
+            .respond_with([6, 2, 0b1, 6 ^ 2 ^ 0b1])
+            .respond_with(response(6, 3, 0b1));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+
+        match relay.set_relays(vec![1, 2, 3], vec![1]).unwrap_err() {
+            Relay8xError::Partial { done, failed } => {
+                assert_eq!(done, vec![1, 3]);
+                assert_eq!(failed.len(), 1);
+                assert_eq!(failed[0].0, 2);
+                assert_eq!(failed[0].1.kind(), ErrorKind::Other);
+            }
+            e => panic!("expected a partial failure, got {:?}", e),
+        }
+        assert_eq!(mock.written_frames().len(), 3);
+        assert_eq!(mock.pending(), 0);
+    }
+
+    #[test]
+    fn check_response_covers_every_branch() {
+        let set = [6, 1, 1, 6 ^ 1 ^ 1];
+        let cases: Vec<(Vec<u8>, Option<ErrorKind>)> = vec![
+            (response(6, 1, 1).to_vec(), None),
+            // not inverted
+            (set.to_vec(), Some(ErrorKind::Other)),
+            // bad XOR
+            (vec![!6, 1, 1, 0], Some(ErrorKind::InvalidData)),
+            // cut off, the rest never arrives
+            (vec![!6, 1], Some(ErrorKind::TimedOut)),
+            // a frame of another card is discarded, then nothing arrives
+            (response(6, 2, 1).to_vec(), Some(ErrorKind::TimedOut)),
+        ];
+        for (resp, kind) in cases {
+            let mock = MockTransport::new();
+            mock.expect_write(set).inject_response(&resp);
+            let mut relay = Relay8x::from_transport(mock.clone(), 1);
+            let result = relay.set_relays(vec![1], vec![1]);
+            assert_eq!(result.err().map(|e| e.kind()), kind, "response {:?}", resp);
+        }
+
+        // read_response filters frames of other addresses before they get checked
+        let sent = BytesMut::from(&set[..]);
+        let err = Relay8x::check_response(&BytesMut::from(&response(6, 2, 1)[..]), &sent);
+        assert!(err.unwrap_err().to_string().starts_with("Wrong Adress"));
+        let broadcast = BytesMut::from(&[6, 0, 1, 7][..]);
+        assert!(
+            Relay8x::check_response(&BytesMut::from(&response(6, 2, 1)[..]), &broadcast).is_ok()
+        );
+    }
+
+    #[test]
+    fn get_relays_returns_mask_per_card() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(2, 1, 0b0011_0001))
+            .respond_with(response(2, 2, 0b1000_0000));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+
+        let states = relay
+            .get_relays(vec![1, 2])
+            .expect("Failed to query relays");
+
+        assert_eq!(states, vec![0b0011_0001, 0b1000_0000]);
+        assert_eq!(
+            mock.written_frames(),
+            vec![[2, 1, 0, 2 ^ 1], [2, 2, 0, 2 ^ 2]]
+        );
+    }
+
+    #[test]
+    fn read_masks_decode_to_relay_lists() {
+        assert_eq!(u8_as_relays(0b0011_0001), vec![1, 5, 6]);
+        assert_eq!(u8_as_relays(0), Vec::<u8>::new());
+        for mask in 0..=255u8 {
+            assert_eq!(Relay8xCmdSet::relay_as_u8(&u8_as_relays(mask)), mask);
+        }
+    }
+
+    #[test]
+    fn bit_order_applies_to_set_and_read() {
+        for &msb_first in &[false, true] {
+            let (relay_1, relay_3) = if msb_first {
+                (0b1000_0000, 0b0010_0000)
+            } else {
+                (0b0000_0001, 0b0000_0100)
+            };
+            let mock = MockTransport::new();
+            mock.expect_write([6, 1, relay_1 | relay_3, 6 ^ 1 ^ (relay_1 | relay_3)])
+                .respond_with(response(6, 1, relay_1 | relay_3));
+            mock.respond_with(response(2, 1, relay_3));
+            let config = Relay8xConfig {
+                msb_first,
+                skip_redundant: true,
+                ..Default::default()
+            };
+            let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+
+            relay.set_relays(vec![1], vec![1, 3]).unwrap();
+            // the states remembered from the set are in the same order
+            relay.set_relays(vec![1], vec![3]).unwrap();
+            assert_eq!(mock.written_frames().len(), 1);
+            assert_eq!(relay.get_relays(vec![1]).unwrap(), vec![0b0000_0100]);
+        }
+    }
+
+    #[test]
+    fn sweep_matches_answers_by_address() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(2, 3, 0b1000_0000))
+            .respond_with(response(2, 7, 0xff))
+            .respond_with([!2, 2, 0b01, 0])
+            .respond_with(response(2, 1, 0b0000_0011));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+
+        let states = relay.sweep(vec![1, 2, 3]).unwrap();
+        assert_eq!(
+            states,
+            vec![(1, Some(0b0000_0011)), (2, None), (3, Some(0b1000_0000))]
+        );
+        assert_eq!(
+            mock.written_frames(),
+            vec![[2, 1, 0, 2 ^ 1], [2, 2, 0, 2 ^ 2], [2, 3, 0, 2 ^ 3]]
+        );
+    }
+
+    #[test]
+    fn pipeline_writes_all_frames_at_once() {
+        let mock = MockTransport::new();
+        // answers in any order, a corrupt one doesn't hide the valid one following it
+        mock.respond_with(response(6, 3, 0b101))
+            .respond_with([!6, 1, 0b101, 0])
+            .respond_with(response(6, 1, 0b101))
+            .respond_with(response(6, 2, 0b101));
+        let config = Relay8xConfig {
+            pipeline: true,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+
+        relay.set_relays(vec![1, 2, 3], vec![1, 3]).unwrap();
+        assert_eq!(mock.writes(), 1);
+        assert_eq!(
+            mock.written_frames(),
+            vec![
+                [6, 1, 0b101, 6 ^ 1 ^ 0b101],
+                [6, 2, 0b101, 6 ^ 2 ^ 0b101],
+                [6, 3, 0b101, 6 ^ 3 ^ 0b101]
+            ]
+        );
+        assert_eq!(mock.pending(), 0);
+
+        // a card answering wrongly fails, the others are switched
+        mock.respond_with(response(8, 2, 0b100))
+            .respond_with([!8, 1, 0b100, 0]);
+        match relay.toggle_relays(vec![1, 2], vec![3]) {
+            Err(Relay8xError::Partial { done, failed }) => {
+                assert_eq!(done, vec![2]);
+                assert_eq!(failed.len(), 1);
+                assert_eq!(failed[0].0, 1);
+                assert_eq!(failed[0].1.kind(), ErrorKind::InvalidData);
+            }
+            other => panic!("expected a partial failure, got {:?}", other),
+        }
+        assert_eq!(mock.writes(), 2);
+    }
+
+    #[test]
+    fn pipeline_leaves_multi_frame_switches_card_by_card() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(6, 1, 0b01))
+            .respond_with(response(6, 1, 0b11))
+            .respond_with(response(6, 2, 0b01))
+            .respond_with(response(6, 2, 0b11));
+        let config = Relay8xConfig {
+            pipeline: true,
+            ordered: true,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+
+        relay.set_relays(vec![1, 2], vec![1, 2]).unwrap();
+        assert_eq!(mock.writes(), 4);
+    }
+
+    #[test]
+    fn garbled_answers_are_retried() {
+        let set = [6, 1, 0b10, 6 ^ 1 ^ 0b10];
+        let mock = MockTransport::new();
+        mock.expect_write(set).respond_with([!6, 1, 0b10, 0]);
+        mock.expect_write(set).respond_with([!6, 1, 0b10, 1]);
+        mock.expect_write(set).respond_with(response(6, 1, 0b10));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        relay.set_retries(2);
+        relay.set_relays(vec![1], vec![2]).unwrap();
+        assert_eq!(mock.written_frames().len(), 3);
+
+        // out of retries, the last error is returned
+        mock.respond_with([!6, 1, 0b10, 0])
+            .respond_with([!6, 1, 0b10, 0])
+            .respond_with([!6, 1, 0b10, 0]);
+        let e = relay.set_relays(vec![1], vec![2]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(mock.written_frames().len(), 6);
+
+        // neither toggles nor relays the card doesn't have are resent
+        mock.respond_with([!8, 1, 0b10, 0]);
+        let e = relay.toggle_relays(vec![1], vec![2]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(mock.written_frames().len(), 7);
+        relay.set_relays(vec![1], vec![9]).unwrap_err();
+        assert_eq!(mock.written_frames().len(), 7);
+        assert_eq!(mock.pending(), 0);
+    }
+
+    #[test]
+    fn option_byte_is_read_and_written() {
+        let sim = sim::SimulatedRelays::new(2);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        assert_eq!(relay.get_option(2).unwrap(), 1);
+        relay.set_option(2, 0b10).unwrap();
+        assert_eq!(relay.get_option(2).unwrap(), 0b10);
+        assert_eq!(relay.get_option(1).unwrap(), 1);
+
+        let mock = MockTransport::new();
+        mock.expect_write([5, 1, 0b11, 5 ^ 1 ^ 0b11])
+            .respond_with(response(5, 1, 0));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        relay.set_option(1, 0b11).unwrap();
+        assert_eq!(relay.get_option(1).unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn ping_only_queries() {
+        let mock = MockTransport::new();
+        mock.expect_write([2, 1, 0, 2 ^ 1])
+            .respond_with(response(2, 1, 0b0000_0100));
+        mock.expect_write([2, 2, 0, 2 ^ 2]);
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+
+        assert!(relay.ping(1).unwrap());
+        assert!(!relay.ping(2).unwrap());
+        assert_eq!(mock.written_frames().len(), 2);
+    }
+
+    #[test]
+    fn normally_closed_relays_switch_the_other_way() {
+        let sim = sim::SimulatedRelays::new(1);
+        let config = Relay8xConfig {
+            normally_closed: vec![2, 4],
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(sim.clone(), config);
+
+        // released coils of normally closed relays keep their circuits closed
+        assert_eq!(relay.get_relays(vec![1]).unwrap(), vec![0b0000_1010]);
+        relay.set_relays(vec![1], vec![1, 2]).unwrap();
+        assert_eq!(sim.relays(1), Some(0b0000_0001));
+        assert_eq!(relay.get_relays(vec![1]).unwrap(), vec![0b0000_1011]);
+
+        relay.reset_relays(vec![1], vec![1, 2, 4]).unwrap();
+        assert_eq!(sim.relays(1), Some(0b0000_1010));
+        assert_eq!(relay.get_relays(vec![1]).unwrap(), vec![0]);
+        assert!(!relay.is_relay_on(1, 4).unwrap());
+
+        relay.toggle_relays(vec![1], vec![3, 4]).unwrap();
+        assert_eq!(relay.get_relays(vec![1]).unwrap(), vec![0b0000_1100]);
+    }
+
+    #[test]
+    fn write_port_sets_the_whole_card() {
+        let sim = sim::SimulatedRelays::new(1);
+        let config = Relay8xConfig {
+            normally_closed: vec![4],
+            interlocks: vec![Interlock::new(vec![1, 2]).unwrap()],
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(sim.clone(), config);
+        relay.set_relays(vec![1], vec![1, 5]).unwrap();
+
+        relay.write_port(vec![1], 0b0000_1100).unwrap();
+        // normally closed relay 4 is closed with its coil released
+        assert_eq!(sim.relays(1), Some(0b0000_0100));
+        assert_eq!(relay.get_relays(vec![1]).unwrap(), vec![0b0000_1100]);
+
+        assert!(relay.write_port(vec![1], 0b0000_0011).is_err());
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        relay.config.momentary.insert(3, Duration::from_millis(20));
+        assert!(relay.write_port(vec![1], 0b0000_0100).is_err());
+        assert_eq!(sim.relays(1), Some(0b0000_0100));
+
+        let mock = MockTransport::new();
+        mock.expect_write([3, 1, 0b101, 3 ^ 1 ^ 0b101])
+            .respond_with(response(3, 1, 0b101));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        relay.write_port(vec![1], 0b101).unwrap();
+        assert_eq!(mock.written_frames().len(), 1);
+    }
+
+    #[test]
+    fn init_without_reconfiguring_the_port() {
+        let mock = MockTransport::new();
+        mock.without_settings();
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        assert_eq!(
+            relay.configure_port().unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        mock.respond_with(response(1, 1, 0x11))
+            .respond_with([1, 2, 0, 1 ^ 2]);
+        let config = Relay8xConfig {
+            reconfigure: false,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+        relay.configure_device().expect("Failed to init device");
+        assert_eq!(relay.cards().len(), 1);
+        assert_eq!(mock.timeouts().last(), Some(&Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn unsupported_baud_rate_is_rejected() {
+        let mock = MockTransport::new();
+        let config = Relay8xConfig {
+            baud: 14400,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+        let err = relay.configure_port().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(mock.timeouts().is_empty());
+    }
+
+    #[test]
+    fn configured_timeout_applies_to_responses() {
+        let mock = MockTransport::new();
+        let config = Relay8xConfig {
+            timeout: Duration::from_millis(250),
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config.clone());
+        relay.configure_port().unwrap();
+        assert_eq!(mock.timeouts(), vec![Duration::from_millis(250)]);
+        match relay.set_relays(vec![1], vec![1]).unwrap_err() {
+            Relay8xError::Timeout { card, timeout } => {
+                assert_eq!((card, timeout), (1, Duration::from_millis(250)))
+            }
+            e => panic!("unexpected error {}", e),
+        }
+
+        let config = Relay8xConfig {
+            timeout: Duration::from_secs(0),
+            ..config
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+        assert!(relay.configure_port().is_err());
+    }
+
+    #[test]
+    fn configure_device_collects_card_info() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(1, 1, 0x11))
+            .respond_with(response(1, 2, 0x12))
+            // init frame passed back by the last card
+            .respond_with([1, 3, 0, 1 ^ 3]);
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+
+        relay.configure_device().expect("Failed to init device");
+
+        assert_eq!(mock.written_frames(), vec![[1, 1, 0, 1 ^ 1]]);
+        assert_eq!(
+            relay.cards(),
+            &[
+                CardInfo {
+                    address: 1,
+                    firmware: 0x11,
+                    relays: 8,
+                },
+                CardInfo {
+                    address: 2,
+                    firmware: 0x12,
+                    relays: 8,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn init_checks_every_acknowledgement() {
+        let corrupt = [!1, 2, 0x12, 0];
+        let skipped = response(1, 3, 0x12);
+        for &(ack, kind) in &[
+            (corrupt, ErrorKind::InvalidData),
+            (skipped, ErrorKind::Other),
+        ] {
+            let mock = MockTransport::new();
+            mock.respond_with(response(1, 1, 0x11))
+                .respond_with(ack)
+                .respond_with([1, 3, 0, 1 ^ 3]);
+            let mut relay = Relay8x::from_transport(mock.clone(), 1);
+            let err = relay.configure_device().unwrap_err();
+            assert_eq!(err.kind(), kind, "acknowledgement {:?}", ack);
+        }
+    }
+
+    #[test]
+    fn relay_moves_to_another_thread() {
+        let sim = sim::SimulatedRelays::new(1);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        let worker = thread::spawn(move || relay.set_relays(vec![1], vec![2]).map(|_| ()));
+        worker.join().unwrap().unwrap();
+        assert_eq!(sim.relays(1), Some(0b10));
+    }
+
+    #[test]
+    fn relays_switch_off_on_drop_if_asked() {
+        let sim = sim::SimulatedRelays::new(2);
+        for &(behavior, left) in &[
+            (DropBehavior::Leave, Some(0b101)),
+            (DropBehavior::AllOff, Some(0)),
+        ] {
+            let mut relay = Relay8x::from_transport(sim.clone(), 1);
+            relay.configure_device().unwrap();
+            relay.set_relays(vec![1, 2], vec![1, 3]).unwrap();
+            relay.set_drop_behavior(behavior);
+            drop(relay);
+            assert_eq!(sim.relays(1), left, "{:?}", behavior);
+            assert_eq!(sim.relays(2), left, "{:?}", behavior);
+        }
+    }
+
+    #[test]
+    fn clear_relays_leaves_other_relays() {
+        let sim = sim::SimulatedRelays::new(1);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+
+        relay.set_relays(vec![1], vec![1, 3]).unwrap();
+        relay.clear_relays(vec![1], vec![3]).unwrap();
+
+        assert_eq!(sim.relays(1), Some(0b001));
+    }
+
+    #[test]
+    fn addressed_counts_cards_from_one() {
+        assert_eq!(Relay8xCmdSet::addressed(1, None).unwrap(), 1);
+        assert_eq!(Relay8xCmdSet::addressed(1, Some(1)).unwrap(), 1);
+        assert_eq!(Relay8xCmdSet::addressed(1, Some(2)).unwrap(), 2);
+        assert_eq!(Relay8xCmdSet::addressed(5, Some(3)).unwrap(), 7);
+        assert_eq!(Relay8xCmdSet::addressed(255, Some(1)).unwrap(), 255);
+
+        for &address in &[0, 1, 5] {
+            let err = Relay8xCmdSet::addressed(address, Some(0)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        assert!(Relay8xCmdSet::addressed(255, Some(2)).is_err());
+
+        let mut relay = Relay8x::from_transport(MockTransport::new(), 1);
+        assert!(relay.set_relays(vec![0], vec![1]).is_err());
+    }
+
+    #[test]
+    fn cards_are_addressed_arithmetically() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(8, 3, 1))
+            .respond_with(response(8, 4, 1));
+        let mut relay = Relay8x::from_transport(mock.clone(), 3);
+
+        relay.toggle_relays(vec![1, 2], vec![1]).unwrap();
+
+        let addresses: Vec<u8> = mock.written_frames().iter().map(|f| f[1]).collect();
+        assert_eq!(addresses, vec![3, 4]);
+    }
+
+    #[test]
+    fn cards_are_addressed_by_map() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(8, 7, 1))
+            .respond_with(response(8, 1, 1));
+        let config = Relay8xConfig {
+            address_map: Some(config::parse_address_map("1:1,2:3,3:7").unwrap()),
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+
+        relay.toggle_relays(vec![3, 1], vec![1]).unwrap();
+        let addresses: Vec<u8> = mock.written_frames().iter().map(|f| f[1]).collect();
+        assert_eq!(addresses, vec![7, 1]);
+
+        let err = relay.toggle_relays(vec![4], vec![1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    fn interlocked(policy: InterlockPolicy) -> (sim::SimulatedRelays, Relay8x) {
+        let sim = sim::SimulatedRelays::new(1);
+        let config = Relay8xConfig {
+            interlocks: vec![Interlock::new(vec![1, 2]).unwrap()],
+            interlock_policy: policy,
+            ..Default::default()
+        };
+        let relay = Relay8x::from_transport_with_config(sim.clone(), config);
+        (sim, relay)
+    }
+
+    #[test]
+    fn interlock_rejects_conflicting_set() {
+        let (sim, mut relay) = interlocked(InterlockPolicy::Reject);
+        relay.set_relays(vec![1], vec![1, 3]).unwrap();
+
+        let err = relay.set_relays(vec![1], vec![2]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = relay.toggle_relays(vec![1], vec![2]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(sim.relays(1), Some(0b101));
+
+        // both at once can't be satisfied by any policy
+        relay.clear_relays(vec![1], vec![1]).unwrap();
+        assert!(relay.set_relays(vec![1], vec![1, 2]).is_err());
+        assert_eq!(sim.relays(1), Some(0b100));
+    }
+
+    #[test]
+    fn interlock_clears_conflicting_relay() {
+        let (sim, mut relay) = interlocked(InterlockPolicy::Clear);
+        relay.set_relays(vec![1], vec![1, 3]).unwrap();
+
+        relay.set_relays(vec![1], vec![2]).unwrap();
+        assert_eq!(sim.relays(1), Some(0b110));
+        relay.toggle_relays(vec![1], vec![1, 2]).unwrap();
+        assert_eq!(sim.relays(1), Some(0b101));
+    }
+
+    #[test]
+    fn counters_are_not_supported() {
+        let mock = MockTransport::new();
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        let err = relay.get_counters(vec![1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert!(mock.written_frames().is_empty());
+    }
+
+    #[test]
+    fn custom_validator_replaces_response_check() {
+        // a clone board answering with the command as sent instead of inverted
+        let mock = MockTransport::new();
+        mock.respond_with([6, 1, 1, 6 ^ 1 ^ 1]);
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        assert!(relay.set_relays(vec![1], vec![1]).is_err());
+
+        mock.respond_with([6, 1, 1, 6 ^ 1 ^ 1])
+            .respond_with(response(6, 1, 1));
+        relay.set_response_validator(|sent, received| {
+            if sent[..2] == received[..2] {
+                Ok(())
+            } else {
+                Err(io::Error::other("Not an echo").into())
+            }
+        });
+        relay.set_relays(vec![1], vec![1]).unwrap();
+        // the default check would accept this one
+        let err = relay.set_relays(vec![1], vec![1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn parses_relay_tokens() {
+        for &(token, relay) in &[("3", 3), ("03", 3), (" 7 ", 7), ("0008", 8), ("\t1\n", 1)] {
+            assert_eq!(parse_relay(token), Ok(relay), "{:?}", token);
+        }
+        for token in &["", " ", "0", "00", "9", "300", "3a", "+3", "-1", "1 2", "x"] {
+            assert!(parse_relay(token).is_err(), "{:?} should not parse", token);
+        }
+        assert_eq!(
+            parse_relay(" 3a ").unwrap_err().to_string(),
+            "Can't parse ' 3a ': '3a' is no relay number (1..8)"
+        );
+        assert_eq!(expand_relays("01-03").unwrap(), vec![1, 2, 3]);
+        assert_eq!(parse_relay_states("03:on").unwrap(), (0b100, 0b100));
+    }
+
+    #[test]
+    fn expands_relay_lists() {
+        assert_eq!(expand_relays("1-3,5").unwrap(), vec![1, 2, 3, 5]);
+        assert_eq!(expand_relays("4").unwrap(), vec![4]);
+        assert_eq!(expand_relays(" 8, 1 - 2 ").unwrap(), vec![8, 1, 2]);
+        assert_eq!(expand_relays("1-8").unwrap().len(), 8);
+        assert_eq!(expand_relays("3-3").unwrap(), vec![3]);
+
+        for spec in &[
+            "", "0", "9", "1-9", "0-2", "4-2", "1,,2", "1-", "-2", "a", "1-2-3",
+        ] {
+            assert!(expand_relays(spec).is_err(), "{} should not parse", spec);
+        }
+        let err = expand_relays("1-3,2").unwrap_err();
+        assert_eq!(err.input, "1-3,2");
+        assert_eq!(
+            err.to_string(),
+            "Can't parse '1-3,2': relay 2 is listed twice"
+        );
+    }
+
+    #[test]
+    fn parses_masks() {
+        assert_eq!(parse_mask("0b00001010", 8).unwrap(), 0b1010);
+        assert_eq!(parse_mask("0x0a", 8).unwrap(), 0b1010);
+        assert_eq!(parse_mask("0xFF", 8).unwrap(), 0xff);
+        assert_eq!(parse_mask("10", 8).unwrap(), 0b1010);
+        assert_eq!(parse_mask("0b1111", 4).unwrap(), 0b1111);
+
+        assert!(parse_mask("0", 8).is_err());
+        assert_eq!(parse_port("0").unwrap(), 0);
+        assert_eq!(parse_port("0b101").unwrap(), 0b101);
+        assert!(parse_port("0x100").is_err());
+        assert!(parse_mask("256", 8).is_err());
+        assert!(parse_mask("0b2", 8).is_err());
+        assert!(parse_mask("0x", 8).is_err());
+        assert!(parse_mask("a", 8).is_err());
+        assert!(parse_mask("0b10000", 4).is_err());
+    }
+
+    #[test]
+    fn broadcast_sends_one_unchecked_frame() {
+        let mock = MockTransport::new();
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        relay.broadcast_relays(vec![1, 3], true).unwrap();
+        relay.broadcast_relays(vec![1], false).unwrap();
+        assert_eq!(
+            mock.written_frames(),
+            vec![[6, 0, 0b101, 6 ^ 0b101], [7, 0, 0b1, 7 ^ 0b1]]
+        );
+
+        // the answers of the cards don't mix with those to the next frames
+        let sim = sim::SimulatedRelays::new(2);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        relay.broadcast_relays(vec![2], true).unwrap();
+        assert_eq!(relay.get_relays(vec![1, 2]).unwrap(), vec![0b10, 0b10]);
+
+        let config = Relay8xConfig {
+            interlocks: vec![Interlock::new(vec![1, 2]).unwrap()],
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+        assert!(relay.broadcast_relays(vec![2], true).is_err());
+        assert!(relay.broadcast_relays(vec![2], false).is_ok());
+    }
+
+    #[test]
+    fn latency_is_measured_and_relay_restored() {
+        let sim = sim::SimulatedRelays::new(1);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        let latency = relay.measure_latency(1, 3, Duration::from_secs(1)).unwrap();
+        assert!(latency.is_some());
+        assert_eq!(sim.relays(1), Some(0));
+
+        // a relay which never reads back on
+        let mock = MockTransport::new();
+        mock.respond_with(response(2, 1, 0))
+            .respond_with(response(6, 1, 0b100))
+            .respond_with(response(2, 1, 0))
+            .respond_with(response(7, 1, 0b100));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        let latency = relay.measure_latency(1, 3, Duration::from_secs(0)).unwrap();
+        assert_eq!(latency, None);
+        assert_eq!(mock.pending(), 0);
+    }
+
+    #[test]
+    fn ordered_set_sends_a_frame_per_relay() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(6, 1, 0b10101));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        relay.set_relays(vec![1], vec![5, 1, 3]).unwrap();
+        assert_eq!(mock.written_frames().len(), 1);
+
+        let mock = MockTransport::new();
+        mock.respond_with(response(6, 1, 0b1))
+            .respond_with(response(6, 1, 0b100))
+            .respond_with(response(6, 1, 0b10000));
+        let config = Relay8xConfig {
+            ordered: true,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+        relay.set_relays(vec![1], vec![5, 1, 3]).unwrap();
+        let data: Vec<u8> = mock.written_frames().iter().map(|f| f[2]).collect();
+        assert_eq!(data, vec![0b1, 0b100, 0b10000]);
+    }
+
+    #[test]
+    fn parses_relay_states() {
+        assert_eq!(parse_relay_states("1:on,3:off").unwrap(), (0b101, 0b001));
+        assert_eq!(parse_relay_states(" 8 : off ").unwrap(), (0b1000_0000, 0));
+        for spec in &["", "1", "1:", "0:on", "9:off", "1:1", "1:on,1:off", "a:on"] {
+            assert!(
+                parse_relay_states(spec).is_err(),
+                "{} should not parse",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn apply_sends_a_reset_and_a_set_frame() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(7, 1, 0b100))
+            .respond_with(response(6, 1, 0b11));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        relay.apply_relays(vec![1], 0b111, 0b1011).unwrap();
+        let frames: Vec<(u8, u8)> = mock.written_frames().iter().map(|f| (f[0], f[2])).collect();
+        assert_eq!(frames, vec![(7, 0b100), (6, 0b11)]);
+
+        // nothing to switch off, a single set frame
+        let mock = MockTransport::new();
+        mock.respond_with(response(6, 1, 0b1));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        relay.apply_relays(vec![1], 0b1, 0b1).unwrap();
+        assert_eq!(mock.written_frames().len(), 1);
+        relay.apply_relays(vec![1], 0, 0xff).unwrap();
+        assert_eq!(mock.written_frames().len(), 1);
+    }
+
+    #[test]
+    fn mixed_chain_checks_relays_per_card() {
+        let sim = sim::SimulatedRelays::new(2);
+        let mut card_relays = BTreeMap::new();
+        card_relays.insert(2, 4);
+        let config = Relay8xConfig {
+            card_relays,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(sim.clone(), config);
+        relay.configure_device().unwrap();
+        let counts: Vec<u8> = relay.cards().iter().map(|c| c.relays).collect();
+        assert_eq!(counts, vec![8, 4]);
+        assert_eq!(relay.all_relays(2), vec![1, 2, 3, 4]);
+
+        let err = relay.set_relays(vec![1, 2], vec![6]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "Relay number 6 out of range (1..4) of card 2"
+        );
+        assert!(relay.is_relay_on(2, 5).is_err());
+        relay.set_relays(vec![1], vec![6]).unwrap();
+        relay.clear_and_set_relays(vec![1, 2], vec![2]).unwrap();
+        assert_eq!(sim.relays(1), Some(0b10));
+        assert_eq!(sim.relays(2), Some(0b10));
+        assert_eq!(relay.self_test(2).unwrap().len(), 4);
+
+        // a broadcast reaches the larger card
+        relay.broadcast_relays(vec![6], false).unwrap();
+        assert!(relay.broadcast_relays(vec![9], false).is_err());
+    }
+
+    #[test]
+    fn stale_bytes_are_drained_before_a_frame() {
+        let mock = MockTransport::new();
+        mock.inject_stale(&[0x42]).respond_with(response(6, 1, 0b1));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        relay.set_relays(vec![1], vec![1]).unwrap();
+        assert_eq!(mock.pending(), 0);
+
+        // without the drain the stale byte shifts the response
+        let mock = MockTransport::new();
+        mock.inject_stale(&[0x42]).respond_with(response(6, 1, 0b1));
+        let config = Relay8xConfig {
+            drain: false,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+        assert!(relay.set_relays(vec![1], vec![1]).is_err());
+    }
+
+    #[test]
+    fn builds_frames_without_a_port() {
+        let relays = vec![3];
+        assert_eq!(
+            build_frame(Relay8xCmdSet::Set, 1, &relays).unwrap(),
+            [6, 1, 0b100, 6 ^ 1 ^ 0b100]
+        );
+        assert_eq!(
+            build_frame(Relay8xCmdSet::Reset, 2, &relays).unwrap(),
+            [7, 2, 0b100, 7 ^ 2 ^ 0b100]
+        );
+        assert_eq!(
+            build_frame(Relay8xCmdSet::Toggle, 1, &vec![1, 8]).unwrap(),
+            [8, 1, 0b1000_0001, 8 ^ 1 ^ 0b1000_0001]
+        );
+        assert_eq!(
+            build_frame(Relay8xCmdSet::GetPort, 3, &relays).unwrap(),
+            [2, 3, 0, 1]
+        );
+        assert_eq!(
+            build_frame(Relay8xCmdSet::SetPort, 2, &vec![1, 2]).unwrap(),
+            [3, 2, 0b11, 3 ^ 2 ^ 0b11]
+        );
+        assert_eq!(
+            build_frame(Relay8xCmdSet::Init, 1, &vec![]).unwrap(),
+            [1, 1, 0, 0]
+        );
+
+        assert!(build_frame(Relay8xCmdSet::Set, 0, &relays).is_err());
+        assert!(build_frame(Relay8xCmdSet::Set, 1, &vec![9]).is_err());
+    }
+
+    #[test]
+    fn encode_rejects_impossible_relays() {
+        for relays in &[vec![0], vec![9], vec![1, 9]] {
+            let mut bytes = BytesMut::with_capacity(4);
+            match Relay8xCmdSet::Set.encode(&mut bytes, 1, Some(2), Some(relays)) {
+                Err(Relay8xError::RelayOutOfRange {
+                    card: 2, max: 8, ..
+                }) => {}
+                other => panic!("{:?} encoded to {:?}", relays, other),
+            }
+        }
+        for cmd_set in &[
+            Relay8xCmdSet::SetPort,
+            Relay8xCmdSet::Set,
+            Relay8xCmdSet::Reset,
+            Relay8xCmdSet::Toggle,
+        ] {
+            let mut bytes = BytesMut::with_capacity(4);
+            let e = cmd_set.encode(&mut bytes, 1, None, None).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput, "{:?}", cmd_set);
+        }
+    }
+
+    #[test]
+    fn reset_all_switches_every_card_off() {
+        let sim = sim::SimulatedRelays::new(3);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        relay.configure_device().unwrap();
+        relay.set_relays(vec![1, 2, 3], vec![1, 5]).unwrap();
+        relay.reset_all().unwrap();
+        assert_eq!(
+            (sim.relays(1), sim.relays(2), sim.relays(3)),
+            (Some(0), Some(0), Some(0))
+        );
+
+        // card 2 answers garbage first, its second frame is acknowledged
+        let init = |mock: &MockTransport| {
+            mock.respond_with(response(1, 1, 0x0b))
+                .respond_with(response(1, 2, 0x0b))
+                .respond_with([1, 3, 0, 1 ^ 3]);
+        };
+        let mock = MockTransport::new();
+        init(&mock);
+        mock.respond_with(response(7, 1, 0xff))
+            .inject_response(&[!7, 2, 0xff, 0])
+            .respond_with(response(7, 2, 0xff));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        relay.configure_device().unwrap();
+        relay.reset_all().unwrap();
+        let frames: Vec<(u8, u8)> = mock.written_frames()[1..]
+            .iter()
+            .map(|f| (f[1], f[2]))
+            .collect();
+        assert_eq!(frames, vec![(1, 0xff), (2, 0xff), (2, 0xff)]);
+
+        // card 2 stays silent
+        let mock = MockTransport::new();
+        init(&mock);
+        mock.respond_with(response(7, 1, 0xff));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        relay.configure_device().unwrap();
+        let err = relay.reset_all().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(err
+            .to_string()
+            .starts_with("cards switched: [1], card 2 failed"));
+    }
+
+    #[test]
+    fn reset_all_broadcasts_to_small_cards() {
+        // without a card list, e.g. with skip_init_ack, the reset is a broadcast
+        let mock = MockTransport::new();
+        let config = Relay8xConfig {
+            relays_per_card: 4,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+        relay.reset_all().unwrap();
+        assert_eq!(mock.written_frames(), vec![[7, 0, 0x0f, 7 ^ 0x0f]]);
+    }
+
+    #[test]
+    fn mirror_follows_source_and_resyncs_target() {
+        let sim = sim::SimulatedRelays::new(2);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        assert_eq!(relay.mirror_card(1, 2).unwrap(), None);
+
+        sim.set_relays(1, 0b1010);
+        assert_eq!(relay.mirror_card(1, 2).unwrap(), Some(0b1010));
+        assert_eq!(sim.relays(2), Some(0b1010));
+        assert_eq!(relay.mirror_card(1, 2).unwrap(), None);
+
+        // switched by hand on the target
+        sim.set_relays(2, 0b0110);
+        assert_eq!(relay.mirror_card(1, 2).unwrap(), Some(0b1010));
+        assert_eq!(sim.relays(2), Some(0b1010));
+    }
+
+    #[test]
+    fn rapid_switches_are_throttled() {
+        let limited = |policy| {
+            let sim = sim::SimulatedRelays::new(1);
+            let config = Relay8xConfig {
+                min_interval: Duration::from_millis(50),
+                rate_limit_policy: policy,
+                ..Default::default()
+            };
+            (
+                sim.clone(),
+                Relay8x::from_transport_with_config(sim, config),
+            )
+        };
+        let (sim, mut relay) = limited(RateLimitPolicy::Reject);
+        relay.set_relays(vec![1], vec![1]).unwrap();
+        assert!(relay.reset_relays(vec![1], vec![1]).is_err());
+        assert!(relay.toggle_relays(vec![1], vec![1, 2]).is_err());
+        assert_eq!(sim.relays(1), Some(0b1));
+        // other relays aren't limited
+        relay.set_relays(vec![1], vec![2]).unwrap();
+        thread::sleep(Duration::from_millis(60));
+        relay.reset_relays(vec![1], vec![1]).unwrap();
+        assert_eq!(sim.relays(1), Some(0b10));
+
+        let (sim, mut relay) = limited(RateLimitPolicy::Delay);
+        let start = Instant::now();
+        for _ in 0..3 {
+            relay.toggle_relays(vec![1], vec![3]).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(sim.relays(1), Some(0b100));
+    }
+
+    #[test]
+    fn cards_report_their_addresses() {
+        let sim = sim::SimulatedRelays::new(3);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        assert_eq!(relay.query_addresses().unwrap(), vec![1, 2, 3]);
+
+        let mock = MockTransport::new();
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        let err = relay.query_addresses().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(mock.written_frames(), vec![[2, 0, 0, 2]]);
+    }
+
+    #[test]
+    fn faults_are_not_supported() {
+        let mock = MockTransport::new();
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        let err = relay.get_faults(vec![1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        let err = relay.clear_faults(vec![1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert!(mock.written_frames().is_empty());
+    }
+
+    #[test]
+    fn configure_device_can_skip_init_ack() {
+        let mock = MockTransport::new();
+        let config = Relay8xConfig {
+            skip_init_ack: true,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+        assert_eq!(relay.configure_device().unwrap(), 0);
+        assert_eq!(mock.written_frames(), vec![[1, 1, 0, 0]]);
+        assert!(relay.cards().is_empty());
+
+        // the strict default gives up once the card stays silent
+        let mut relay = Relay8x::from_transport(MockTransport::new(), 1);
+        assert!(relay.configure_device().is_err());
+    }
+
+    #[test]
+    fn init_waits_for_its_own_timeout() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(1, 1, 0x0b))
+            .respond_with([1, 2, 0, 1 ^ 2])
+            .respond_with(response(6, 1, 0b1));
+        let config = Relay8xConfig {
+            timeout: Duration::from_millis(200),
+            init_timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config.clone());
+        relay.configure_device().unwrap();
+        let ms: Vec<u128> = mock.timeouts().iter().map(|t| t.as_millis()).collect();
+        assert_eq!(ms, vec![200, 5000, 200]);
+
+        // switching keeps the timeout of the port
+        relay.set_relays(vec![1], vec![1]).unwrap();
+        assert_eq!(mock.timeouts().len(), 3);
+
+        // restored also when the init fails
+        let mock = MockTransport::new();
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+        assert!(relay.configure_device().is_err());
+        assert_eq!(mock.timeouts().last(), Some(&Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn init_detects_a_device_which_is_no_relay_card() {
+        // NMEA output of a GPS receiver, silence, a frame with a bad XOR, the init frame
+        // echoed by a loopback
+        let responses: Vec<&[u8]> = vec![b"$GPGGA,1", &[], &[!1, 1, 0x0b, 0], &[1, 1, 0, 1]];
+        for resp in responses {
+            let mock = MockTransport::new();
+            mock.inject_response(resp);
+            let mut relay = Relay8x::from_transport(mock, 1);
+            let err = relay.configure_device().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound, "response {:?}", resp);
+            assert_eq!(
+                err.to_string(),
+                "No relay card detected on the port, check the device path"
+            );
+        }
+
+        // a card answered, losing the rest of the chain is a plain read error
+        let mock = MockTransport::new();
+        mock.respond_with(response(1, 1, 0x0b));
+        let mut relay = Relay8x::from_transport(mock, 1);
+        let err = relay.configure_device().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn momentary_relay_is_pulsed() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(6, 1, 0b100))
+            .respond_with(response(7, 1, 0b100))
+            .respond_with(response(6, 1, 0b1));
+        let mut config = Relay8xConfig::default();
+        config.momentary.insert(3, Duration::from_millis(20));
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+
+        let start = Instant::now();
+        relay.set_relays(vec![1], vec![3]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(
+            mock.written_frames(),
+            vec![[6, 1, 0b100, 6 ^ 1 ^ 0b100], [7, 1, 0b100, 7 ^ 1 ^ 0b100]]
+        );
+
+        // latching relays are still switched with a single frame
+        relay.set_relays(vec![1], vec![1]).unwrap();
+        assert_eq!(mock.written_frames().len(), 3);
+        assert_eq!(mock.pending(), 0);
+    }
+
+    #[test]
+    fn pulse_switches_off_after_a_failing_card() {
+        // card 2 doesn't exist, its frame comes back unanswered; card 1 must not stay on
+        let sim = sim::SimulatedRelays::new(1);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        let start = Instant::now();
+        assert!(relay
+            .pulse_relays(vec![1, 2], vec![2], Duration::from_secs(10))
+            .is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(sim.relays(1), Some(0));
+        assert!(relay
+            .pulse_relays(vec![1], vec![9], Duration::from_millis(10))
+            .is_err());
+    }
+
+    #[test]
+    fn auto_address_finds_first_card() {
+        let sim = sim::SimulatedRelays::new(2);
+        Relay8x::from_transport(sim.clone(), 5)
+            .configure_device()
+            .unwrap();
+
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        assert_eq!(relay.auto_address().unwrap(), 5);
+        relay.configure_device().unwrap();
+        assert_eq!(relay.cards()[1].address, 6);
+
+        let mut relay = Relay8x::from_transport(MockTransport::new(), 1);
+        assert_eq!(
+            relay.auto_address().unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn locate_restores_relay_states() {
+        let sim = sim::SimulatedRelays::new(2);
+        sim.set_relays(1, 0b0011_0101);
+        let mut config = Relay8xConfig {
+            interlocks: vec![Interlock::new(vec![7, 8]).unwrap()],
+            ..Default::default()
+        };
+        config.momentary.insert(6, Duration::from_millis(10));
+        let mut relay = Relay8x::from_transport_with_config(sim.clone(), config);
+
+        relay.locate(vec![1, 2], 1).unwrap();
+        assert_eq!(sim.relays(1), Some(0b0011_0101));
+        assert_eq!(sim.relays(2), Some(0));
+    }
+
+    #[test]
+    fn locate_strobes_only_relays_of_the_card() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(2, 1, 0b0101));
+        for &cmd in &[6, 7, 6, 7, 6, 7] {
+            mock.respond_with(response(cmd, 1, 0));
+        }
+        let config = Relay8xConfig {
+            relays_per_card: 4,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+        relay.locate(vec![1], 1).unwrap();
+        let data: Vec<(u8, u8)> = mock.written_frames()[1..]
+            .iter()
+            .map(|f| (f[0], f[2]))
+            .collect();
+        assert_eq!(
+            data,
+            vec![
+                (6, 0xf),
+                (7, 0xf),
+                (6, 0xf),
+                (7, 0xf),
+                (6, 0b0101),
+                (7, 0b1010)
+            ]
+        );
+    }
+
+    #[test]
+    fn locate_restores_the_other_cards_if_one_fails() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(2, 1, 0b1))
+            .respond_with(response(2, 2, 0b1))
+            // card 1 garbles the answer to its restore
+            .inject_response(&[!6, 1, 0b1, 0])
+            .respond_with(response(6, 2, 0b1))
+            .respond_with(response(7, 2, 0xfe));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+        match relay.locate(vec![1, 2], 0).unwrap_err() {
+            Relay8xError::Partial { done, failed } => {
+                assert_eq!(done, vec![2]);
+                assert_eq!(failed.len(), 1);
+                assert_eq!(failed[0].0, 1);
+            }
+            e => panic!("unexpected error {}", e),
+        }
+        assert_eq!(mock.written_frames().len(), 5);
+    }
+
+    #[test]
+    fn redundant_set_is_skipped() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(6, 1, 0b11))
+            .respond_with(response(7, 1, 0b10))
+            .respond_with(response(6, 1, 0b10));
+        let config = Relay8xConfig {
+            skip_redundant: true,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(mock.clone(), config);
+
+        relay.set_relays(vec![1], vec![1, 2]).unwrap();
+        relay.set_relays(vec![1], vec![1, 2]).unwrap();
+        relay.set_relays(vec![1], vec![2]).unwrap();
+        assert_eq!(mock.written_frames().len(), 1);
+
+        relay.clear_relays(vec![1], vec![2]).unwrap();
+        relay.clear_relays(vec![1], vec![2]).unwrap();
+        assert_eq!(mock.written_frames().len(), 2);
+
+        // someone else may have switched it back on
+        relay.forget_states();
+        relay.set_relays(vec![1], vec![2]).unwrap();
+        assert_eq!(mock.written_frames().len(), 3);
+        assert_eq!(mock.pending(), 0);
+    }
+
+    #[test]
+    fn switching_works_without_init() {
+        // cards initialised by an earlier process keep their addresses
+        let sim = sim::SimulatedRelays::new(2);
+        Relay8x::from_transport(sim.clone(), 3)
+            .configure_device()
+            .unwrap();
+
+        let mut relay = Relay8x::from_transport(sim.clone(), 3);
+        relay.configure_port().unwrap();
+        relay.set_relays(vec![2], vec![2]).unwrap();
+        assert_eq!(sim.relays(4), Some(0b10));
+        assert!(relay.cards().is_empty());
+    }
+
+    #[test]
+    fn clear_first_resets_before_setting() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(7, 1, 0xff))
+            .respond_with(response(6, 1, 0b100));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+
+        relay.clear_and_set_relays(vec![1], vec![3]).unwrap();
+        assert_eq!(
+            mock.written_frames(),
+            vec![[7, 1, 0xff, 7 ^ 1 ^ 0xff], [6, 1, 0b100, 6 ^ 1 ^ 0b100]]
+        );
+    }
+
+    #[test]
+    fn is_relay_on_extracts_bit() {
+        let mock = MockTransport::new();
+        mock.respond_with(response(2, 2, 0b100))
+            .respond_with(response(2, 2, 0b100));
+        let mut relay = Relay8x::from_transport(mock.clone(), 1);
+
+        assert!(relay.is_relay_on(2, 3).unwrap());
+        assert!(!relay.is_relay_on(2, 4).unwrap());
+        assert_eq!(mock.written_frames()[0], [2, 2, 0, 2 ^ 2]);
+        for &number in &[0, 9] {
+            let err = relay.is_relay_on(2, number).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn self_test_checks_every_relay_and_restores_state() {
+        let sim = sim::SimulatedRelays::new(1);
+        sim.set_relays(1, 0b1001);
+        let mut config = Relay8xConfig::default();
+        config.momentary.insert(8, Duration::from_millis(10));
+        let mut relay = Relay8x::from_transport_with_config(sim.clone(), config);
+
+        let results = relay.self_test(1).unwrap();
+        assert_eq!(results.len(), 8);
+        assert!(results[..7].iter().all(|&(_, r)| r == RelayTest::Pass));
+        assert_eq!(results[7], (8, RelayTest::Skipped));
+        assert_eq!(sim.relays(1), Some(0b1001));
+    }
+
+    #[test]
+    fn on_change_reports_switched_relays() {
+        use std::sync::{Arc, Mutex};
+
+        let sim = sim::SimulatedRelays::new(2);
+        sim.set_relays(2, 0b10);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = changes.clone();
+        relay.set_on_change(move |card, number, on| {
+            recorded.lock().unwrap().push((card, number, on))
+        });
+
+        relay.set_relays(vec![1], vec![1, 3]).unwrap();
+        relay.clear_relays(vec![1], vec![3]).unwrap();
+        relay.toggle_relays(vec![2], vec![1, 2]).unwrap();
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![
+                (1, 1, true),
+                (1, 3, true),
+                (1, 3, false),
+                (2, 1, true),
+                (2, 2, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn max_cards_bounds_init_and_scan() {
+        let sim = sim::SimulatedRelays::new(3);
+        let config = Relay8xConfig {
+            max_cards: 2,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(sim.clone(), config.clone());
+        assert!(relay.configure_device().is_err());
+
+        Relay8x::from_transport(sim.clone(), 3)
+            .configure_device()
+            .unwrap();
+        let mut relay = Relay8x::from_transport_with_config(sim.clone(), config);
+        assert_eq!(
+            relay.auto_address().unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn talks_to_a_card_behind_a_tcp_bridge() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+
+        // fake card answering the first frame only
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let card = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut frame = [0u8; 4];
+            stream.read_exact(&mut frame).unwrap();
+            stream
+                .write_all(&response(frame[0], frame[1], frame[2]))
+                .unwrap();
+            stream.read_exact(&mut frame).unwrap();
+            // the stream stays open, the reset has to time out instead of hitting EOF
+            (frame, stream)
+        });
+
+        let config = Relay8xConfig {
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut relay = Relay8x::with_config(&format!("tcp://{}", address), config).unwrap();
+        relay.configure_port().unwrap();
+        relay.set_relays(vec![1], vec![1, 3]).unwrap();
+        let err = relay.reset_relays(vec![1], vec![1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(card.join().unwrap().0, [7, 1, 1, 7 ^ 1 ^ 1]);
+    }
+
+    #[test]
+    fn talks_over_any_byte_stream() {
+        use std::io::Cursor;
+        use std::sync::{Arc, Mutex};
+
+        // canned answers to read, written frames land in a buffer the test keeps
+        struct Pair {
+            rx: Cursor<Vec<u8>>,
+            tx: Arc<Mutex<Vec<u8>>>,
+        }
+        impl Read for Pair {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.rx.read(buf)
+            }
+        }
+        impl Write for Pair {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.tx.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let answers = [response(2, 1, 0b101), response(6, 1, 0b10)].concat();
+        let tx = Arc::new(Mutex::new(Vec::new()));
+        let pair = Pair {
+            rx: Cursor::new(answers),
+            tx: tx.clone(),
+        };
+        let mut relay = Relay8x::from_io(pair, 1);
+        relay.configure_port().unwrap();
+        assert_eq!(relay.get_relays(vec![1]).unwrap(), vec![0b101]);
+        relay.set_relays(vec![1], vec![2]).unwrap();
+        assert_eq!(
+            *tx.lock().unwrap(),
+            vec![2, 1, 0, 2 ^ 1, 6, 1, 0b10, 6 ^ 1 ^ 0b10]
+        );
+
+        // the answers ran out, like a card which stopped responding
+        let err = relay.reset_relays(vec![1], vec![2]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn read_modify_write_takes_turns_under_the_lock() {
+        use std::env::temp_dir;
+        use std::fs::{remove_file, File};
+        use std::process;
+
+        let path = temp_dir().join(format!("relay8x-rmw-test-{}", process::id()));
+        File::create(&path).unwrap();
+        let path = path.to_str().unwrap().to_string();
+        let sim = sim::SimulatedRelays::new(1);
+
+        // both processes count up the relays of the same card
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let (sim, path) = (sim.clone(), path.clone());
+                thread::spawn(move || {
+                    let mut relay = Relay8x::from_transport(sim, 1);
+                    for _ in 0..20 {
+                        let _lock = DeviceLock::wait(&path).unwrap();
+                        relay.modify_relays(1, |state| state + 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(sim.relays(1), Some(40));
+        remove_file(&path).unwrap();
+    }
 }
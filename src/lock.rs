@@ -0,0 +1,64 @@
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::ErrorKind;
+
+/// advisory lock on a serial device, held until dropped
+///
+/// the lock is an exclusive `flock` on the device node itself (e.g. `/dev/ttyUSB0`),
+/// so it is shared by every process honouring it and vanishes with the process
+pub struct DeviceLock {
+    // keeps the lock alive
+    _file: File,
+}
+
+impl DeviceLock {
+    /// lock `device_name`, fails fast with `WouldBlock` if another process holds the lock
+    pub fn acquire(device_name: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(device_name)?;
+        file.try_lock_exclusive().map_err(|e| {
+            if e.kind() == fs2::lock_contended_error().kind() {
+                io::Error::new(
+                    ErrorKind::WouldBlock,
+                    format!("{}: device busy, used by another process", device_name),
+                )
+            } else {
+                e
+            }
+        })?;
+        debug!("Locked {}", device_name);
+        Ok(Self { _file: file })
+    }
+
+    /// lock `device_name`, waiting for another process to release it first
+    ///
+    /// for read-modify-write cycles which have to take turns with other processes
+    pub fn wait(device_name: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(device_name)?;
+        file.lock_exclusive()?;
+        debug!("Locked {}", device_name);
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env::temp_dir;
+    use std::process;
+
+    #[test]
+    fn second_lock_reports_busy() {
+        let path = temp_dir().join(format!("relay8x-lock-test-{}", process::id()));
+        File::create(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let lock = DeviceLock::acquire(path).expect("Failed to lock");
+        let err = DeviceLock::acquire(path).err().expect("Locked twice");
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        drop(lock);
+        DeviceLock::acquire(path).expect("Failed to lock after release");
+        ::std::fs::remove_file(path).unwrap();
+    }
+}
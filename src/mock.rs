@@ -0,0 +1,175 @@
+//! in-memory stand-in for a serial port, used by the unit tests
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use transport::RelayTransport;
+
+#[derive(Default)]
+struct MockState {
+    // every byte written by `Relay8x`
+    written: Vec<u8>,
+    // bytes handed out on read, in order
+    pending: VecDeque<u8>,
+    // bytes waiting before the next write, read ahead of `pending` unless drained
+    stale: VecDeque<u8>,
+    // frames the next writes have to match
+    expected: VecDeque<[u8; 4]>,
+    // written bytes already compared to an expected frame
+    checked: usize,
+    // number of writes, several frames may go in one
+    writes: usize,
+    // every read timeout set, in order
+    timeouts: Vec<Duration>,
+    // configure fails, like a transport without serial settings
+    no_settings: bool,
+}
+
+/// records written frames and replays canned responses
+///
+/// clones share their state, so a test can keep one handle while `Relay8x` owns the other
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// queue a response frame, it is read after all previously queued ones
+    pub fn respond_with(&self, frame: [u8; 4]) -> &Self {
+        self.state.lock().unwrap().pending.extend(frame.iter());
+        self
+    }
+
+    /// queue arbitrary response bytes, e.g. a short or otherwise malformed frame
+    pub fn inject_response(&self, bytes: &[u8]) -> &Self {
+        self.state.lock().unwrap().pending.extend(bytes.iter());
+        self
+    }
+
+    /// leave bytes of an earlier operation in the input buffer, a drain discards them
+    pub fn inject_stale(&self, bytes: &[u8]) -> &Self {
+        self.state.lock().unwrap().stale.extend(bytes.iter());
+        self
+    }
+
+    /// let `configure` fail, like a transport without baud rate or parity would
+    pub fn without_settings(&self) -> &Self {
+        self.state.lock().unwrap().no_settings = true;
+        self
+    }
+
+    /// the next frame written has to be `frame`, the write panics otherwise
+    ///
+    /// chains with `respond_with`: `mock.expect_write(set).respond_with(ack)`
+    pub fn expect_write(&self, frame: [u8; 4]) -> &Self {
+        self.state.lock().unwrap().expected.push_back(frame);
+        self
+    }
+
+    /// all frames written so far
+    pub fn written_frames(&self) -> Vec<[u8; 4]> {
+        self.state
+            .lock()
+            .unwrap()
+            .written
+            .chunks(4)
+            .map(|c| [c[0], c[1], c[2], c[3]])
+            .collect()
+    }
+
+    /// number of writes so far
+    pub fn writes(&self) -> usize {
+        self.state.lock().unwrap().writes
+    }
+
+    /// read timeouts set so far, in order
+    pub fn timeouts(&self) -> Vec<Duration> {
+        self.state.lock().unwrap().timeouts.clone()
+    }
+
+    /// number of response bytes not consumed yet
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+}
+
+/// response a card sends for `cmd`: inverted command, own address, data, XOR
+pub fn response(cmd: u8, address: u8, data: u8) -> [u8; 4] {
+    let cmd = !cmd;
+    [cmd, address, data, cmd ^ address ^ data]
+}
+
+impl Read for MockTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.is_empty() && state.stale.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "mock: no response"));
+        }
+        let mut n = 0;
+        while n < buf.len() {
+            match state
+                .stale
+                .pop_front()
+                .or_else(|| state.pending.pop_front())
+            {
+                Some(byte) => buf[n] = byte,
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MockTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.written.extend_from_slice(buf);
+        state.writes += 1;
+        while state.written.len() >= state.checked + 4 {
+            let at = state.checked;
+            state.checked += 4;
+            let written = &state.written[at..at + 4];
+            let written = [written[0], written[1], written[2], written[3]];
+            if let Some(expected) = state.expected.pop_front() {
+                assert_eq!(written, expected, "mock: unexpected frame written");
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl RelayTransport for MockTransport {
+    fn configure(&mut self, _baud: u32) -> io::Result<()> {
+        if self.state.lock().unwrap().no_settings {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "mock: no serial settings",
+            ));
+        }
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.state.lock().unwrap().timeouts.push(timeout);
+        Ok(())
+    }
+
+    fn drain(&mut self) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let drained = state.stale.len();
+        state.stale.clear();
+        Ok(drained)
+    }
+}
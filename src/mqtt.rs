@@ -0,0 +1,237 @@
+//! MQTT bridge for home automation, e.g. Home Assistant
+//!
+//! the relays of a card are switched by publishing `on`, `off` or `toggle` to
+//! `<prefix>/relay/<n>/set`; their states are published as `on` or `off`, retained, to
+//! `<prefix>/relay/<n>/state` on every (re)connect and after each change
+
+use std::io;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+
+use {iter_relays, parse_relay, Backoff, ConfigWatcher, ParseError, Relay8x};
+
+/// port of a broker without one in its url
+const DEFAULT_PORT: u16 = 1883;
+
+/// how often the broker is pinged while idle
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// host and port of the broker
+#[derive(Debug, Clone, PartialEq)]
+pub struct Broker {
+    /// name or address of the broker
+    pub host: String,
+    /// port the broker listens on
+    pub port: u16,
+}
+
+/// parses a broker url like `mqtt://192.168.1.10:1883`, `tcp://broker` or `broker:1883`
+pub fn parse_broker(url: &str) -> Result<Broker, ParseError> {
+    let address = url.trim();
+    let address = match address.split_once("://") {
+        Some(("mqtt", rest)) | Some(("tcp", rest)) => rest,
+        Some((scheme, _)) => {
+            return Err(ParseError::new(
+                url,
+                format!("scheme '{}' is neither mqtt nor tcp", scheme),
+            ))
+        }
+        None => address,
+    };
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| ParseError::new(url, format!("port '{}' is no number", port)))?;
+            (host, port)
+        }
+        None => (address, DEFAULT_PORT),
+    };
+    if host.is_empty() {
+        return Err(ParseError::new(url, "use e.g. mqtt://192.168.1.10:1883"));
+    }
+    Ok(Broker {
+        host: host.to_string(),
+        port,
+    })
+}
+
+/// what a message to a `set` topic asks for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Switch {
+    /// switch the relay on
+    On,
+    /// switch the relay off
+    Off,
+    /// toggle the relay
+    Toggle,
+}
+
+impl Switch {
+    /// parses a payload `on`, `off` or `toggle`, in any case
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        match String::from_utf8_lossy(payload)
+            .trim()
+            .to_lowercase()
+            .as_str()
+        {
+            "on" => Some(Switch::On),
+            "off" => Some(Switch::Off),
+            "toggle" => Some(Switch::Toggle),
+            _ => None,
+        }
+    }
+}
+
+/// relay number of a topic like `<prefix>/relay/3/set`
+pub fn relay_of(prefix: &str, topic: &str) -> Option<u8> {
+    let number = topic
+        .strip_prefix(prefix)?
+        .strip_prefix("/relay/")?
+        .strip_suffix("/set")?;
+    parse_relay(number).ok()
+}
+
+/// bridges the relays of `card` to the broker until the device fails or the broker can't
+/// be reached for `backoff.attempts` attempts in a row
+///
+/// a switch failing, e.g. for a relay the card doesn't have, is logged and bridging goes
+/// on; a changed config of `watcher` is applied to `relay` between messages
+pub fn serve(
+    relay: &mut Relay8x,
+    card: u8,
+    broker: &Broker,
+    prefix: &str,
+    backoff: Backoff,
+    mut watcher: Option<ConfigWatcher>,
+) -> io::Result<()> {
+    let mut options = MqttOptions::new(
+        format!("relay8x-{}", process::id()),
+        broker.host.clone(),
+        broker.port,
+    );
+    options.set_keep_alive(KEEP_ALIVE);
+    // room for the subscription and the states of all relays, publishing never blocks
+    let (client, mut connection) = Client::new(options, 16);
+    // relay states last published, none after a (re)connect
+    let mut published = None;
+    let mut attempt = 0;
+    for event in connection.iter() {
+        if let Some(ref mut watcher) = watcher {
+            watcher.update(relay);
+        }
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to {}:{}", broker.host, broker.port);
+                attempt = 0;
+                // the session is clean, the subscription is gone after a reconnect
+                client
+                    .try_subscribe(format!("{}/relay/+/set", prefix), QoS::AtLeastOnce)
+                    .map_err(io::Error::other)?;
+                published = None;
+                publish(relay, card, &client, prefix, &mut published)?;
+            }
+            Ok(Event::Incoming(Packet::Publish(msg))) => {
+                let relays = relay_of(prefix, &msg.topic).map(|number| vec![number]);
+                let result = match (relays, Switch::parse(&msg.payload)) {
+                    (Some(relays), Some(Switch::On)) => relay.set_relays(vec![card], relays),
+                    (Some(relays), Some(Switch::Off)) => relay.reset_relays(vec![card], relays),
+                    (Some(relays), Some(Switch::Toggle)) => relay.toggle_relays(vec![card], relays),
+                    _ => {
+                        warn!(
+                            "Ignoring '{}' on {}",
+                            String::from_utf8_lossy(&msg.payload),
+                            msg.topic
+                        );
+                        continue;
+                    }
+                };
+                if let Err(e) = result {
+                    warn!("Failed to switch for {}: {}", msg.topic, e);
+                }
+                publish(relay, card, &client, prefix, &mut published)?;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                if attempt >= backoff.attempts {
+                    return Err(io::Error::other(format!(
+                        "Lost the broker {}:{}: {}",
+                        broker.host, broker.port, e
+                    )));
+                }
+                let delay = backoff.delay(attempt);
+                warn!(
+                    "Broker connection failed: {}, reconnecting in {} ms",
+                    e,
+                    delay.as_millis()
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// reads the relays of `card` and publishes the states which changed since `published`,
+/// all of them without
+fn publish(
+    relay: &mut Relay8x,
+    card: u8,
+    client: &Client,
+    prefix: &str,
+    published: &mut Option<u8>,
+) -> io::Result<()> {
+    let state = relay.get_relays(vec![card])?[0];
+    let changed = published.map_or(0xff, |before| before ^ state);
+    let relays = relay.all_relays(card);
+    for ((number, on), (_, differs)) in iter_relays(state).zip(iter_relays(changed)) {
+        if differs && relays.contains(&number) {
+            let topic = format!("{}/relay/{}/state", prefix, number);
+            let payload = if on { "on" } else { "off" };
+            client
+                .try_publish(topic, QoS::AtLeastOnce, true, payload)
+                .map_err(io::Error::other)?;
+        }
+    }
+    *published = Some(state);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_brokers() {
+        let broker = |host: &str, port| Broker {
+            host: host.to_string(),
+            port,
+        };
+        assert_eq!(
+            parse_broker("mqtt://192.168.1.10:1884"),
+            Ok(broker("192.168.1.10", 1884))
+        );
+        assert_eq!(parse_broker("tcp://broker"), Ok(broker("broker", 1883)));
+        assert_eq!(parse_broker(" broker:1883 "), Ok(broker("broker", 1883)));
+        for url in &["", "mqtt://", "ws://broker", "broker:port", "broker:70000"] {
+            assert!(parse_broker(url).is_err(), "{} should not parse", url);
+        }
+    }
+
+    #[test]
+    fn parses_set_topics_and_payloads() {
+        assert_eq!(relay_of("home/relays", "home/relays/relay/3/set"), Some(3));
+        assert_eq!(relay_of("home/relays", "home/relays/relay/3/state"), None);
+        assert_eq!(relay_of("home/relays", "home/relays/relay/9/set"), None);
+        assert_eq!(relay_of("home/relays", "other/relay/3/set"), None);
+
+        assert_eq!(Switch::parse(b"on"), Some(Switch::On));
+        assert_eq!(Switch::parse(b"OFF"), Some(Switch::Off));
+        assert_eq!(Switch::parse(b" toggle\n"), Some(Switch::Toggle));
+        assert_eq!(Switch::parse(b"1"), None);
+    }
+}
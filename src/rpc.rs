@@ -0,0 +1,501 @@
+//! JSON-RPC 2.0 interface to a chain of relay cards
+//!
+//! a request names the cards and relays to act on, both default to the first card and
+//! all of its relays:
+//!
+//! ```text
+//! {"jsonrpc": "2.0", "id": 1, "method": "set", "params": {"cards": [1], "relays": [1, 3], "state": "on"}}
+//! ```
+//!
+//! and is answered with the resulting relay states of the cards, bit 0 is relay 1:
+//!
+//! ```text
+//! {"jsonrpc": "2.0", "id": 1, "result": [{"card": 1, "state": 5}]}
+//! ```
+//!
+//! methods are `set` (with `state` `on` or `off`), `toggle` (needs `relays`), `reset`
+//! and `get`; a batch is an array of operations, the same methods with their params
+//! plus `sleep`, operations without `cards` act on the cards of the session:
+//!
+//! ```text
+//! [{"op": "set", "relays": [1], "state": true}, {"op": "sleep", "ms": 100}, {"op": "toggle", "relays": [2]}]
+//! ```
+
+use serde_json;
+use serde_json::Value;
+use std::io;
+use std::io::ErrorKind;
+use std::thread;
+use std::time::Duration;
+
+use {CardIndex, Relay8x, Relay8xError, RelayIndex};
+
+/// a call of one of the methods
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Request {
+    /// echoed in the response, requests without id are still answered
+    #[serde(default)]
+    pub id: Value,
+    /// `set`, `toggle`, `reset` or `get`
+    pub method: String,
+    /// what to act on
+    #[serde(default)]
+    pub params: Params,
+}
+
+/// arguments of a request
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Params {
+    /// cards to act on, the first card if empty
+    #[serde(default)]
+    pub cards: CardIndex,
+    /// relays to act on, all relays if empty
+    #[serde(default)]
+    pub relays: RelayIndex,
+    /// `on` or `off`, only used by `set`
+    #[serde(default)]
+    pub state: Option<String>,
+}
+
+/// relay states of a card
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CardState {
+    /// card number
+    pub card: u8,
+    /// relay mask like `get_relays`
+    pub state: u8,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Vec<CardState>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorObject>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorObject {
+    code: i32,
+    message: String,
+}
+
+// error codes defined by JSON-RPC 2.0, -32000 is the first one left to applications
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const DEVICE_ERROR: i32 = -32000;
+
+impl Request {
+    /// checks method and params without touching the cards
+    pub fn validate(&self) -> io::Result<()> {
+        let params = &self.params;
+        let invalid = |msg: String| Err(io::Error::new(ErrorKind::InvalidInput, msg));
+        match self.method.as_str() {
+            "set" => match params.state.as_deref() {
+                Some("on") | Some("off") => {}
+                _ => return invalid("set needs state 'on' or 'off'".to_string()),
+            },
+            "toggle" if params.relays.is_empty() => {
+                return invalid("toggle needs the relays to toggle".to_string());
+            }
+            "toggle" | "reset" | "get" => {}
+            method => {
+                return Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("Unknown method '{}'", method),
+                ))
+            }
+        }
+        if let Some(card) = params.cards.iter().find(|&&c| c == 0) {
+            return invalid(format!("Card {} doesn't exist, cards count from 1", card));
+        }
+        if let Some(relay) = params.relays.iter().find(|&&r| r == 0 || r > 8) {
+            return invalid(format!("Relay number {} out of range (1..8)", relay));
+        }
+        Ok(())
+    }
+}
+
+/// executes a request and returns the relay states of its cards afterwards
+pub fn execute(relay: &mut Relay8x, request: &Request) -> Result<Vec<CardState>, Relay8xError> {
+    request.validate()?;
+    let params = &request.params;
+    let cards = if params.cards.is_empty() {
+        vec![1]
+    } else {
+        params.cards.clone()
+    };
+    // without relays every card switches all of its own
+    for (group, relays) in relay.relay_groups(&cards, &params.relays) {
+        match (request.method.as_str(), params.state.as_deref()) {
+            ("set", Some("on")) => relay.set_relays(group, relays).map(|_| ())?,
+            ("set", _) | ("reset", _) => relay.reset_relays(group, relays).map(|_| ())?,
+            ("toggle", _) => relay.toggle_relays(group, relays).map(|_| ())?,
+            _ => {}
+        }
+    }
+    let states = relay.get_relays(cards.clone())?;
+    Ok(cards
+        .into_iter()
+        .zip(states)
+        .map(|(card, state)| CardState { card, state })
+        .collect())
+}
+
+/// a step of a batch, e.g. `{"op": "set", "relays": [1], "state": true}`
+///
+/// apart from `sleep`, the operations are the methods of a request with its params
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Operation {
+    /// switch relays on or off
+    Set {
+        /// cards to switch, the default cards if empty
+        #[serde(default)]
+        cards: CardIndex,
+        /// relays to switch, all relays if empty
+        #[serde(default)]
+        relays: RelayIndex,
+        /// on or off
+        state: bool,
+    },
+    /// toggle relays
+    Toggle {
+        /// cards to toggle, the default cards if empty
+        #[serde(default)]
+        cards: CardIndex,
+        /// relays to toggle
+        relays: RelayIndex,
+    },
+    /// switch relays off
+    Reset {
+        /// cards to switch off, the default cards if empty
+        #[serde(default)]
+        cards: CardIndex,
+        /// relays to switch off, all relays if empty
+        #[serde(default)]
+        relays: RelayIndex,
+    },
+    /// read the relay states
+    Get {
+        /// cards to read, the default cards if empty
+        #[serde(default)]
+        cards: CardIndex,
+    },
+    /// pause for a while
+    Sleep {
+        /// milliseconds to pause
+        ms: u64,
+    },
+}
+
+impl Operation {
+    /// the equivalent request, `None` for `sleep`
+    pub fn request(&self) -> Option<Request> {
+        let (method, cards, relays, state) = match *self {
+            Operation::Set {
+                ref cards,
+                ref relays,
+                state,
+            } => (
+                "set",
+                cards,
+                relays.clone(),
+                Some(if state { "on" } else { "off" }),
+            ),
+            Operation::Toggle {
+                ref cards,
+                ref relays,
+            } => ("toggle", cards, relays.clone(), None),
+            Operation::Reset {
+                ref cards,
+                ref relays,
+            } => ("reset", cards, relays.clone(), None),
+            Operation::Get { ref cards } => ("get", cards, Vec::new(), None),
+            Operation::Sleep { .. } => return None,
+        };
+        Some(Request {
+            id: Value::Null,
+            method: method.to_string(),
+            params: Params {
+                cards: cards.clone(),
+                relays,
+                state: state.map(str::to_string),
+            },
+        })
+    }
+}
+
+/// parses a JSON array of operations and validates all of them
+pub fn parse_batch(text: &str) -> io::Result<Vec<Operation>> {
+    let ops: Vec<Operation> = serde_json::from_str(text)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("Bad batch: {}", e)))?;
+    for (i, op) in ops.iter().enumerate() {
+        if let Some(request) = op.request() {
+            request.validate().map_err(|e| {
+                io::Error::new(ErrorKind::InvalidInput, format!("Operation {}: {}", i, e))
+            })?;
+        }
+    }
+    Ok(ops)
+}
+
+/// executes the operations in order, returning the relay states after each of them,
+/// empty for `sleep`
+///
+/// operations without `cards` act on `cards`, the default of the session; cards of all
+/// operations are checked against the initialised chain before the first one runs, the
+/// batch stops at the first failing operation and its index is part of the error
+pub fn run_batch(
+    relay: &mut Relay8x,
+    ops: &[Operation],
+    cards: &CardIndex,
+) -> io::Result<Vec<Vec<CardState>>> {
+    let mut requests = Vec::with_capacity(ops.len());
+    for (i, op) in ops.iter().enumerate() {
+        let request = op.request().map(|mut request| {
+            if request.params.cards.is_empty() {
+                request.params.cards = cards.clone();
+            }
+            request
+        });
+        if let Some(ref request) = request {
+            for &card in &request.params.cards {
+                relay
+                    .check_card(card)
+                    .map_err(|e| io::Error::new(e.kind(), format!("Operation {}: {}", i, e)))?;
+            }
+        }
+        requests.push(request);
+    }
+
+    let mut results = Vec::with_capacity(ops.len());
+    for (i, (op, request)) in ops.iter().zip(requests).enumerate() {
+        let states = match (op, request) {
+            (&Operation::Sleep { ms }, _) => {
+                thread::sleep(Duration::from_millis(ms));
+                Vec::new()
+            }
+            (_, Some(request)) => execute(relay, &request)
+                .map_err(|e| io::Error::new(e.kind(), format!("Operation {} failed: {}", i, e)))?,
+            (_, None) => Vec::new(),
+        };
+        results.push(states);
+    }
+    Ok(results)
+}
+
+/// parses, executes and answers a request
+pub fn handle(relay: &mut Relay8x, text: &str) -> String {
+    let response = match serde_json::from_str::<Request>(text) {
+        Ok(request) => {
+            let id = request.id.clone();
+            match execute(relay, &request) {
+                Ok(states) => Response {
+                    jsonrpc: "2.0",
+                    id,
+                    result: Some(states),
+                    error: None,
+                },
+                Err(e) => {
+                    let code = match e.kind() {
+                        ErrorKind::NotFound => METHOD_NOT_FOUND,
+                        ErrorKind::InvalidInput => INVALID_PARAMS,
+                        _ => DEVICE_ERROR,
+                    };
+                    error_response(id, code, e.to_string())
+                }
+            }
+        }
+        Err(e) => error_response(Value::Null, PARSE_ERROR, e.to_string()),
+    };
+    serde_json::to_string(&response).unwrap_or_default()
+}
+
+fn error_response(id: Value, code: i32, message: String) -> Response {
+    Response {
+        jsonrpc: "2.0",
+        id,
+        result: None,
+        error: Some(ErrorObject { code, message }),
+    }
+}
+
+/// `state` notification telling clients about changed relay states
+pub fn notification(states: &[CardState]) -> String {
+    #[derive(Serialize)]
+    struct Notification<'a> {
+        jsonrpc: &'static str,
+        method: &'static str,
+        params: &'a [CardState],
+    }
+    serde_json::to_string(&Notification {
+        jsonrpc: "2.0",
+        method: "state",
+        params: states,
+    })
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sim::SimulatedRelays;
+    use std::collections::BTreeMap;
+    use Relay8xConfig;
+
+    #[test]
+    fn handles_requests() {
+        let sim = SimulatedRelays::new(2);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+
+        let resp = handle(
+            &mut relay,
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "set", "params": {"cards": [2], "relays": [1, 3], "state": "on"}}"#,
+        );
+        assert_eq!(
+            resp,
+            r#"{"jsonrpc":"2.0","id":1,"result":[{"card":2,"state":5}]}"#
+        );
+        assert_eq!(sim.relays(2), Some(0b101));
+
+        let resp = handle(&mut relay, r#"{"id": "a", "method": "get"}"#);
+        assert_eq!(
+            resp,
+            r#"{"jsonrpc":"2.0","id":"a","result":[{"card":1,"state":0}]}"#
+        );
+    }
+
+    #[test]
+    fn switches_all_relays_of_mixed_cards() {
+        let sim = SimulatedRelays::new(2);
+        let mut card_relays = BTreeMap::new();
+        card_relays.insert(2, 4);
+        let config = Relay8xConfig {
+            card_relays,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_transport_with_config(sim.clone(), config);
+
+        let resp = handle(
+            &mut relay,
+            r#"{"id": 1, "method": "set", "params": {"cards": [1, 2], "state": "on"}}"#,
+        );
+        assert_eq!(
+            resp,
+            r#"{"jsonrpc":"2.0","id":1,"result":[{"card":1,"state":255},{"card":2,"state":15}]}"#
+        );
+        let resp = handle(
+            &mut relay,
+            r#"{"id": 2, "method": "reset", "params": {"cards": [1, 2]}}"#,
+        );
+        assert_eq!(
+            resp,
+            r#"{"jsonrpc":"2.0","id":2,"result":[{"card":1,"state":0},{"card":2,"state":0}]}"#
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut relay = Relay8x::from_transport(SimulatedRelays::new(1), 1);
+        let mut code = |text: &str| {
+            let resp: Value = serde_json::from_str(&handle(&mut relay, text)).unwrap();
+            resp["error"]["code"].as_i64().unwrap()
+        };
+        assert_eq!(code("{"), PARSE_ERROR as i64);
+        assert_eq!(
+            code(r#"{"id": 1, "method": "jump"}"#),
+            METHOD_NOT_FOUND as i64
+        );
+        assert_eq!(code(r#"{"id": 1, "method": "set"}"#), INVALID_PARAMS as i64);
+        assert_eq!(
+            code(r#"{"id": 1, "method": "toggle"}"#),
+            INVALID_PARAMS as i64
+        );
+    }
+
+    #[test]
+    fn runs_batch_in_order() {
+        let sim = SimulatedRelays::new(1);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        let ops = parse_batch(
+            r#"[{"op":"set","relays":[1],"state":true},{"op":"sleep","ms":10},{"op":"toggle","relays":[1,2]}]"#,
+        )
+        .unwrap();
+        let results = run_batch(&mut relay, &ops, &vec![1]).unwrap();
+        assert_eq!(results[0], vec![CardState { card: 1, state: 1 }]);
+        assert!(results[1].is_empty());
+        assert_eq!(results[2], vec![CardState { card: 1, state: 2 }]);
+
+        // nothing runs if any operation is invalid
+        assert!(
+            parse_batch(r#"[{"op":"set","relays":[1],"state":true},{"op":"toggle"}]"#).is_err()
+        );
+        assert!(parse_batch(r#"[{"op":"reset","relays":[9]}]"#).is_err());
+        assert!(parse_batch(r#"[{"op":"jump"}]"#).is_err());
+
+        let ops = parse_batch(r#"[{"op":"get"},{"op":"get","cards":[2]}]"#).unwrap();
+        let err = run_batch(&mut relay, &ops, &vec![1]).unwrap_err();
+        assert!(err.to_string().starts_with("Operation 1 failed"));
+    }
+
+    #[test]
+    fn mixed_batch_keeps_order_and_names_failing_operation() {
+        let sim = SimulatedRelays::new(1);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        let ops = parse_batch(
+            r#"[{"op":"set","relays":[1],"state":true},{"op":"toggle","relays":[1,2]},{"op":"toggle","relays":[2]},{"op":"set","relays":[3],"state":true}]"#,
+        )
+        .unwrap();
+        let states: Vec<u8> = run_batch(&mut relay, &ops, &vec![1])
+            .unwrap()
+            .iter()
+            .map(|s| s[0].state)
+            .collect();
+        assert_eq!(states, vec![0b1, 0b10, 0b0, 0b100]);
+
+        // the operations before the failing one stay applied
+        let ops = parse_batch(
+            r#"[{"op":"toggle","relays":[2]},{"op":"toggle","cards":[2],"relays":[1]},{"op":"reset"}]"#,
+        )
+        .unwrap();
+        let err = run_batch(&mut relay, &ops, &vec![1]).unwrap_err();
+        assert!(err.to_string().starts_with("Operation 1 failed"), "{}", err);
+        assert_eq!(sim.relays(1), Some(0b110));
+    }
+
+    #[test]
+    fn batch_operations_override_session_cards() {
+        let sim = SimulatedRelays::new(2);
+        let mut relay = Relay8x::from_transport(sim.clone(), 1);
+        relay.configure_device().unwrap();
+        let ops = parse_batch(
+            r#"[{"op":"set","relays":[1],"state":true},{"op":"set","cards":[2],"relays":[2],"state":true}]"#,
+        )
+        .unwrap();
+        let results = run_batch(&mut relay, &ops, &vec![1]).unwrap();
+        assert_eq!(results[0], vec![CardState { card: 1, state: 1 }]);
+        assert_eq!(results[1], vec![CardState { card: 2, state: 2 }]);
+        assert_eq!((sim.relays(1), sim.relays(2)), (Some(1), Some(2)));
+
+        // card 3 didn't answer the initialisation, nothing runs
+        let ops = parse_batch(r#"[{"op":"reset"},{"op":"get","cards":[3]}]"#).unwrap();
+        let err = run_batch(&mut relay, &ops, &vec![1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(err.to_string().starts_with("Operation 1:"));
+        assert_eq!(sim.relays(1), Some(1));
+    }
+
+    #[test]
+    fn notifies_states() {
+        let states = [CardState { card: 1, state: 3 }];
+        assert_eq!(
+            notification(&states),
+            r#"{"jsonrpc":"2.0","method":"state","params":[{"card":1,"state":3}]}"#
+        );
+    }
+}
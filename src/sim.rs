@@ -0,0 +1,287 @@
+//! in-memory chain of relay cards speaking the card protocol
+//!
+//! `SimulatedRelays` executes every frame written to it the way a chain of real cards
+//! would and queues the responses for reading, so `Relay8x` can be driven without any
+//! hardware, e.g. for tests, demos or documentation.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use iter_relays;
+use transport::RelayTransport;
+
+/// software version reported by simulated cards in the init response
+pub const FIRMWARE: u8 = 0x0b;
+
+/// options of a card as shipped: broadcasts are executed and passed on
+const DEFAULT_OPTION: u8 = 1;
+
+#[derive(Debug, Clone)]
+struct Card {
+    address: u8,
+    port: u8,
+    option: u8,
+}
+
+#[derive(Debug)]
+struct Chain {
+    cards: Vec<Card>,
+    // bytes written but not yet forming a complete frame
+    incoming: Vec<u8>,
+    outgoing: VecDeque<u8>,
+}
+
+/// a chain of simulated relay cards
+///
+/// clones share the same cards, so one handle can be inspected while `Relay8x` owns
+/// another one
+#[derive(Debug, Clone)]
+pub struct SimulatedRelays {
+    chain: Arc<Mutex<Chain>>,
+}
+
+fn frame(cmd: u8, address: u8, data: u8) -> [u8; 4] {
+    [cmd, address, data, cmd ^ address ^ data]
+}
+
+impl SimulatedRelays {
+    /// chain of `count` cards, addressed from 1 on as if initialised already
+    pub fn new(count: u8) -> Self {
+        let cards = (1..=count)
+            .map(|address| Card {
+                address,
+                port: 0,
+                option: DEFAULT_OPTION,
+            })
+            .collect();
+        Self {
+            chain: Arc::new(Mutex::new(Chain {
+                cards,
+                incoming: Vec::new(),
+                outgoing: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// relay states of the card at `address`, bit 0 is relay 1
+    pub fn relays(&self, address: u8) -> Option<u8> {
+        let chain = self.chain.lock().unwrap();
+        chain
+            .cards
+            .iter()
+            .find(|c| c.address == address)
+            .map(|c| c.port)
+    }
+
+    /// overwrite the relay states of the card at `address`, e.g. by "manual intervention"
+    pub fn set_relays(&self, address: u8, port: u8) {
+        let mut chain = self.chain.lock().unwrap();
+        if let Some(card) = chain.cards.iter_mut().find(|c| c.address == address) {
+            card.port = port;
+        }
+    }
+
+    /// addresses of all cards in chain order
+    pub fn addresses(&self) -> Vec<u8> {
+        let chain = self.chain.lock().unwrap();
+        chain.cards.iter().map(|c| c.address).collect()
+    }
+
+    /// the relay states of all cards drawn as boxes, filled for relays which are on
+    ///
+    /// ```text
+    /// card 1   [#] [ ] [#] [ ] [ ] [ ] [ ] [ ]
+    /// card 2   [ ] [ ] [ ] [ ] [ ] [ ] [ ] [#]
+    ///           1   2   3   4   5   6   7   8
+    /// ```
+    pub fn panel(&self) -> String {
+        let chain = self.chain.lock().unwrap();
+        let mut panel = String::new();
+        for card in chain.cards.iter() {
+            let boxes: Vec<&str> = iter_relays(card.port)
+                .map(|(_, on)| if on { "[#]" } else { "[ ]" })
+                .collect();
+            panel += &format!("card {:<3} {}\n", card.address, boxes.join(" "));
+        }
+        let numbers: Vec<String> = (1..=8).map(|r| r.to_string()).collect();
+        panel += &format!("{:10}{}\n", "", numbers.join("   "));
+        panel
+    }
+}
+
+impl Chain {
+    /// runs a frame through the chain, queueing everything that arrives back at the host
+    fn process(&mut self, f: [u8; 4]) {
+        let [cmd, address, data, xor] = f;
+        if cmd ^ address ^ data != xor {
+            // the first card detects the transmission error and does not pass it on
+            let own = self.cards.first().map(|c| c.address).unwrap_or(0);
+            return self.outgoing.extend(frame(255, own, 0).iter());
+        }
+        if cmd == 1 {
+            // init: each card takes the address and passes it on incremented
+            let mut next = address;
+            for card in &mut self.cards {
+                card.address = next;
+                self.outgoing.extend(frame(!cmd, next, FIRMWARE).iter());
+                next = next.wrapping_add(1);
+            }
+            return self.outgoing.extend(frame(cmd, next, 0).iter());
+        }
+        if address == 0 {
+            for card in &mut self.cards {
+                if card.option & 1 == 1 {
+                    let resp = card.execute(cmd, data);
+                    self.outgoing.extend(resp.iter());
+                }
+                if card.option & 2 == 2 {
+                    // broadcast is blocked, the following cards only see a NOP
+                    return self.outgoing.extend(frame(0, 0, 0).iter());
+                }
+            }
+            return self.outgoing.extend(f.iter());
+        }
+        match self.cards.iter_mut().find(|c| c.address == address) {
+            Some(card) => {
+                let resp = card.execute(cmd, data);
+                self.outgoing.extend(resp.iter())
+            }
+            // no card with this address, the frame travels through the chain unchanged
+            None => self.outgoing.extend(f.iter()),
+        }
+    }
+}
+
+impl Card {
+    /// executes a command and returns the response frame
+    fn execute(&mut self, cmd: u8, data: u8) -> [u8; 4] {
+        match cmd {
+            2 => frame(!cmd, self.address, self.port),
+            3 => {
+                self.port = data;
+                frame(!cmd, self.address, 0)
+            }
+            4 => frame(!cmd, self.address, self.option),
+            5 => {
+                self.option = data & 3;
+                frame(!cmd, self.address, 0)
+            }
+            6 => {
+                self.port |= data;
+                frame(!cmd, self.address, data)
+            }
+            7 => {
+                self.port &= !data;
+                frame(!cmd, self.address, data)
+            }
+            8 => {
+                self.port ^= data;
+                frame(!cmd, self.address, data)
+            }
+            // NOP and unknown commands are answered with an error frame
+            _ => frame(255, self.address, 0),
+        }
+    }
+}
+
+impl Read for SimulatedRelays {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chain = self.chain.lock().unwrap();
+        if chain.outgoing.is_empty() {
+            return Err(io::Error::new(ErrorKind::TimedOut, "no response"));
+        }
+        let mut n = 0;
+        while n < buf.len() {
+            match chain.outgoing.pop_front() {
+                Some(byte) => buf[n] = byte,
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for SimulatedRelays {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut chain = self.chain.lock().unwrap();
+        chain.incoming.extend_from_slice(buf);
+        while chain.incoming.len() >= 4 {
+            let f: Vec<u8> = chain.incoming.drain(..4).collect();
+            chain.process([f[0], f[1], f[2], f[3]]);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl RelayTransport for SimulatedRelays {
+    fn configure(&mut self, _baud: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn drain(&mut self) -> io::Result<usize> {
+        let mut chain = self.chain.lock().unwrap();
+        let drained = chain.outgoing.len();
+        chain.outgoing.clear();
+        Ok(drained)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn transceive(sim: &mut SimulatedRelays, f: [u8; 4]) -> Vec<u8> {
+        sim.write_all(&f).unwrap();
+        let mut resp = Vec::new();
+        let mut buf = [0u8; 64];
+        while let Ok(n) = sim.read(&mut buf) {
+            resp.extend_from_slice(&buf[..n]);
+        }
+        resp
+    }
+
+    #[test]
+    fn init_addresses_chain() {
+        let mut sim = SimulatedRelays::new(2);
+        let resp = transceive(&mut sim, frame(1, 5, 0));
+        assert_eq!(resp.len(), 12);
+        assert_eq!(&resp[8..], &frame(1, 7, 0));
+        assert_eq!(sim.addresses(), vec![5, 6]);
+    }
+
+    #[test]
+    fn del_single_keeps_other_relays() {
+        let mut sim = SimulatedRelays::new(1);
+        transceive(&mut sim, frame(6, 1, 0b101));
+        transceive(&mut sim, frame(7, 1, 0b100));
+        assert_eq!(sim.relays(1), Some(0b001));
+    }
+
+    #[test]
+    fn panel_shows_relay_states() {
+        let sim = SimulatedRelays::new(2);
+        sim.set_relays(1, 0b101);
+        sim.set_relays(2, 0b1000_0000);
+        let panel: Vec<String> = sim.panel().lines().map(str::to_string).collect();
+        assert_eq!(
+            panel,
+            vec![
+                "card 1   [#] [ ] [#] [ ] [ ] [ ] [ ] [ ]",
+                "card 2   [ ] [ ] [ ] [ ] [ ] [ ] [ ] [#]",
+                "          1   2   3   4   5   6   7   8",
+            ]
+        );
+    }
+}
@@ -0,0 +1,432 @@
+use serial::prelude::*;
+use std::cell::RefCell;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// byte stream a chain of relay cards is attached to
+///
+/// besides reading and writing frames, `Relay8x` only needs to apply the serial
+/// settings of the card and to bound how long a read may block
+pub trait RelayTransport: Read + Write {
+    /// apply the interface parameters of the relay card (8N1, no flow control) at `baud`
+    fn configure(&mut self, baud: u32) -> io::Result<()>;
+
+    /// maximum time a single read waits for data
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// discard the bytes already waiting to be read, returns how many there were
+    ///
+    /// transports without an input buffer have nothing to drain
+    fn drain(&mut self) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+/// how long a drain waits for more stale bytes
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// most bytes a drain discards, a device streaming data would keep it busy forever
+const MAX_DRAIN_BYTES: usize = 1024;
+
+/// transport over a serial port of the `serial` crate
+pub struct SerialTransport<P: SerialPort> {
+    port: P,
+}
+
+impl<P: SerialPort> SerialTransport<P> {
+    /// wrap an already opened serial port
+    pub fn new(port: P) -> Self {
+        Self { port }
+    }
+}
+
+impl<P: SerialPort> Read for SerialTransport<P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+impl<P: SerialPort> Write for SerialTransport<P> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl<P: SerialPort> RelayTransport for SerialTransport<P> {
+    fn configure(&mut self, baud: u32) -> io::Result<()> {
+        let baud_rate = ::serial::BaudRate::from_speed(baud as usize);
+        // configure interface with its params, see doc of relay card, the settings are
+        // changed on a copy and only written to the port once all of them were accepted
+        self.port
+            .reconfigure(&|settings| {
+                settings.set_baud_rate(baud_rate)?;
+                settings.set_char_size(::serial::Bits8);
+                settings.set_parity(::serial::ParityNone);
+                settings.set_stop_bits(::serial::Stop1);
+                settings.set_flow_control(::serial::FlowNone);
+                Ok(())
+            })
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Failed to configure serial port for {} baud: {}", baud, e),
+                )
+            })?;
+
+        // writing the settings may succeed although the adapter ignored some of them
+        let applied = RefCell::new(None);
+        self.port.reconfigure(&|settings| {
+            *applied.borrow_mut() = Some((
+                settings.baud_rate(),
+                settings.char_size(),
+                settings.parity(),
+                settings.stop_bits(),
+                settings.flow_control(),
+            ));
+            Ok(())
+        })?;
+        let unsupported = |what: String| {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("adapter does not support {}", what),
+            ))
+        };
+        match applied.into_inner() {
+            Some((baud_rate_read, ..)) if baud_rate_read != Some(baud_rate) => {
+                unsupported(format!("{} baud", baud))
+            }
+            Some((_, char_size, ..)) if char_size != Some(::serial::Bits8) => {
+                unsupported("8 data bits".to_string())
+            }
+            Some((_, _, parity, ..)) if parity != Some(::serial::ParityNone) => {
+                unsupported("disabling parity".to_string())
+            }
+            Some((_, _, _, stop_bits, _)) if stop_bits != Some(::serial::Stop1) => {
+                unsupported("1 stop bit".to_string())
+            }
+            Some((.., flow_control)) if flow_control != Some(::serial::FlowNone) => {
+                unsupported("disabling flow control".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.port.set_timeout(timeout)?;
+        Ok(())
+    }
+
+    fn drain(&mut self) -> io::Result<usize> {
+        let timeout = self.port.timeout();
+        self.port.set_timeout(DRAIN_TIMEOUT)?;
+        let mut buf = [0u8; 64];
+        let mut drained = 0;
+        let result = loop {
+            match self.port.read(&mut buf) {
+                Ok(0) => break Ok(drained),
+                Ok(n) if drained + n >= MAX_DRAIN_BYTES => break Ok(drained + n),
+                Ok(n) => drained += n,
+                Err(ref e) if e.kind() == ErrorKind::TimedOut => break Ok(drained),
+                Err(e) => break Err(e),
+            }
+        };
+        self.port.set_timeout(timeout)?;
+        result
+    }
+}
+
+/// device name prefix of a card chain behind a serial-to-Ethernet bridge, e.g. ser2net
+pub const TCP_SCHEME: &str = "tcp://";
+
+/// transport over a TCP connection to a serial bridge
+///
+/// the bridge owns the serial settings, frames pass through unchanged
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    /// connect to the bridge at `address`, e.g. `192.168.1.50:5000`
+    pub fn connect(address: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        // frames are tiny, don't hold them back waiting for more data
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // an elapsed read timeout is `WouldBlock` on unix, callers expect `TimedOut`
+        self.stream.read(buf).map_err(|e| {
+            if e.kind() == ErrorKind::WouldBlock {
+                Error::new(ErrorKind::TimedOut, e)
+            } else {
+                e
+            }
+        })
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl RelayTransport for TcpTransport {
+    fn configure(&mut self, _baud: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        // a zero timeout is rejected by the socket, it would block forever as `None`
+        self.stream
+            .set_read_timeout(Some(timeout.max(DRAIN_TIMEOUT)))
+    }
+
+    fn drain(&mut self) -> io::Result<usize> {
+        let timeout = self.stream.read_timeout()?;
+        self.stream.set_read_timeout(Some(DRAIN_TIMEOUT))?;
+        let mut buf = [0u8; 64];
+        let mut drained = 0;
+        let result = loop {
+            match self.read(&mut buf) {
+                Ok(0) => break Ok(drained),
+                Ok(n) if drained + n >= MAX_DRAIN_BYTES => break Ok(drained + n),
+                Ok(n) => drained += n,
+                Err(ref e) if e.kind() == ErrorKind::TimedOut => break Ok(drained),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_read_timeout(timeout)?;
+        result
+    }
+}
+
+/// transport over any byte stream, e.g. a custom link or an in-memory buffer
+///
+/// there are no serial settings to apply and reads block as long as the stream does;
+/// the end of the stream reads like a card which doesn't answer
+pub struct IoTransport<T: Read + Write> {
+    io: T,
+}
+
+impl<T: Read + Write> IoTransport<T> {
+    /// wrap a byte stream
+    pub fn new(io: T) -> Self {
+        Self { io }
+    }
+}
+
+impl<T: Read + Write> Read for IoTransport<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.io.read(buf)? {
+            0 if !buf.is_empty() => Err(Error::new(ErrorKind::TimedOut, "end of stream")),
+            n => Ok(n),
+        }
+    }
+}
+
+impl<T: Read + Write> Write for IoTransport<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: Read + Write> RelayTransport for IoTransport<T> {
+    fn configure(&mut self, _baud: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serial::core::{SerialDevice, SerialPortSettings};
+    use serial::{BaudRate, CharSize, FlowControl, Parity, StopBits};
+    use Relay8x;
+
+    #[derive(Clone, Default)]
+    struct FakeSettings {
+        baud_rate: Option<BaudRate>,
+        char_size: Option<CharSize>,
+        parity: Option<Parity>,
+        stop_bits: Option<StopBits>,
+        flow_control: Option<FlowControl>,
+        // baud rate the adapter refuses
+        rejected_baud: Option<BaudRate>,
+    }
+
+    impl SerialPortSettings for FakeSettings {
+        fn baud_rate(&self) -> Option<BaudRate> {
+            self.baud_rate
+        }
+        fn char_size(&self) -> Option<CharSize> {
+            self.char_size
+        }
+        fn parity(&self) -> Option<Parity> {
+            self.parity
+        }
+        fn stop_bits(&self) -> Option<StopBits> {
+            self.stop_bits
+        }
+        fn flow_control(&self) -> Option<FlowControl> {
+            self.flow_control
+        }
+        fn set_baud_rate(&mut self, baud_rate: BaudRate) -> ::serial::Result<()> {
+            if Some(baud_rate) == self.rejected_baud {
+                return Err(::serial::Error::new(
+                    ::serial::ErrorKind::InvalidInput,
+                    "unsupported baud rate",
+                ));
+            }
+            self.baud_rate = Some(baud_rate);
+            Ok(())
+        }
+        fn set_char_size(&mut self, char_size: CharSize) {
+            self.char_size = Some(char_size);
+        }
+        fn set_parity(&mut self, parity: Parity) {
+            self.parity = Some(parity);
+        }
+        fn set_stop_bits(&mut self, stop_bits: StopBits) {
+            self.stop_bits = Some(stop_bits);
+        }
+        fn set_flow_control(&mut self, flow_control: FlowControl) {
+            self.flow_control = Some(flow_control);
+        }
+    }
+
+    /// serial device whose settings live in memory
+    #[derive(Default)]
+    struct FakeDevice {
+        settings: FakeSettings,
+        writes: usize,
+        // the adapter accepts but silently ignores stop bits
+        ignores_stop_bits: bool,
+    }
+
+    impl Read for FakeDevice {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for FakeDevice {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SerialDevice for FakeDevice {
+        type Settings = FakeSettings;
+
+        fn read_settings(&self) -> ::serial::Result<FakeSettings> {
+            Ok(self.settings.clone())
+        }
+        fn write_settings(&mut self, settings: &FakeSettings) -> ::serial::Result<()> {
+            let stop_bits = self.settings.stop_bits;
+            self.settings = settings.clone();
+            if self.ignores_stop_bits {
+                self.settings.stop_bits = stop_bits;
+            }
+            self.writes += 1;
+            Ok(())
+        }
+        fn timeout(&self) -> Duration {
+            Duration::from_millis(0)
+        }
+        fn set_timeout(&mut self, _timeout: Duration) -> ::serial::Result<()> {
+            Ok(())
+        }
+        fn set_rts(&mut self, _level: bool) -> ::serial::Result<()> {
+            Ok(())
+        }
+        fn set_dtr(&mut self, _level: bool) -> ::serial::Result<()> {
+            Ok(())
+        }
+        fn read_cts(&mut self) -> ::serial::Result<bool> {
+            Ok(false)
+        }
+        fn read_dsr(&mut self) -> ::serial::Result<bool> {
+            Ok(false)
+        }
+        fn read_ri(&mut self) -> ::serial::Result<bool> {
+            Ok(false)
+        }
+        fn read_cd(&mut self) -> ::serial::Result<bool> {
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn configure_applies_card_settings() {
+        let mut transport = SerialTransport::new(FakeDevice::default());
+        transport.configure(19200).expect("Failed to configure");
+        let settings = &transport.port.settings;
+        assert_eq!(settings.baud_rate, Some(::serial::Baud19200));
+        assert_eq!(settings.char_size, Some(::serial::Bits8));
+        assert_eq!(settings.stop_bits, Some(::serial::Stop1));
+    }
+
+    #[test]
+    fn configure_rejected_baud_leaves_port_untouched() {
+        let mut device = FakeDevice::default();
+        device.settings.rejected_baud = Some(::serial::Baud19200);
+        let mut transport = SerialTransport::new(device);
+
+        let err = transport.configure(19200).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("19200 baud"));
+        assert_eq!(transport.port.writes, 0);
+        assert_eq!(transport.port.settings.char_size, None);
+    }
+
+    #[test]
+    fn configure_names_ignored_setting() {
+        let device = FakeDevice {
+            ignores_stop_bits: true,
+            ..Default::default()
+        };
+        let mut transport = SerialTransport::new(device);
+
+        let err = transport.configure(19200).unwrap_err();
+        assert_eq!(err.to_string(), "adapter does not support 1 stop bit");
+    }
+
+    #[test]
+    fn relay_configures_the_port_it_is_given() {
+        let mut relay = Relay8x::from_port(FakeDevice::default(), 1);
+        relay.configure_port().expect("Failed to configure");
+
+        let device = FakeDevice {
+            ignores_stop_bits: true,
+            ..Default::default()
+        };
+        let mut relay = Relay8x::from_port(device, 1);
+        let err = relay.configure_port().unwrap_err();
+        assert_eq!(err.to_string(), "adapter does not support 1 stop bit");
+    }
+}
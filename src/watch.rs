@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use iter_relays;
+
+/// filters relay states polled from noisy boards
+///
+/// a relay differing from its last reported state is only reported once it kept the new
+/// state for the whole debounce window, flickers shorter than that are dropped
+#[derive(Debug, Clone)]
+pub struct Debouncer {
+    window: Duration,
+    // per card: last reported port and when each relay started to differ from it
+    cards: BTreeMap<u8, (u8, [Option<Instant>; 8])>,
+}
+
+impl Debouncer {
+    /// debouncer reporting changes which persist for `window`, zero reports every change
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            cards: BTreeMap::new(),
+        }
+    }
+
+    /// feed the state of `card` polled at `now`, returns the confirmed changes as relay
+    /// number and new state
+    ///
+    /// the first state of a card is taken as is and reports nothing
+    pub fn update(&mut self, card: u8, state: u8, now: Instant) -> Vec<(u8, bool)> {
+        let (reported, pending) = match self.cards.get_mut(&card) {
+            Some(entry) => entry,
+            None => {
+                self.cards.insert(card, (state, [None; 8]));
+                return Vec::new();
+            }
+        };
+        let mut changes = Vec::new();
+        for (relay, differs) in iter_relays(state ^ *reported) {
+            let i = relay as usize - 1;
+            if !differs {
+                pending[i] = None;
+                continue;
+            }
+            let since = *pending[i].get_or_insert(now);
+            if now.duration_since(since) >= self.window {
+                *reported ^= 1 << i;
+                pending[i] = None;
+                changes.push((relay, state & 1 << i != 0));
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transient_flicker_is_suppressed() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut debouncer = Debouncer::new(Duration::from_millis(50));
+        assert!(debouncer.update(1, 0b0000_0001, at(0)).is_empty());
+
+        // relay 2 flickers for 20ms, relay 1 goes off for good
+        assert!(debouncer.update(1, 0b0000_0010, at(10)).is_empty());
+        assert!(debouncer.update(1, 0b0000_0000, at(30)).is_empty());
+        assert!(debouncer.update(1, 0b0000_0000, at(50)).is_empty());
+        assert_eq!(debouncer.update(1, 0b0000_0000, at(60)), vec![(1, false)]);
+        assert!(debouncer.update(1, 0b0000_0000, at(200)).is_empty());
+
+        let mut debouncer = Debouncer::new(Duration::from_millis(0));
+        debouncer.update(2, 0, at(0));
+        assert_eq!(debouncer.update(2, 0b1000_0000, at(1)), vec![(8, true)]);
+    }
+}
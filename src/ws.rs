@@ -0,0 +1,212 @@
+//! WebSocket endpoint speaking the JSON-RPC interface of `rpc`
+//!
+//! every connected client may send requests, text messages with one request each;
+//! whenever relay states of the watched cards change, by a request of any client or
+//! from outside, all clients receive a `state` notification
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use tungstenite;
+use tungstenite::Message;
+
+use audit::AuditLog;
+use rpc;
+use rpc::CardState;
+use {CardIndex, ConfigWatcher, Relay8x};
+
+/// how often the watched cards are queried for changes made from outside
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how long a client thread waits for a message before passing on notifications
+const CLIENT_READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+enum Event {
+    /// a new client, which receives everything sent to it
+    Join(Sender<String>),
+    /// a request, answered to the sender
+    Request(String, Sender<String>),
+}
+
+/// serves clients connecting to `listener` until the device fails
+///
+/// `cards` are the cards whose states are broadcast to the clients and, if given, snapshot
+/// to `audit`; failing to write a snapshot is logged and doesn't stop serving; a changed
+/// config of `watcher` is applied to `relay` between requests
+pub fn serve(
+    mut relay: Relay8x,
+    listener: TcpListener,
+    cards: CardIndex,
+    mut audit: Option<AuditLog>,
+    mut watcher: Option<ConfigWatcher>,
+) -> io::Result<()> {
+    let (events, rx) = channel();
+    thread::spawn(move || accept(listener, events));
+
+    let mut clients: Vec<Sender<String>> = Vec::new();
+    let mut known = BTreeMap::new();
+    loop {
+        if let Some(ref mut watcher) = watcher {
+            watcher.update(&mut relay);
+        }
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Event::Join(client)) => {
+                if client.send(rpc::notification(&snapshot(&known))).is_ok() {
+                    clients.push(client);
+                }
+            }
+            Ok(Event::Request(text, client)) => {
+                let _ = client.send(rpc::handle(&mut relay, &text));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::other("WebSocket listener stopped"));
+            }
+        }
+        let changed = changes(&mut relay, &cards, &mut known)?;
+        if !changed.is_empty() {
+            let msg = rpc::notification(&changed);
+            clients.retain(|client| client.send(msg.clone()).is_ok());
+        }
+        if let Some(ref mut audit) = audit {
+            let now = SystemTime::now();
+            if audit.due(now) {
+                if let Err(e) = audit.record(&snapshot(&known), now) {
+                    warn!("Failed to write audit snapshot: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// the known states of all cards
+fn snapshot(known: &BTreeMap<u8, u8>) -> Vec<CardState> {
+    known
+        .iter()
+        .map(|(&card, &state)| CardState { card, state })
+        .collect()
+}
+
+/// queries the cards and returns those whose states differ from the known ones
+fn changes(
+    relay: &mut Relay8x,
+    cards: &CardIndex,
+    known: &mut BTreeMap<u8, u8>,
+) -> io::Result<Vec<CardState>> {
+    let states = relay.get_relays(cards.clone())?;
+    let mut changed = Vec::new();
+    for (&card, state) in cards.iter().zip(states) {
+        if known.insert(card, state) != Some(state) {
+            changed.push(CardState { card, state });
+        }
+    }
+    Ok(changed)
+}
+
+fn accept(listener: TcpListener, events: Sender<Event>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let events = events.clone();
+                thread::spawn(move || {
+                    if let Err(e) = client(stream, events) {
+                        debug!("WebSocket client left: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept WebSocket client: {}", e),
+        }
+    }
+}
+
+fn client(stream: TcpStream, events: Sender<Event>) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut socket = tungstenite::accept(stream).map_err(|e| io::Error::other(e.to_string()))?;
+    socket
+        .get_ref()
+        .set_read_timeout(Some(CLIENT_READ_TIMEOUT))?;
+    info!("WebSocket client {} connected", peer);
+
+    let (tx, rx): (Sender<String>, Receiver<String>) = channel();
+    events
+        .send(Event::Join(tx.clone()))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => events
+                .send(Event::Request(text, tx.clone()))
+                .map_err(|e| io::Error::other(e.to_string()))?,
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(ref e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        }
+        while let Ok(msg) = rx.try_recv() {
+            socket
+                .send(Message::Text(msg))
+                .map_err(|e| io::Error::other(e.to_string()))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json;
+    use serde_json::Value;
+    use sim::SimulatedRelays;
+
+    fn next(
+        socket: &mut tungstenite::WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>,
+    ) -> Value {
+        loop {
+            if let Message::Text(text) = socket.read().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn broadcasts_changes_to_all_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let sim = SimulatedRelays::new(1);
+        let served = sim.clone();
+        thread::spawn(move || {
+            serve(
+                Relay8x::from_transport(served, 1),
+                listener,
+                vec![1],
+                None,
+                None,
+            )
+        });
+
+        let (mut first, _) = tungstenite::connect(url.as_str()).unwrap();
+        let (mut second, _) = tungstenite::connect(url.as_str()).unwrap();
+        next(&mut first);
+        next(&mut second);
+
+        first
+            .send(Message::Text(
+                r#"{"id": 1, "method": "set", "params": {"relays": [2], "state": "on"}}"#.into(),
+            ))
+            .unwrap();
+        let mut msgs = [next(&mut first), next(&mut first)];
+        msgs.sort_by_key(|m| m["id"].is_null());
+        assert_eq!(msgs[0]["result"][0]["state"], 2);
+        assert_eq!(msgs[1]["method"], "state");
+        // the initial state may still be on its way to the second client
+        let mut msg = next(&mut second);
+        while msg["params"][0]["state"] != 2 {
+            msg = next(&mut second);
+        }
+        assert_eq!(sim.relays(1), Some(2));
+    }
+}